@@ -1,9 +1,24 @@
 #![feature(iter_repeat_n)]
 use std::collections::HashMap;
 
-use geonext_shared::{territories::Territories, ServerMessage};
-use glam::Mat4;
+use geonext_shared::{
+	buildings::Buildings,
+	combat::BattleResult,
+	deformation::Deformation,
+	diplomacy::{Diplomacy, DiplomaticAction, Proposal, Relation},
+	economy::{BuildingKind, EconomyReport},
+	lobby::{GameId, GameMode, GameSummary},
+	map_loader::HexCoord,
+	orders::Order,
+	pathfinding::{find_path, MovementCosts},
+	territories::{CountryId, Territories, TerritoryDeltaError},
+	units::{Unit, UnitId, Units},
+	victory::{GameResult, VictoryConditions},
+	visibility::Visibility,
+	ClientMessage, ServerMessage,
+};
 pub use glam::{IVec2, UVec2, Vec2};
+use glam::{Mat4, Vec3};
 use renderer::OpenGl;
 mod camera;
 mod events;
@@ -18,6 +33,11 @@ pub use time::Time;
 #[macro_use]
 extern crate log;
 
+/// How long a unit takes to slide to the hex it has moved to, in milliseconds
+const UNIT_ANIMATION_MS: f32 = 400.;
+/// How bright hexes that cannot be seen are drawn, compared to those that can
+const FOG_BRIGHTNESS: f32 = 0.4;
+
 #[derive(Debug)]
 pub struct Assets(pub HashMap<String, Vec<u8>>);
 
@@ -41,6 +61,53 @@ pub struct GameState {
 	pub terrain: terrain::Terrain,
 	pub input: InputSystem,
 	pub map: map::Map,
+	/// Messages waiting to be sent to the server
+	pub outgoing: Vec<ClientMessage>,
+	/// The smoothed round trip time to the server in milliseconds, once a ping has been answered
+	pub rtt: Option<f32>,
+	last_ping: f32,
+	/// Every game on the server, as last announced by the lobby
+	pub games: Vec<GameSummary>,
+	/// The game the client has joined
+	pub current_game: Option<GameId>,
+	/// The country this player controls in the current game
+	pub country: Option<CountryId>,
+	/// The latest stockpile and production of this player's country
+	pub economy: Option<EconomyReport>,
+	/// Every building in the current game
+	pub buildings: Buildings,
+	/// Every unit in the current game
+	pub units: Units,
+	/// The hex each recently moved unit came from and when it moved, so that it can be drawn sliding between them
+	unit_motion: HashMap<UnitId, (HexCoord, f32)>,
+	/// The unit that move orders are given to
+	pub selected_unit: Option<UnitId>,
+	/// The most recent battle in the current game
+	pub last_battle: Option<BattleResult>,
+	/// The hexes this player's country can see, or `None` if the whole map can be seen
+	pub visibility: Option<Visibility>,
+	/// The relations between countries in the current game
+	pub diplomacy: Diplomacy,
+	/// Proposals made to this player's country that have not been answered, as (from, proposal)
+	pub proposals: Vec<(CountryId, Proposal)>,
+	/// The latest diplomatic news, such as a declaration of war, so that it can be shown
+	pub diplomatic_news: Option<String>,
+	/// How the current game ended, once it has
+	pub game_over: Option<GameResult>,
+	/// The current turn, if the game is turn-based
+	pub turn: Option<Turn>,
+	/// Orders for the current turn that have not been submitted yet
+	pub orders: Vec<Order>,
+}
+
+/// The state of a turn in a turn-based game
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Turn {
+	pub number: u32,
+	/// When orders must be submitted by, in the same milliseconds as [`Time::time`]
+	pub deadline: f32,
+	/// Set once the orders for this turn have been sent
+	pub submitted: bool,
 }
 impl GameState {
 	#[inline]
@@ -50,8 +117,22 @@ impl GameState {
 	pub fn init(&mut self, event_layers: &mut EventLayers) {
 		self.camera.position = self.terrain.size.as_vec2() / 2.;
 
+		event_layers.push(Self::heartbeat);
+		event_layers.push(Self::lobby);
+		event_layers.push(Self::turns);
+		event_layers.push(Self::economy);
+		event_layers.push(Self::battles);
+		event_layers.push(Self::diplomacy);
+		event_layers.push(Self::game_over);
+		event_layers.push(Self::update_visibility);
 		event_layers.push(Self::update_camera);
 		event_layers.push(Self::update_map);
+		event_layers.push(Self::update_territories);
+		event_layers.push(Self::update_terrain);
+		event_layers.push(Self::update_buildings);
+		event_layers.push(Self::update_units);
+		event_layers.push(Self::select_unit);
+		event_layers.push(Self::act);
 		event_layers.push(Self::hover);
 	}
 	pub fn projection_mat(&self) -> Mat4 {
@@ -60,6 +141,265 @@ impl GameState {
 	pub fn view_mat(&self) -> Mat4 {
 		self.camera.to_matrix(&self.terrain)
 	}
+	/// Pings the server regularly so that it knows the connection is alive, and measures the latency from the replies
+	fn heartbeat(&mut self, event: &EventType) -> bool {
+		/// Milliseconds between pings
+		const PING_INTERVAL: f32 = 2000.;
+		match event {
+			EventType::Update => {
+				if self.time.time - self.last_ping > PING_INTERVAL {
+					self.last_ping = self.time.time;
+					self.outgoing.push(ClientMessage::Ping { timestamp: self.time.time });
+				}
+				// Other layers also need to see updates
+				false
+			}
+			EventType::Message(ServerMessage::Pong { timestamp }) => {
+				let sample = self.time.time - timestamp;
+				self.rtt = Some(self.rtt.map_or(sample, |rtt| rtt * 0.875 + sample * 0.125));
+				true
+			}
+			_ => false,
+		}
+	}
+
+	/// Keeps track of the games in the lobby, joining one automatically when not in a game
+	fn lobby(&mut self, event: &EventType) -> bool {
+		let EventType::Message(message) = event else {
+			return false;
+		};
+		match message {
+			ServerMessage::GameList { games } => self.games = games.clone(),
+			ServerMessage::GameUpdated(summary) => match self.games.iter_mut().find(|game| game.id == summary.id) {
+				Some(game) => *game = summary.clone(),
+				None => self.games.push(summary.clone()),
+			},
+			ServerMessage::GameClosed { game } => self.games.retain(|summary| summary.id != *game),
+			ServerMessage::Joined { game } => {
+				self.current_game = Some(*game);
+				return true;
+			}
+			ServerMessage::CountryAssigned { country } => {
+				self.country = Some(*country);
+				return true;
+			}
+			ServerMessage::Left => {
+				self.current_game = None;
+				self.country = None;
+				self.economy = None;
+				self.buildings.clear();
+				self.units.clear();
+				self.unit_motion.clear();
+				self.selected_unit = None;
+				self.last_battle = None;
+				self.diplomacy = Diplomacy::default();
+				self.proposals.clear();
+				self.diplomatic_news = None;
+				self.game_over = None;
+				if self.visibility.take().is_some() {
+					self.map.dirty_chunks = self.map.height_map.chunks().collect();
+				}
+				self.turn = None;
+				self.orders.clear();
+				self.outgoing.push(ClientMessage::ListGames);
+				return true;
+			}
+			_ => return false,
+		}
+		if self.current_game.is_none() && matches!(message, ServerMessage::GameList { .. }) {
+			let join = match self.games.iter().find(|game| !game.is_full()) {
+				Some(game) => ClientMessage::JoinGame { game: game.id },
+				None => ClientMessage::CreateGame {
+					name: format!("Game {}", self.games.len() + 1),
+					capacity: 8,
+					mode: GameMode::RealTime,
+					victory: VictoryConditions::default(),
+				},
+			};
+			self.outgoing.push(join);
+		}
+		true
+	}
+
+	/// Follows the turns of a turn-based game and submits orders when enter is pressed
+	fn turns(&mut self, event: &EventType) -> bool {
+		match event {
+			EventType::Message(ServerMessage::TurnStarted { turn, remaining_ms }) => {
+				if self.turn.map(|current| current.number) != Some(*turn) {
+					self.orders.clear();
+				}
+				let submitted = self.turn.is_some_and(|current| current.number == *turn && current.submitted);
+				self.turn = Some(Turn {
+					number: *turn,
+					deadline: self.time.time + *remaining_ms as f32,
+					submitted,
+				});
+				info!("Turn {turn} started, {}s left to submit orders", remaining_ms / 1000);
+				true
+			}
+			EventType::Message(ServerMessage::OrdersSubmitted { turn, player }) => {
+				info!("{player} has submitted their orders for turn {turn}");
+				true
+			}
+			EventType::Message(ServerMessage::TurnResolved { turn, results }) => {
+				for result in results {
+					if let Some(error) = &result.error {
+						warn!("Turn {turn}: {:?} by {} failed: {error}", result.order, result.player);
+					}
+				}
+				true
+			}
+			EventType::KeyDown(key) if key == "Enter" => {
+				let Some(turn) = &mut self.turn else {
+					return false;
+				};
+				turn.submitted = true;
+				self.outgoing.push(ClientMessage::SubmitOrders {
+					turn: turn.number,
+					orders: self.orders.clone(),
+				});
+				true
+			}
+			_ => false,
+		}
+	}
+
+	/// Keeps the latest report on this player's economy so that it can be shown
+	fn economy(&mut self, event: &EventType) -> bool {
+		let EventType::Message(ServerMessage::Economy(report)) = event else {
+			return false;
+		};
+		self.economy = Some(*report);
+		true
+	}
+
+	/// Keeps the most recent battle so that it can be shown
+	fn battles(&mut self, event: &EventType) -> bool {
+		let EventType::Message(ServerMessage::BattleResult(result)) = event else {
+			return false;
+		};
+		let battle = result.battle;
+		let outcome = if result.attacker_won { "won" } else { "was repelled" };
+		info!(
+			"Country {} attacked country {} at {:?} with {} against {} (+{}%) and {outcome}, losing {} to {}",
+			battle.attacker.0,
+			battle.defender.0,
+			battle.hex,
+			battle.attack,
+			battle.defence,
+			battle.modifiers.total(),
+			result.attacker_losses,
+			result.defender_losses
+		);
+		self.last_battle = Some(*result);
+		true
+	}
+
+	/// Follows relations between countries, and declares war (w), proposes peace or an alliance (p) or accepts a proposal (y) to the hovered country
+	fn diplomacy(&mut self, event: &EventType) -> bool {
+		match event {
+			EventType::Message(ServerMessage::RelationChanged(change)) => {
+				let (a, b) = change.countries;
+				self.diplomacy.set(a, b, change.relation);
+				// Proposals are dropped whenever the relation changes
+				self.proposals.retain(|&(from, _)| !(Some(a) == self.country && from == b || Some(b) == self.country && from == a));
+				let names = (self.map.borders.get_name(a), self.map.borders.get_name(b));
+				let news = match change.relation {
+					Relation::War => format!("{} and {} are at war", names.0, names.1),
+					Relation::Peace => format!("{} and {} have made peace", names.0, names.1),
+					Relation::Alliance => format!("{} and {} have formed an alliance", names.0, names.1),
+				};
+				info!("{news}");
+				self.diplomatic_news = Some(news);
+				true
+			}
+			EventType::Message(ServerMessage::ProposalReceived { from, proposal }) => {
+				let news = format!("{} proposes {} (press y over their territory to accept)", self.map.borders.get_name(*from), proposal.to());
+				info!("{news}");
+				self.diplomatic_news = Some(news);
+				if !self.proposals.contains(&(*from, *proposal)) {
+					self.proposals.push((*from, *proposal));
+				}
+				true
+			}
+			EventType::KeyDown(key) if matches!(key.as_str(), "w" | "p" | "y") => {
+				let Some(country) = self.country else {
+					return false;
+				};
+				let target = self.map.hovered_country();
+				if target == country || target == CountryId::SEA {
+					return false;
+				}
+				let action = match key.as_str() {
+					"w" => DiplomaticAction::DeclareWar(target),
+					"p" if self.diplomacy.at_war(country, target) => DiplomaticAction::Propose(target, Proposal::Peace),
+					"p" => DiplomaticAction::Propose(target, Proposal::Alliance),
+					_ => match self.proposals.iter().find(|(from, _)| *from == target) {
+						Some(&(from, proposal)) => DiplomaticAction::Accept(from, proposal),
+						None => {
+							warn!("{} has not made a proposal", self.map.borders.get_name(target));
+							return true;
+						}
+					},
+				};
+				self.outgoing.push(ClientMessage::Diplomacy(action));
+				true
+			}
+			_ => false,
+		}
+	}
+
+	/// Keeps the result of the game for the summary screen, and leaves the game when escape is pressed on it
+	fn game_over(&mut self, event: &EventType) -> bool {
+		match event {
+			EventType::Message(ServerMessage::GameOver(result)) => {
+				let winner = result.winner.map_or("Nobody", |winner| self.map.borders.get_name(winner));
+				info!("The game is over, {winner} won by {}", result.reason);
+				self.game_over = Some(result.clone());
+				true
+			}
+			EventType::KeyDown(key) if key == "Escape" && self.game_over.is_some() => {
+				self.outgoing.push(ClientMessage::LeaveGame);
+				true
+			}
+			_ => false,
+		}
+	}
+
+	/// The relation between this player's country and the hovered country, if they are different countries
+	pub fn hovered_relation(&self) -> Option<Relation> {
+		let (country, hovered) = (self.country?, self.map.hovered_country());
+		(hovered != country && hovered != CountryId::SEA).then(|| self.diplomacy.relation(country, hovered))
+	}
+
+	/// Tracks which hexes can be seen, so that the rest can be darkened
+	fn update_visibility(&mut self, event: &EventType) -> bool {
+		let EventType::Message(ServerMessage::VisibilityChanged { visible, hidden }) = event else {
+			return false;
+		};
+		match &mut self.visibility {
+			Some(visibility) => {
+				visibility.apply(visible, hidden);
+				self.map.terrain_changed(visible.iter().chain(hidden).copied());
+			}
+			None => {
+				let mut visibility = Visibility::default();
+				visibility.apply(visible, hidden);
+				self.visibility = Some(visibility);
+				self.map.dirty_chunks = self.map.height_map.chunks().collect();
+			}
+		}
+		true
+	}
+
+	/// How brightly a hex is drawn, which is darker if it cannot be seen
+	pub fn brightness(&self, hex: HexCoord) -> f32 {
+		match &self.visibility {
+			Some(visibility) if !visibility.contains(hex) => FOG_BRIGHTNESS,
+			_ => 1.,
+		}
+	}
+
 	fn update_camera(&mut self, event: &EventType) -> bool {
 		match event {
 			EventType::PointerMove(delta) if self.input.mouse_down(MouseButton::Primary) => {
@@ -113,6 +453,125 @@ impl GameState {
 		true
 	}
 
+	fn update_territories(&mut self, event: &EventType) -> bool {
+		let EventType::Message(ServerMessage::TerritoryDelta(delta)) = event else {
+			return false;
+		};
+		match self.map.borders.apply(delta) {
+			Ok(countries) => self.map.countries_changed(countries),
+			Err(TerritoryDeltaError::Stale { .. }) => {}
+			Err(e) => {
+				warn!("{e}, requesting the whole map");
+				self.outgoing.push(ClientMessage::RequestMap);
+			}
+		}
+		true
+	}
+
+	fn update_terrain(&mut self, event: &EventType) -> bool {
+		let EventType::Message(ServerMessage::TerrainChanged { changes }) = event else {
+			return false;
+		};
+		let hexes = self.map.height_map.apply_changes(changes);
+		self.map.terrain_changed(hexes);
+		self.map.updated = true;
+		true
+	}
+
+	fn update_buildings(&mut self, event: &EventType) -> bool {
+		let EventType::Message(ServerMessage::BuildingsChanged { buildings }) = event else {
+			return false;
+		};
+		for &(hex, building) in buildings {
+			self.buildings.insert(hex, building);
+		}
+		true
+	}
+
+	fn update_units(&mut self, event: &EventType) -> bool {
+		let EventType::Message(ServerMessage::UnitsChanged { units, removed }) = event else {
+			return false;
+		};
+		for unit in units {
+			if let Some(previous) = self.units.get(unit.id).filter(|previous| previous.position != unit.position) {
+				self.unit_motion.insert(unit.id, (previous.position, self.time.time));
+			}
+			self.units.insert(unit.clone());
+		}
+		for &unit in removed {
+			self.units.remove(unit);
+			self.unit_motion.remove(&unit);
+		}
+		true
+	}
+
+	/// Where to draw a unit, sliding from its previous hex for [`UNIT_ANIMATION_MS`] after it moves
+	pub fn unit_position(&self, unit: &Unit) -> Vec3 {
+		let centre = |hex: HexCoord| {
+			let offset = hex.to_offset().as_uvec2();
+			self.map.height_map.hex_centre(offset.x, offset.y)
+		};
+		let to = centre(unit.position);
+		match self.unit_motion.get(&unit.id) {
+			Some(&(from, moved_at)) => centre(from).lerp(to, ((self.time.time - moved_at) / UNIT_ANIMATION_MS).clamp(0., 1.)),
+			None => to,
+		}
+	}
+
+	/// Selects this player's unit on the hovered hex, or clears the selection if there is none
+	fn select_unit(&mut self, event: &EventType) -> bool {
+		if !matches!(event, EventType::KeyDown(key) if key == "v") {
+			return false;
+		}
+		let hex = self.map.hovered_hex();
+		self.selected_unit = self.units.at(hex).find(|unit| Some(unit.owner) == self.country).map(|unit| unit.id);
+		true
+	}
+
+	/// Orders the selected unit to move to a hex along the cheapest path. The server checks the path with the same costs.
+	fn move_order(&self, goal: HexCoord) -> Option<Order> {
+		let unit = self.units.get(self.selected_unit?)?;
+		let path = find_path(&self.map.height_map, &self.map.borders, &self.diplomacy, unit.owner, unit.position, goal, &MovementCosts::default())?;
+		Some(Order::Move {
+			unit: unit.id,
+			path: path.hexes[1..].to_vec(),
+		})
+	}
+
+	/// Gives an order for the hovered hex, such as deforming it, capturing it or building on it. The server sends the resulting changes back to every client.
+	fn act(&mut self, event: &EventType) -> bool {
+		let EventType::KeyDown(key) = event else {
+			return false;
+		};
+		let hex = self.map.hovered_hex();
+		let order = match key.as_str() {
+			"c" => Order::Deform(Deformation::Crater { centre: hex, radius: 3, depth: 40 }),
+			"f" => Order::Deform(Deformation::Flood { hex }),
+			"x" => Order::Deform(Deformation::CollapseCliffs { centre: hex, radius: 3, max_step: 5 }),
+			"t" => Order::Capture { hex, whole_region: false },
+			"r" => Order::Capture { hex, whole_region: true },
+			"1" => Order::PlaceBuilding { hex, kind: BuildingKind::Sawmill },
+			"2" => Order::PlaceBuilding { hex, kind: BuildingKind::Farm },
+			"3" => Order::PlaceBuilding { hex, kind: BuildingKind::Mine },
+			"m" => match self.move_order(hex) {
+				Some(order) => order,
+				None => {
+					warn!("No unit is selected or it cannot reach {hex:?}");
+					return true;
+				}
+			},
+			_ => return false,
+		};
+		// In turn-based games orders wait for the end of the turn
+		match (self.turn, order) {
+			(Some(_), order) => self.orders.push(order),
+			(None, Order::PlaceBuilding { hex, kind }) => self.outgoing.push(ClientMessage::PlaceBuilding { hex, kind }),
+			(None, Order::Move { unit, path }) => self.outgoing.push(ClientMessage::MoveUnit { unit, path }),
+			(None, order) => self.outgoing.push(ClientMessage::Act(order)),
+		}
+		true
+	}
+
 	fn hover(&mut self, event: &EventType) -> bool {
 		if let EventType::PointerMove(_delta) = event {
 			self.map.update_hover(self.projection_mat(), self.view_mat(), self.input.mouse_pos.as_vec2() / self.viewport.as_vec2());
@@ -139,10 +598,9 @@ impl Application {
 	/// Constructs a new application based on the specified game state, glow context and assets
 	pub fn new(mut game_state: GameState, context: glow::Context, mut assets: Assets) -> Result<Self, ErrorKind> {
 		let mut renderer = OpenGl::new(context);
-		game_state.map.load(assets.take("map"));
+		game_state.map.load(assets.take("map")).map_err(|e| ErrorKind::LoadMap(e.to_string()))?;
 		game_state.terrain.load(&assets.get("heightmap"));
-		let (vertices, indices) = game_state.map.height_map.generate_terrain();
-		renderer.init(&vertices, &indices, &game_state)?;
+		renderer.init(&game_state)?;
 		renderer.font.add_font(&assets, "regular");
 
 		let mut input_layers = Default::default();
@@ -161,6 +619,8 @@ impl Application {
 		self.event(EventType::Update);
 		self.renderer.rerender(&self.game_state);
 		self.game_state.map.updated = false;
+		self.game_state.map.dirty_chunks.clear();
+		self.game_state.map.changed_countries.clear();
 		//Mat4::orthographic_rh_gl(left, right, bottom, top, near, far)
 
 		// self.canvas.set_size(width, height, dpi_factor as f32);
@@ -195,6 +655,7 @@ pub enum ErrorKind {
 	VertexArray(String),
 	IndexArray(String),
 	InstanceArray(String),
+	LoadMap(String),
 }
 
 impl core::fmt::Display for ErrorKind {
@@ -210,6 +671,7 @@ impl core::fmt::Display for ErrorKind {
 				ErrorKind::VertexArray(x) => x,
 				ErrorKind::IndexArray(x) => x,
 				ErrorKind::InstanceArray(x) => x,
+				ErrorKind::LoadMap(x) => x,
 			}
 		)
 	}
@@ -1,5 +1,5 @@
 use geonext_shared::{
-	map_loader::{HeightMap, HexCoord},
+	map_loader::{ChunkId, HeightMap, HeightMapError, HexCoord},
 	territories::{CountryId, Territories},
 };
 use glam::{Mat4, UVec2, Vec2, Vec3};
@@ -12,11 +12,33 @@ pub struct Map {
 	pub borders: Territories,
 	hovered: UVec2,
 	pub updated: bool,
+	/// Terrain chunks that need their mesh regenerating on the next frame
+	pub dirty_chunks: Vec<ChunkId>,
+	/// Countries whose borders need re-tracing on the next frame
+	pub changed_countries: Vec<CountryId>,
 }
 
 impl Map {
-	pub fn load(&mut self, map: Vec<u8>) {
-		self.height_map.load(map);
+	pub fn load(&mut self, map: Vec<u8>) -> Result<(), HeightMapError> {
+		self.height_map.load(map)
+	}
+
+	/// Schedules the terrain around hexes whose elevation or shading has changed to be rebuilt
+	pub fn terrain_changed(&mut self, hexes: impl IntoIterator<Item = HexCoord>) {
+		for chunk in self.height_map.chunks_touched(hexes) {
+			if !self.dirty_chunks.contains(&chunk) {
+				self.dirty_chunks.push(chunk);
+			}
+		}
+	}
+
+	/// Schedules the borders of countries that gained or lost hexes to be re-traced
+	pub fn countries_changed(&mut self, countries: impl IntoIterator<Item = CountryId>) {
+		for country in countries {
+			if !self.changed_countries.contains(&country) {
+				self.changed_countries.push(country);
+			}
+		}
 	}
 
 	pub fn update_hover(&mut self, projection: Mat4, view: Mat4, normalised_mouse: Vec2) {
@@ -28,14 +50,15 @@ impl Map {
 		let ray_direction = (view.inverse() * dir_eye).truncate().normalize();
 		let ray_origin = view.inverse().w_axis.truncate();
 
-		let hovered = (0..self.height_map.width as i32)
-			.flat_map(|x| (0..self.height_map.height as i32).map(move |y| (x, y)))
-			.find(|&(x, y)| HexCoord::from_offset(x, y).intersect_ray(0., ray_origin, ray_direction).is_some());
-		let Some((x, y)) = hovered else {
+		let Some(hovered) = self.height_map.intersect_ray(ray_origin, ray_direction) else {
 			return;
 		};
 
-		self.hovered = UVec2::new(x as u32, y as u32);
+		self.hovered = hovered.to_offset().as_uvec2();
+	}
+
+	pub fn hovered_hex(&self) -> HexCoord {
+		HexCoord::from_offset(self.hovered.x as i32, self.hovered.y as i32)
 	}
 
 	pub fn hovered_name(&self) -> &str {
@@ -1,6 +1,8 @@
+use std::collections::HashMap;
 use std::rc::Rc;
 
-use geonext_shared::map_loader::HexCoord;
+use geonext_shared::economy::BuildingKind;
+use geonext_shared::map_loader::ChunkId;
 use glam::Vec3;
 use glow::{Context, HasContext};
 
@@ -54,7 +56,7 @@ impl Programs {
 
 /// Contains the glow opengl state
 pub struct OpenGl {
-	terrain: Option<SceneRender>,
+	terrain: HashMap<ChunkId, SceneRender>,
 	sawmill: Option<SceneRender>,
 	mine: Option<SceneRender>,
 	farm: Option<SceneRender>,
@@ -72,7 +74,7 @@ impl OpenGl {
 	pub fn new(context: glow::Context) -> Self {
 		let context = Rc::new(context);
 		Self {
-			terrain: None,
+			terrain: HashMap::new(),
 			sawmill: None,
 			farm: None,
 			mine: None,
@@ -101,16 +103,12 @@ impl OpenGl {
 	}
 
 	/// Initalise opengl
-	pub fn init(&mut self, verts: &[f32], indices: &[u32], game_state: &GameState) -> Result<(), ErrorKind> {
+	pub fn init(&mut self, game_state: &GameState) -> Result<(), ErrorKind> {
 		self.setup_opengl();
 		self.font.init()?;
 		self.programs = Some(Programs::load_shaders(&self.context)?);
 
-		unsafe {
-			let (_, indices_data, _) = indices.align_to();
-			let (_, vert_data, _) = verts.align_to();
-			self.terrain = Some(SceneRender::new(self.context.clone(), vert_data, indices_data)?);
-		}
+		self.rebuild_chunks(game_state, game_state.map.height_map.chunks())?;
 
 		let to_scene = |dat: &[u8]| {
 			let len_vert = u32::from_le_bytes([dat[0], dat[1], dat[2], dat[3]]) as usize;
@@ -130,6 +128,11 @@ impl OpenGl {
 
 	/// Renders a frame
 	pub fn rerender(&mut self, game_state: &GameState) {
+		if !game_state.map.dirty_chunks.is_empty() {
+			if let Err(e) = self.rebuild_chunks(game_state, game_state.map.dirty_chunks.iter().copied()) {
+				error!("Error rebuilding terrain {e}");
+			}
+		}
 		let Some(Programs {
 			scene_program,
 			border_program,
@@ -144,24 +147,42 @@ impl OpenGl {
 				Ok(borders) => self.border = Some(borders),
 				Err(e) => error!("Error generating borders {e}"),
 			}
+		} else if !game_state.map.changed_countries.is_empty() {
+			if let Some(border) = &mut self.border {
+				unsafe { border.retrace(&game_state.map, &game_state.map.changed_countries) };
+			}
 		}
 		unsafe {
 			self.context.clear_color(28. / 255., 27. / 255., 34. / 255., 1.);
 			self.context.clear(glow::COLOR_BUFFER_BIT | glow::DEPTH_BUFFER_BIT);
 		}
-		if let Some(terrain) = &self.terrain {
+		for terrain in self.terrain.values() {
 			unsafe { terrain.render(&scene_program, game_state, &[Vec3::ZERO]) };
 		}
 
 		if let Some(border) = &self.border {
 			unsafe { border.render(&border_program, game_state) };
 		}
-		if let Some(sawmill) = &self.sawmill {
-			let val = (0..game_state.map.height_map.height)
-				.flat_map(|y| (0..game_state.map.height_map.width).map(move |x| game_state.map.height_map.hex_centre(x, y)))
+		// Each kind of building is drawn with one instanced call
+		for (kind, scene) in [(BuildingKind::Sawmill, &self.sawmill), (BuildingKind::Farm, &self.farm), (BuildingKind::Mine, &self.mine)] {
+			let Some(scene) = scene else { continue };
+			let positions = game_state
+				.buildings
+				.iter()
+				.filter(|(_, building)| building.kind == kind)
+				.map(|(hex, _)| hex.to_offset().as_uvec2())
+				.map(|offset| game_state.map.height_map.hex_centre(offset.x, offset.y))
 				.collect::<Vec<_>>();
+			if !positions.is_empty() {
+				unsafe { scene.render(&scene_program, game_state, &positions) };
+			}
+		}
 
-			unsafe { sawmill.render(&scene_program, game_state, &val) };
+		if let Some(army) = &self.army {
+			let positions = game_state.units.iter().map(|unit| game_state.unit_position(unit)).collect::<Vec<_>>();
+			if !positions.is_empty() {
+				unsafe { army.render(&scene_program, game_state, &positions) };
+			}
 		}
 
 		// UI must be last so it doesn't cause artifact
@@ -170,6 +191,20 @@ impl OpenGl {
 		}
 	}
 
+	/// Regenerates the terrain mesh for the specified chunks, replacing their old buffers. Hexes that cannot be seen are darkened.
+	pub fn rebuild_chunks(&mut self, game_state: &GameState, chunks: impl IntoIterator<Item = ChunkId>) -> Result<(), ErrorKind> {
+		for chunk in chunks {
+			let (verts, indices) = game_state.map.height_map.generate_chunk_shaded(chunk, |hex| game_state.brightness(hex));
+			let scene = unsafe {
+				let (_, indices_data, _) = indices.align_to();
+				let (_, vert_data, _) = verts.align_to();
+				SceneRender::new(self.context.clone(), vert_data, indices_data)?
+			};
+			self.terrain.insert(chunk, scene);
+		}
+		Ok(())
+	}
+
 	/// Resizes the viewport
 	pub fn resize(&mut self, game_state: &GameState) {
 		unsafe {
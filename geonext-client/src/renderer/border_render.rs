@@ -1,31 +1,44 @@
 use super::program::Program;
 use crate::{map::Map, ErrorKind, GameState};
 use geonext_shared::{
-	map_loader::{Channel, HeightMap, HexCoord},
+	map_loader::{HeightMap, HexCoord},
 	territories::CountryId,
 };
 use glam::{Mat4, UVec2, Vec2, Vec3};
 use glow::{Context, HasContext};
-use std::{collections::HashSet, rc::Rc};
+use std::{
+	collections::{HashMap, HashSet},
+	rc::Rc,
+};
+
+type Vertex = (u32, Vec3, Vec3, Vec3, Vec2);
+
+/// The border geometry of a single country, with indices relative to its own vertices
+#[derive(Default)]
+struct CountryOutline {
+	verts: Vec<Vertex>,
+	indices: Vec<u32>,
+}
 
 pub struct BorderRender {
 	vertex_array: <glow::Context as glow::HasContext>::VertexArray,
 	vertex_buffer: <glow::Context as glow::HasContext>::Buffer,
+	indices_buffer: <glow::Context as glow::HasContext>::Buffer,
 	context: Rc<glow::Context>,
-	verts: Vec<(u32, Vec3, Vec3, Vec3, Vec2)>,
-	indices: Vec<u32>,
+	outlines: HashMap<CountryId, CountryOutline>,
+	index_count: usize,
 }
 
-impl BorderRender {
-	fn trace_country(&mut self, start_hex: HexCoord, map: &Map, index: &mut u32, country_id: CountryId, visited: &mut HashSet<HexCoord>) {
-		let [primary, secondary] = country_id.colours();
+impl CountryOutline {
+	fn trace(&mut self, start_hex: HexCoord, map: &Map, country_id: CountryId, visited: &mut HashSet<HexCoord>) {
+		let mut index = self.verts.len() as u32;
+		let [primary, secondary] = map.borders.colours(country_id);
 		let mut direction = HexCoord::TOP_LEFT;
 		let mut current_hex = start_hex;
-		let mut current_height = HeightMap::elevation_to_z(map.height_map.sample_at(Channel::TOPO, current_hex.to_offset().as_uvec2()));
+		let mut current_height = HeightMap::elevation_to_z(map.height_map.elevation(current_hex).unwrap_or_default());
 
 		let mut started = false;
-		let start_index = *index;
-		let indices_start = self.indices.len();
+		let start_index = index;
 		while !(direction == HexCoord::TOP_LEFT && current_hex == start_hex) || !started {
 			let start_corner = current_hex.corner(direction.rotate_anticlockwise());
 			let middle_corner = current_hex.corner(direction);
@@ -34,7 +47,7 @@ impl BorderRender {
 			let proposed_offset = (current_hex + direction).to_offset();
 			if map.height_map.in_bounds(proposed_offset) && country_id == map.borders.country_id(proposed_offset.as_uvec2()) {
 				current_hex = current_hex + direction;
-				current_height = HeightMap::elevation_to_z(map.height_map.sample_at(Channel::TOPO, current_hex.to_offset().as_uvec2()));
+				current_height = HeightMap::elevation_to_z(map.height_map.elevation(current_hex).unwrap_or_default());
 				direction = direction.rotate_anticlockwise();
 			} else {
 				direction = direction.rotate_clockwise();
@@ -53,25 +66,29 @@ impl BorderRender {
 			]);
 
 			if started {
-				self.indices.extend([*index - 2, *index - 1, *index, *index, *index - 1, *index + 1]);
-				self.indices.extend([*index, *index + 1, *index + 2, *index + 2, *index + 1, *index + 3]);
+				self.indices.extend([index - 2, index - 1, index, index, index - 1, index + 1]);
+				self.indices.extend([index, index + 1, index + 2, index + 2, index + 1, index + 3]);
 			}
-			*index += 4;
+			index += 4;
 
 			if direction == HexCoord::TOP_LEFT {
 				visited.insert(current_hex);
 			}
 			started = true;
 		}
-		if *index != start_index {
-			self.indices.extend([start_index, start_index + 1, *index - 2, *index - 2, start_index + 1, *index - 1]);
+		if index != start_index {
+			self.indices.extend([start_index, start_index + 1, index - 2, index - 2, start_index + 1, index - 1]);
 		}
 	}
+}
 
-	fn map_gen(&mut self, map: &Map) {
-		self.verts.clear();
-		self.indices.clear();
-		let mut index = 0;
+impl BorderRender {
+	/// Traces the borders of the specified countries, or every country if `only` is `None`
+	fn trace_countries(&mut self, map: &Map, only: Option<&[CountryId]>) {
+		match only {
+			Some(countries) => self.outlines.retain(|country, _| !countries.contains(country)),
+			None => self.outlines.clear(),
+		}
 
 		let mut visited = HashSet::new();
 		for y in 0..map.borders.height() {
@@ -82,7 +99,7 @@ impl BorderRender {
 					continue;
 				}
 				previous_country_id = Some(country_id);
-				if country_id == CountryId::SEA {
+				if country_id == CountryId::SEA || only.is_some_and(|only| !only.contains(&country_id)) {
 					continue;
 				}
 
@@ -91,11 +108,42 @@ impl BorderRender {
 					continue;
 				}
 
-				self.trace_country(start_hex, map, &mut index, country_id, &mut visited);
+				self.outlines.entry(country_id).or_default().trace(start_hex, map, country_id, &mut visited);
 			}
 		}
 	}
 
+	/// Re-traces only the borders of countries that have gained or lost hexes
+	pub unsafe fn retrace(&mut self, map: &Map, countries: &[CountryId]) {
+		self.trace_countries(map, Some(countries));
+		self.upload();
+	}
+
+	/// Joins the outlines of every country and copies them to the gpu
+	unsafe fn upload(&mut self) {
+		let mut verts = Vec::new();
+		let mut indices = Vec::new();
+		for outline in self.outlines.values() {
+			let base = verts.len() as u32;
+			verts.extend_from_slice(&outline.verts);
+			indices.extend(outline.indices.iter().map(|index| base + index));
+		}
+		self.index_count = indices.len();
+
+		let (_, indices_data, _) = indices.align_to();
+		let (s, vert_data, e) = verts.align_to();
+		assert_eq!(s.len(), 0);
+		assert_eq!(e.len(), 0);
+
+		self.context.bind_vertex_array(Some(self.vertex_array));
+		self.context.bind_buffer(glow::ARRAY_BUFFER, Some(self.vertex_buffer));
+		self.context.buffer_data_u8_slice(glow::ARRAY_BUFFER, vert_data, glow::STATIC_DRAW);
+		self.context.bind_buffer(glow::ELEMENT_ARRAY_BUFFER, Some(self.indices_buffer));
+		self.context.buffer_data_u8_slice(glow::ELEMENT_ARRAY_BUFFER, indices_data, glow::STATIC_DRAW);
+		self.context.bind_buffer(glow::ARRAY_BUFFER, None);
+		self.context.bind_vertex_array(None);
+	}
+
 	pub unsafe fn new(context: Rc<Context>, map: &Map) -> Result<Self, ErrorKind> {
 		info!("Width {} height {}", map.height_map.width, map.height_map.height);
 
@@ -108,28 +156,23 @@ impl BorderRender {
 		let mut border = Self {
 			vertex_array,
 			vertex_buffer,
+			indices_buffer,
 			context: context.clone(),
-			verts: Vec::new(),
-			indices: Vec::new(),
+			outlines: HashMap::new(),
+			index_count: 0,
 		};
-		border.map_gen(map);
+		border.trace_countries(map, None);
+		border.upload();
 
-		let (_, indices_data, _) = border.indices.align_to();
-		let (s, vert_data, e) = border.verts.align_to();
-		assert_eq!(s.len(), 0);
-		assert_eq!(e.len(), 0);
 		let stride = core::mem::size_of::<f32>() as i32 * 12;
-		assert_eq!(vert_data.len(), border.verts.len() * stride as usize);
+		assert_eq!(core::mem::size_of::<Vertex>(), stride as usize);
 
 		// bind the Vertex Array Object first, then bind and set vertex buffer(s), and then configure vertex attributes(s).
 
 		context.bind_vertex_array(Some(vertex_array));
 
 		context.bind_buffer(glow::ARRAY_BUFFER, Some(vertex_buffer));
-		context.buffer_data_u8_slice(glow::ARRAY_BUFFER, vert_data, glow::STATIC_DRAW);
-
 		context.bind_buffer(glow::ELEMENT_ARRAY_BUFFER, Some(indices_buffer));
-		context.buffer_data_u8_slice(glow::ELEMENT_ARRAY_BUFFER, indices_data, glow::STATIC_DRAW);
 
 		context.vertex_attrib_pointer_f32(0, 3, glow::FLOAT, false, stride, core::mem::size_of::<f32>() as i32 * 1);
 		context.enable_vertex_attrib_array(0);
@@ -165,7 +208,7 @@ impl BorderRender {
 		scene_program.set_uint("select", game_state.map.hovered_country().0 as u32);
 
 		self.context.bind_vertex_array(Some(self.vertex_array));
-		self.context.draw_elements(glow::TRIANGLES, self.indices.len() as i32, glow::UNSIGNED_INT, 0);
+		self.context.draw_elements(glow::TRIANGLES, self.index_count as i32, glow::UNSIGNED_INT, 0);
 	}
 }
 
@@ -173,5 +216,6 @@ impl Drop for BorderRender {
 	fn drop(&mut self) {
 		unsafe { self.context.delete_vertex_array(self.vertex_array) };
 		unsafe { self.context.delete_buffer(self.vertex_buffer) };
+		unsafe { self.context.delete_buffer(self.indices_buffer) };
 	}
 }
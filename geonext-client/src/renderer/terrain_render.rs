@@ -7,6 +7,7 @@ use std::rc::Rc;
 pub struct SceneRender {
 	vertex_array: <glow::Context as glow::HasContext>::VertexArray,
 	vertex_buffer: <glow::Context as glow::HasContext>::Buffer,
+	indices_buffer: <glow::Context as glow::HasContext>::Buffer,
 	indicies_count: usize,
 	instances_buffer: <glow::Context as glow::HasContext>::Buffer,
 	context: Rc<glow::Context>,
@@ -53,6 +54,7 @@ impl SceneRender {
 			vertex_array,
 			indicies_count: indices.len() / core::mem::size_of::<u32>() as usize,
 			vertex_buffer,
+			indices_buffer,
 			instances_buffer,
 			context,
 		})
@@ -85,5 +87,8 @@ impl Drop for SceneRender {
 	fn drop(&mut self) {
 		unsafe { self.context.delete_vertex_array(self.vertex_array) };
 		unsafe { self.context.delete_buffer(self.vertex_buffer) };
+		// Terrain chunks are rebuilt during play, so all of the buffers must be freed
+		unsafe { self.context.delete_buffer(self.indices_buffer) };
+		unsafe { self.context.delete_buffer(self.instances_buffer) };
 	}
 }
@@ -78,6 +78,8 @@ impl TextRender {
 			child: Flex {
 				children: (
 					TextNode::new(font, &"GeoNext Alpha", "regular", 1.),
+					TextNode::new(font, &game_state.economy.map(|economy| economy.stockpile.to_string()).unwrap_or_default(), "regular", 1.),
+					TextNode::new(font, game_state.diplomatic_news.as_deref().unwrap_or_default(), "regular", 1.),
 					TextNode::new(font, &format!("Peek: {}ms", game_state.time.peak_frametime().round()), "regular", 1.),
 				),
 				main_axis_alignment: MainAxisAlignment::SpaceBetween,
@@ -86,9 +88,13 @@ impl TextRender {
 			margin: 10.,
 			..default()
 		};
+		let hovered = match game_state.hovered_relation() {
+			Some(relation) => format!("{} ({relation})", game_state.map.hovered_name()),
+			None => game_state.map.hovered_name().to_string(),
+		};
 		let tooltip = Tooltip {
 			child: Container {
-				child: TextNode::new(font, game_state.map.hovered_name(), "regular", 1.),
+				child: TextNode::new(font, &hovered, "regular", 1.),
 				margin: 10.,
 				..default()
 			},
@@ -108,6 +114,54 @@ impl TextRender {
 			},
 		);
 
+		if let Some(result) = &game_state.game_over {
+			let name = |country| game_state.map.borders.get_name(country);
+			let headline = match result.winner {
+				Some(winner) if Some(winner) == game_state.country => format!("Victory by {}!", result.reason),
+				Some(winner) => format!("{} won by {}", name(winner), result.reason),
+				None => format!("Draw by {}", result.reason),
+			};
+			let standings = result
+				.standings
+				.iter()
+				.enumerate()
+				.map(|(rank, standing)| {
+					format!(
+						"{}. {}: {} ({} hexes, {} strength)",
+						rank + 1,
+						name(standing.country),
+						standing.score,
+						standing.hexes,
+						standing.strength
+					)
+				})
+				.collect::<Vec<_>>()
+				.join("   ");
+			let mut summary = Container {
+				child: Flex {
+					children: (
+						TextNode::new(font, &headline, "regular", 1.5),
+						TextNode::new(font, &standings, "regular", 1.),
+						TextNode::new(font, "Press Escape to return to the lobby", "regular", 1.),
+					),
+					direction: Axis::Vertical,
+					main_axis_alignment: MainAxisAlignment::Center,
+					cross_axis_alignment: CrossAxisAlignment::Center,
+					..default()
+				},
+				margin: 40.,
+				..default()
+			};
+			summary.layout(BoxConstraint::tight(game_state.viewport.as_dvec2()));
+			summary.render(
+				glam::DVec2::ZERO,
+				&mut UiRenderer {
+					cache: font,
+					instances: self.instance_buffer,
+				},
+			);
+		}
+
 		self.context.bind_vertex_array(None);
 		self.context.bind_texture(glow::TEXTURE_2D, None);
 	}
@@ -0,0 +1,174 @@
+use crate::economy::{Building, BuildingKind, Economy, Resources};
+use crate::map_loader::{Channel, HeightMap, HexCoord};
+use crate::territories::{CountryId, Territories};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Sawmills need at least this much vegetation
+const MIN_SAWMILL_VEGETATION: u8 = 64;
+/// Farms cannot be built at or above this elevation
+const MAX_FARM_ELEVATION: u8 = 128;
+/// Mines need at least this elevation
+const MIN_MINE_ELEVATION: u8 = 64;
+
+impl BuildingKind {
+	/// The resources needed to place the building
+	pub fn cost(self) -> Resources {
+		match self {
+			Self::Sawmill => Resources::new(30, 10, 0),
+			Self::Farm => Resources::new(40, 0, 0),
+			Self::Mine => Resources::new(50, 20, 0),
+		}
+	}
+
+	/// Whether the terrain of a land hex suits the building
+	pub fn suits(self, elevation: u8, vegetation: u8) -> bool {
+		match self {
+			Self::Sawmill => vegetation >= MIN_SAWMILL_VEGETATION,
+			Self::Farm => elevation < MAX_FARM_ELEVATION,
+			Self::Mine => elevation >= MIN_MINE_ELEVATION,
+		}
+	}
+}
+
+/// The reasons a building cannot be placed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PlacementError {
+	OutOfBounds(HexCoord),
+	Sea(HexCoord),
+	/// The hex is owned by another country
+	NotOwned(HexCoord),
+	/// The hex already has a building
+	Occupied(HexCoord),
+	Unsuitable {
+		hex: HexCoord,
+		kind: BuildingKind,
+	},
+	CannotAfford {
+		cost: Resources,
+		stockpile: Resources,
+	},
+}
+
+impl core::fmt::Display for PlacementError {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		match self {
+			Self::OutOfBounds(hex) => write!(f, "Hex {hex:?} is outside the map"),
+			Self::Sea(hex) => write!(f, "Hex {hex:?} is sea"),
+			Self::NotOwned(hex) => write!(f, "Hex {hex:?} is not owned by your country"),
+			Self::Occupied(hex) => write!(f, "Hex {hex:?} already has a building"),
+			Self::Unsuitable { hex, kind } => write!(f, "The terrain of hex {hex:?} is not suitable for a {kind:?}"),
+			Self::CannotAfford { cost, stockpile } => write!(f, "A cost of {cost} cannot be paid from {stockpile}"),
+		}
+	}
+}
+
+impl std::error::Error for PlacementError {}
+
+/// Every building in a game, by hex
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Buildings {
+	buildings: HashMap<HexCoord, Building>,
+}
+
+impl Buildings {
+	pub fn get(&self, hex: HexCoord) -> Option<Building> {
+		self.buildings.get(&hex).copied()
+	}
+	/// Every building, in no particular order
+	pub fn iter(&self) -> impl Iterator<Item = (HexCoord, Building)> + '_ {
+		self.buildings.iter().map(|(&hex, &building)| (hex, building))
+	}
+	pub fn len(&self) -> usize {
+		self.buildings.len()
+	}
+	pub fn is_empty(&self) -> bool {
+		self.buildings.is_empty()
+	}
+	/// Adds or replaces a building without any checks, for example when applying changes from the server
+	pub fn insert(&mut self, hex: HexCoord, building: Building) {
+		self.buildings.insert(hex, building);
+	}
+	pub fn clear(&mut self) {
+		self.buildings.clear();
+	}
+
+	/// Checks that `country` can place a building on a hex
+	pub fn check_placement(&self, economy: &Economy, territories: &Territories, height_map: &HeightMap, country: CountryId, hex: HexCoord, kind: BuildingKind) -> Result<(), PlacementError> {
+		let elevation = height_map.elevation(hex).ok_or(PlacementError::OutOfBounds(hex))?;
+		if HeightMap::is_sea(elevation) {
+			return Err(PlacementError::Sea(hex));
+		}
+		if territories.hex_owner(hex) != Some(country) {
+			return Err(PlacementError::NotOwned(hex));
+		}
+		if self.buildings.contains_key(&hex) {
+			return Err(PlacementError::Occupied(hex));
+		}
+		let vegetation = height_map.sample_at(Channel::VEG, hex.to_offset().as_uvec2()).unwrap_or(0);
+		if !kind.suits(elevation, vegetation) {
+			return Err(PlacementError::Unsuitable { hex, kind });
+		}
+		let stockpile = economy.stockpile(country);
+		if !stockpile.contains(&kind.cost()) {
+			return Err(PlacementError::CannotAfford { cost: kind.cost(), stockpile });
+		}
+		Ok(())
+	}
+
+	/// Places a level 1 building, paying for it from the country's stockpile
+	pub fn place(&mut self, economy: &mut Economy, territories: &Territories, height_map: &HeightMap, country: CountryId, hex: HexCoord, kind: BuildingKind) -> Result<Building, PlacementError> {
+		self.check_placement(economy, territories, height_map, country, hex, kind)?;
+		economy.spend(country, kind.cost());
+		let building = Building { kind, level: 1 };
+		self.buildings.insert(hex, building);
+		Ok(building)
+	}
+}
+
+#[test]
+fn placing_buildings() {
+	let (height_map, territories) = crate::test_utils::map();
+	let crate::test_utils::Border { congo_hex, zambia_hex, congo, .. } = crate::test_utils::border(&territories);
+	let mut economy = Economy::default();
+	let mut buildings = Buildings::default();
+	let mut place = |economy: &mut Economy, hex, kind| buildings.place(economy, &territories, &height_map, congo, hex, kind);
+
+	let cost = BuildingKind::Sawmill.cost();
+	assert_eq!(
+		place(&mut economy, congo_hex, BuildingKind::Sawmill),
+		Err(PlacementError::CannotAfford {
+			cost,
+			stockpile: Resources::default()
+		})
+	);
+	economy.add(congo, Resources::new(1000, 1000, 1000));
+	assert_eq!(
+		place(&mut economy, congo_hex, BuildingKind::Sawmill),
+		Ok(Building {
+			kind: BuildingKind::Sawmill,
+			level: 1
+		})
+	);
+	assert_eq!(economy.stockpile(congo), Resources::new(970, 990, 1000));
+	assert_eq!(place(&mut economy, congo_hex, BuildingKind::Farm), Err(PlacementError::Occupied(congo_hex)));
+	assert_eq!(place(&mut economy, zambia_hex, BuildingKind::Farm), Err(PlacementError::NotOwned(zambia_hex)));
+	let sea = HexCoord::from_offset(0, 0);
+	assert_eq!(place(&mut economy, sea, BuildingKind::Farm), Err(PlacementError::Sea(sea)));
+	assert_eq!(
+		place(&mut economy, HexCoord::new(-1000, 0), BuildingKind::Farm),
+		Err(PlacementError::OutOfBounds(HexCoord::new(-1000, 0)))
+	);
+	assert_eq!(economy.stockpile(congo), Resources::new(970, 990, 1000), "Failed placements cost nothing");
+	assert_eq!(buildings.len(), 1);
+}
+
+#[test]
+fn terrain_suitability() {
+	assert!(BuildingKind::Sawmill.suits(100, MIN_SAWMILL_VEGETATION));
+	assert!(!BuildingKind::Sawmill.suits(100, MIN_SAWMILL_VEGETATION - 1));
+	assert!(BuildingKind::Farm.suits(MAX_FARM_ELEVATION - 1, 0));
+	assert!(!BuildingKind::Farm.suits(MAX_FARM_ELEVATION, 255));
+	assert!(BuildingKind::Mine.suits(MIN_MINE_ELEVATION, 0));
+	assert!(!BuildingKind::Mine.suits(MIN_MINE_ELEVATION - 1, 255));
+}
@@ -0,0 +1,255 @@
+use crate::combat::{Battle, BattleResult};
+use crate::diplomacy::Diplomacy;
+use crate::map_loader::{HeightMap, HexCoord};
+use crate::regions::{RegionId, Regions};
+use crate::territories::{CountryId, Territories, TerritoryDelta};
+use crate::units::{UnitId, Units};
+
+/// The defence of each hex even when it has no army
+pub const GARRISON: u16 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CaptureError {
+	OutOfBounds(HexCoord),
+	Sea(HexCoord),
+	AlreadyOwned(HexCoord),
+	NoRegion(HexCoord),
+	NoAdjacentArmy,
+	NotAtWar(CountryId),
+}
+
+impl core::fmt::Display for CaptureError {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		match self {
+			Self::OutOfBounds(hex) => write!(f, "Hex {hex:?} is outside the map"),
+			Self::Sea(hex) => write!(f, "Hex {hex:?} is sea and cannot be captured"),
+			Self::AlreadyOwned(hex) => write!(f, "Hex {hex:?} is already owned"),
+			Self::NoRegion(hex) => write!(f, "Hex {hex:?} is not part of a region"),
+			Self::NoAdjacentArmy => write!(f, "None of your armies are next to the target"),
+			Self::NotAtWar(country) => write!(f, "You are not at war with {country:?}"),
+		}
+	}
+}
+
+impl std::error::Error for CaptureError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capture {
+	pub attacker: CountryId,
+	pub defender: CountryId,
+	pub army: UnitId,
+	pub attack: u16,
+	pub target: HexCoord,
+	pub hexes: Vec<HexCoord>,
+	pub region: Option<RegionId>,
+	pub defence: u16,
+	pub defenders: Vec<UnitId>,
+}
+
+/// The whole region is only captured if the attacker is at war with the owner of every hex in it
+pub fn plan_capture(territories: &Territories, regions: &Regions, units: &Units, diplomacy: &Diplomacy, attacker: CountryId, hex: HexCoord, whole_region: bool) -> Result<Capture, CaptureError> {
+	let defender = match territories.hex_owner(hex) {
+		None => return Err(CaptureError::OutOfBounds(hex)),
+		Some(CountryId::SEA) => return Err(CaptureError::Sea(hex)),
+		Some(owner) if owner == attacker => return Err(CaptureError::AlreadyOwned(hex)),
+		Some(owner) => owner,
+	};
+	let (hexes, region) = if whole_region {
+		let region = regions.region_at(hex).ok_or(CaptureError::NoRegion(hex))?;
+		let hexes = regions
+			.hexes(region)
+			.iter()
+			.copied()
+			.filter(|&hex| !matches!(territories.hex_owner(hex), Some(owner) if owner == attacker || owner == CountryId::SEA));
+		(hexes.collect::<Vec<_>>(), Some(region))
+	} else {
+		(vec![hex], None)
+	};
+	if let Some(owner) = hexes.iter().filter_map(|&hex| territories.hex_owner(hex)).find(|&owner| !diplomacy.at_war(attacker, owner)) {
+		return Err(CaptureError::NotAtWar(owner));
+	}
+
+	let army = units
+		.owned_by(attacker)
+		.filter(|unit| unit.strength > 0 && hexes.iter().any(|hex| hex.is_neighbour(unit.position)))
+		.max_by_key(|unit| (unit.strength, core::cmp::Reverse(unit.id)))
+		.ok_or(CaptureError::NoAdjacentArmy)?;
+	let mut defending_armies = hexes.iter().flat_map(|&hex| units.at(hex)).filter(|unit| unit.owner != attacker).collect::<Vec<_>>();
+	defending_armies.sort_by_key(|unit| unit.id);
+	let defence = (hexes.len() as u16).saturating_mul(GARRISON).saturating_add(defending_armies.iter().map(|unit| unit.strength).sum());
+	Ok(Capture {
+		attacker,
+		defender,
+		army: army.id,
+		attack: army.strength,
+		target: hex,
+		hexes,
+		region,
+		defence,
+		defenders: defending_armies.iter().map(|unit| unit.id).collect(),
+	})
+}
+
+impl Capture {
+	pub fn battle(&self, height_map: &HeightMap) -> Battle {
+		Battle::new(height_map, self.target, self.attacker, self.defender, self.attack, self.defence)
+	}
+
+	/// Returns the ownership changes, or `None` if the attack was repelled
+	pub fn apply(&self, result: &BattleResult, territories: &mut Territories, regions: &mut Regions, units: &mut Units) -> Option<TerritoryDelta> {
+		// The garrison takes losses before the armies
+		let mut defender_losses = result.defender_losses.saturating_sub((self.hexes.len() as u16).saturating_mul(GARRISON));
+		for &id in &self.defenders {
+			damage(units, id, &mut defender_losses);
+		}
+		let mut attacker_losses = result.attacker_losses;
+		damage(units, self.army, &mut attacker_losses);
+		if !result.attacker_won {
+			return None;
+		}
+		if let Some(army) = units.get_mut(self.army) {
+			if let (None, [hex]) = (self.region, self.hexes.as_slice()) {
+				army.position = *hex;
+				army.path.clear();
+				army.progress = 0;
+			}
+		}
+
+		let delta = match self.region {
+			Some(region) => regions.set_owner(region, self.attacker, territories),
+			None => None,
+		};
+		Some(delta.unwrap_or_else(|| {
+			let changes = self.hexes.iter().map(|hex| hex.to_offset().as_uvec2()).map(|offset| ((offset.x, offset.y), self.attacker));
+			let delta = territories.change_owners(changes.collect::<Vec<_>>());
+			// Keep the owners of regions in step with their hexes
+			let captured_regions = self.hexes.iter().filter_map(|&hex| regions.region_at(hex)).collect::<Vec<_>>();
+			for region in captured_regions {
+				if regions.hexes(region).iter().all(|&hex| territories.hex_owner(hex) == Some(self.attacker)) {
+					regions.set_owner(region, self.attacker, territories);
+				}
+			}
+			delta
+		}))
+	}
+}
+
+fn damage(units: &mut Units, id: UnitId, losses: &mut u16) {
+	let Some(unit) = units.get_mut(id) else { return };
+	let taken = (*losses).min(unit.strength);
+	unit.strength -= taken;
+	*losses -= taken;
+	if unit.strength == 0 {
+		units.remove(id);
+	}
+}
+
+#[cfg(test)]
+use crate::test_utils;
+
+#[test]
+fn capture_hex() {
+	let (height_map, mut territories) = test_utils::map();
+	let mut regions = test_utils::regions(&territories);
+	let test_utils::Border {
+		congo_hex, zambia_hex, congo, zambia, ..
+	} = test_utils::border(&territories);
+	let mut units = Units::default();
+	let mut diplomacy = Diplomacy::default();
+	diplomacy.declare_war(zambia, congo).unwrap();
+	let weak = units.spawn(zambia, zambia_hex, 3);
+	let army = units.spawn(zambia, zambia_hex, 20);
+	let defender = units.spawn(congo, congo_hex, 2);
+
+	let capture = plan_capture(&territories, &regions, &units, &diplomacy, zambia, congo_hex, false).unwrap();
+	assert_eq!((capture.army, capture.attack, capture.defence, capture.region), (army, 20, GARRISON + 2, None));
+	assert_eq!((capture.defender, capture.defenders.as_slice()), (congo, [defender].as_slice()));
+	let result = capture.battle(&height_map).resolve(0);
+	assert!(result.attacker_won, "An army this strong always wins");
+	let delta = capture.apply(&result, &mut territories, &mut regions, &mut units).unwrap();
+
+	assert_eq!(delta.changes.len(), 1);
+	assert_eq!(territories.hex_owner(congo_hex), Some(zambia));
+	assert_eq!(regions.owner(regions.region_at(congo_hex).unwrap()), Some(zambia));
+	assert_eq!(units.get(defender), None);
+	assert_eq!(units.get(army).map(|unit| (unit.position, unit.strength)), Some((congo_hex, 20 - result.attacker_losses)));
+	assert_eq!(units.get(weak).map(|unit| unit.position), Some(zambia_hex));
+}
+
+#[test]
+fn capture_region() {
+	let (height_map, mut territories) = test_utils::map();
+	let mut regions = test_utils::regions(&territories);
+	let test_utils::Border {
+		congo_hex, zambia_hex, congo, zambia, ..
+	} = test_utils::border(&territories);
+	let mut units = Units::default();
+	let mut diplomacy = Diplomacy::default();
+	diplomacy.declare_war(zambia, congo).unwrap();
+	let army = units.spawn(zambia, zambia_hex, 100);
+
+	let capture = plan_capture(&territories, &regions, &units, &diplomacy, zambia, congo_hex, true).unwrap();
+	let region = regions.region_at(congo_hex).unwrap();
+	assert_eq!(capture.region, Some(region));
+	assert_eq!(capture.hexes, regions.hexes(region));
+	let result = capture.battle(&height_map).resolve(0);
+	let delta = capture.apply(&result, &mut territories, &mut regions, &mut units).unwrap();
+	assert_eq!(delta.changes.len(), regions.hexes(region).len());
+	assert_eq!(regions.owner(region), Some(zambia));
+	// The army stays put when capturing a region
+	assert_eq!(units.get(army).map(|unit| unit.position), Some(zambia_hex));
+}
+
+#[test]
+fn repelled_attacks() {
+	let (height_map, mut territories) = test_utils::map();
+	let mut regions = test_utils::regions(&territories);
+	let test_utils::Border {
+		congo_hex, zambia_hex, congo, zambia, ..
+	} = test_utils::border(&territories);
+	let mut units = Units::default();
+	let mut diplomacy = Diplomacy::default();
+	diplomacy.declare_war(zambia, congo).unwrap();
+	let army = units.spawn(zambia, zambia_hex, 4);
+	let (first, second) = (units.spawn(congo, congo_hex, 3), units.spawn(congo, congo_hex, 2));
+
+	let capture = plan_capture(&territories, &regions, &units, &diplomacy, zambia, congo_hex, false).unwrap();
+	let result = BattleResult {
+		battle: capture.battle(&height_map),
+		attacker_won: false,
+		attacker_losses: 2,
+		defender_losses: 4,
+	};
+	assert_eq!(capture.apply(&result, &mut territories, &mut regions, &mut units), None);
+	assert_eq!(territories.hex_owner(congo_hex), Some(congo));
+	assert_eq!(units.get(army).map(|unit| (unit.position, unit.strength)), Some((zambia_hex, 2)));
+	// The garrison takes the first loss, then the armies in id order
+	assert_eq!(units.get(first), None);
+	assert_eq!(units.get(second).map(|unit| unit.strength), Some(2));
+}
+
+#[test]
+fn invalid_captures() {
+	let territories = test_utils::territories();
+	let regions = test_utils::regions(&territories);
+	let test_utils::Border {
+		congo_hex, zambia_hex, congo, zambia, ..
+	} = test_utils::border(&territories);
+	let mut units = Units::default();
+	let mut diplomacy = Diplomacy::default();
+	diplomacy.declare_war(zambia, congo).unwrap();
+	units.spawn(zambia, zambia_hex, 2);
+	units.spawn(congo, congo_hex, 1);
+
+	let capture = |hex| plan_capture(&territories, &regions, &units, &diplomacy, zambia, hex, false);
+	assert!(capture(congo_hex).is_ok(), "Weaker armies can still attack");
+	assert_eq!(capture(zambia_hex), Err(CaptureError::AlreadyOwned(zambia_hex)));
+	assert_eq!(capture(HexCoord::from_offset(0, 0)), Err(CaptureError::Sea(HexCoord::from_offset(0, 0))));
+	assert_eq!(capture(HexCoord::new(-1000, 0)), Err(CaptureError::OutOfBounds(HexCoord::new(-1000, 0))));
+	let chile_hex = HexCoord::new(10, 133);
+	let chile = territories.hex_owner(chile_hex).unwrap();
+	assert_eq!(capture(chile_hex), Err(CaptureError::NotAtWar(chile)));
+	diplomacy.declare_war(zambia, chile).unwrap();
+	let capture = |hex| plan_capture(&territories, &regions, &units, &diplomacy, zambia, hex, false);
+	assert_eq!(capture(chile_hex), Err(CaptureError::NoAdjacentArmy));
+}
@@ -1,5 +1,66 @@
 use serde::{Deserialize, Serialize};
+
+use crate::diplomacy::DiplomaticAction;
+use crate::economy::BuildingKind;
+use crate::lobby::{GameId, GameMode};
+use crate::map_loader::HexCoord;
+use crate::orders::Order;
+use crate::units::UnitId;
+use crate::victory::VictoryConditions;
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum ClientMessage {
-	Auth { code: String },
+	/// Starts the handshake and must be sent before anything else.
+	///
+	/// This must remain the first variant so that its encoding is the same for every protocol version.
+	Hello {
+		protocol_version: u32,
+		client_build: String,
+	},
+	Auth {
+		code: String,
+	},
+	/// Asks the server to resend the whole map of the current game, for example after a [`crate::territories::TerritoryDelta`] was missed
+	RequestMap,
+	/// Sent after reconnecting to rejoin the previous game and receive only the updates that were missed
+	Resume {
+		token: u64,
+	},
+	/// Sent regularly to keep the connection alive and measure latency. The server replies with the same timestamp.
+	Ping {
+		timestamp: f32,
+	},
+	/// Asks for every game in the lobby
+	ListGames,
+	/// Creates a new game and joins it
+	CreateGame {
+		name: String,
+		capacity: u8,
+		mode: GameMode,
+		victory: VictoryConditions,
+	},
+	/// Leaves the current game, if any, and joins another. The server replies with the state of the game.
+	JoinGame {
+		game: GameId,
+	},
+	LeaveGame,
+	/// Carries out an order straight away in a real-time game
+	Act(Order),
+	/// Submits this player's orders for a turn of a turn-based game, replacing any orders already submitted for that turn
+	SubmitOrders {
+		turn: u32,
+		orders: Vec<Order>,
+	},
+	/// Places a building straight away in a real-time game. In turn-based games buildings are placed with [`Order::PlaceBuilding`].
+	PlaceBuilding {
+		hex: HexCoord,
+		kind: BuildingKind,
+	},
+	/// Moves a unit straight away in a real-time game. In turn-based games units are moved with [`Order::Move`].
+	MoveUnit {
+		unit: UnitId,
+		path: Vec<HexCoord>,
+	},
+	/// Declares war, or makes or accepts a proposal. This takes effect straight away, even in turn-based games.
+	Diplomacy(DiplomaticAction),
 }
@@ -0,0 +1,220 @@
+use crate::map_loader::{Channel, HeightMap, HexCoord};
+use crate::territories::CountryId;
+use serde::{Deserialize, Serialize};
+
+/// Rolls are a percentage of each side's strength
+pub const MIN_ROLL: u32 = 80;
+pub const MAX_ROLL: u32 = 120;
+/// Percent per unit of [`HeightMap::elevation_to_z`]
+const ELEVATION_BONUS: f32 = 10.;
+pub const ROUGH_VEGETATION: u8 = 160;
+const ROUGH_BONUS: u32 = 25;
+const RIVER_BONUS: u32 = 20;
+const COAST_BONUS: u32 = 10;
+
+/// Splitmix64, so that the server and replays roll the same numbers
+#[derive(Clone, Debug)]
+pub struct BattleRng(u64);
+
+impl BattleRng {
+	pub fn new(seed: u64) -> Self {
+		Self(seed)
+	}
+
+	pub fn next_u64(&mut self) -> u64 {
+		self.0 = self.0.wrapping_add(0x9E3779B97F4A7C15);
+		let mut z = self.0;
+		z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+		z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+		z ^ (z >> 31)
+	}
+
+	pub fn roll(&mut self, min: u32, max: u32) -> u32 {
+		min + (self.next_u64() % (max - min + 1) as u64) as u32
+	}
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DefenceModifiers {
+	pub elevation: u32,
+	pub vegetation: u32,
+	pub river: u32,
+	pub coast: u32,
+}
+
+impl DefenceModifiers {
+	pub fn at(height_map: &HeightMap, hex: HexCoord) -> Self {
+		let sample = |channel, hex: HexCoord| {
+			Some(hex.to_offset())
+				.filter(|offset| offset.x >= 0 && offset.y >= 0)
+				.and_then(|offset| height_map.sample_at(channel, offset.as_uvec2()))
+		};
+		let Some(elevation) = height_map.elevation(hex) else {
+			return Self::default();
+		};
+		let vegetation = sample(Channel::VEG, hex).unwrap_or(0);
+		let river = core::iter::once(hex).chain(hex.neighbours()).any(|hex| sample(Channel::RIVERS, hex).is_some_and(|river| river > 0));
+		let coast = hex.neighbours().any(|hex| height_map.elevation(hex).is_some_and(HeightMap::is_sea));
+		Self {
+			elevation: (HeightMap::elevation_to_z(elevation).max(0.) * ELEVATION_BONUS) as u32,
+			vegetation: if vegetation >= ROUGH_VEGETATION { ROUGH_BONUS } else { 0 },
+			river: if river { RIVER_BONUS } else { 0 },
+			coast: if coast { COAST_BONUS } else { 0 },
+		}
+	}
+
+	pub fn total(&self) -> u32 {
+		self.elevation + self.vegetation + self.river + self.coast
+	}
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Battle {
+	pub hex: HexCoord,
+	pub attacker: CountryId,
+	pub defender: CountryId,
+	pub attack: u16,
+	pub defence: u16,
+	pub modifiers: DefenceModifiers,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BattleResult {
+	pub battle: Battle,
+	pub attacker_won: bool,
+	pub attacker_losses: u16,
+	pub defender_losses: u16,
+}
+
+impl Battle {
+	pub fn new(height_map: &HeightMap, hex: HexCoord, attacker: CountryId, defender: CountryId, attack: u16, defence: u16) -> Self {
+		Self {
+			hex,
+			attacker,
+			defender,
+			attack,
+			defence,
+			modifiers: DefenceModifiers::at(height_map, hex),
+		}
+	}
+
+	/// The winner loses what the loser rolled but keeps at least 1. A losing attacker falls back with half of its strength.
+	pub fn resolve(self, seed: u64) -> BattleResult {
+		let mut rng = BattleRng::new(seed);
+		let (attack_roll, defence_roll) = (rng.roll(MIN_ROLL, MAX_ROLL), rng.roll(MIN_ROLL, MAX_ROLL));
+		// Both are in hundredths of strength
+		let attack = self.attack as u32 * attack_roll;
+		let defence = self.defence as u32 * (100 + self.modifiers.total()) * defence_roll / 100;
+		let attacker_won = attack > defence;
+		let (attacker_losses, defender_losses) = if attacker_won {
+			let losses = defence.div_ceil(attack_roll).min(self.attack as u32 - 1);
+			(losses as u16, self.defence)
+		} else {
+			let losses = (attack * self.defence as u32).checked_div(defence).unwrap_or(0).min(self.defence.saturating_sub(1) as u32);
+			(self.attack.div_ceil(2), losses as u16)
+		};
+		BattleResult {
+			battle: self,
+			attacker_won,
+			attacker_losses,
+			defender_losses,
+		}
+	}
+}
+
+#[cfg(test)]
+const TEST_SIZE: u16 = 8;
+
+#[cfg(test)]
+fn test_map(edit: impl Fn(HexCoord, &mut u8, &mut u8, &mut u8)) -> HeightMap {
+	crate::test_utils::synthetic_map(TEST_SIZE, |hex| {
+		let (mut topo, mut veg, mut rivers) = (0, 0, 0);
+		edit(hex, &mut topo, &mut veg, &mut rivers);
+		(topo, veg, rivers)
+	})
+}
+
+#[cfg(test)]
+fn centre() -> HexCoord {
+	HexCoord::from_offset(4, 4)
+}
+
+#[test]
+fn elevation_modifier() {
+	let hill = |elevation| test_map(move |hex, topo, _, _| *topo = if hex == centre() { elevation } else { 0 });
+	let (low, high) = (DefenceModifiers::at(&hill(51), centre()), DefenceModifiers::at(&hill(204), centre()));
+	assert!(high.elevation > low.elevation && low.elevation > 0);
+	assert_eq!(
+		high,
+		DefenceModifiers {
+			elevation: high.elevation,
+			..Default::default()
+		}
+	);
+	assert_eq!(DefenceModifiers::at(&hill(0), centre()), DefenceModifiers::default());
+}
+
+#[test]
+fn vegetation_modifier() {
+	let rough = test_map(|_, _, veg, _| *veg = ROUGH_VEGETATION);
+	assert_eq!(DefenceModifiers::at(&rough, centre()).vegetation, ROUGH_BONUS);
+	let sparse = test_map(|_, _, veg, _| *veg = ROUGH_VEGETATION - 1);
+	assert_eq!(DefenceModifiers::at(&sparse, centre()).vegetation, 0);
+}
+
+#[test]
+fn river_and_coast_modifiers() {
+	let on_river = test_map(|hex, _, _, river| *river = (hex == centre()) as u8);
+	assert_eq!(DefenceModifiers::at(&on_river, centre()).river, RIVER_BONUS);
+	let next_to_river = test_map(|hex, _, _, river| *river = (hex == centre() + HexCoord::RIGHT) as u8);
+	assert_eq!(DefenceModifiers::at(&next_to_river, centre()).river, RIVER_BONUS);
+	assert_eq!(DefenceModifiers::at(&next_to_river, centre() + HexCoord::RIGHT * 3).river, 0);
+
+	let coast = test_map(|hex, topo, _, _| *topo = if hex == centre() + HexCoord::RIGHT { 255 } else { 0 });
+	assert_eq!(
+		DefenceModifiers::at(&coast, centre()),
+		DefenceModifiers {
+			coast: COAST_BONUS,
+			..Default::default()
+		}
+	);
+	assert_eq!(DefenceModifiers::at(&coast, centre() + HexCoord::RIGHT * 3).coast, 0);
+	// Maps without a rivers channel still work
+	assert_eq!(DefenceModifiers::at(&crate::test_utils::height_map(), HexCoord::new(100, 100)).river, 0);
+}
+
+#[test]
+fn battles_are_deterministic() {
+	let height_map = test_map(|_, _, _, _| {});
+	let battle = Battle::new(&height_map, centre(), CountryId(1), CountryId(2), 10, 10);
+	assert_eq!(battle.resolve(7), battle.resolve(7));
+	let results = (0..100).map(|seed| battle.resolve(seed)).collect::<Vec<_>>();
+	assert!(
+		results.iter().any(|result| result.attacker_won) && results.iter().any(|result| !result.attacker_won),
+		"Even battles can go either way"
+	);
+	for result in results {
+		if result.attacker_won {
+			assert_eq!(result.defender_losses, 10);
+			assert!(result.attacker_losses < 10);
+		} else {
+			assert_eq!(result.attacker_losses, 5);
+			assert!(result.defender_losses < 10);
+		}
+	}
+
+	let overwhelming = Battle::new(&height_map, centre(), CountryId(1), CountryId(2), 100, 10);
+	assert!((0..100).all(|seed| overwhelming.resolve(seed).attacker_won));
+}
+
+#[test]
+fn modifiers_strengthen_the_defence() {
+	let flat = test_map(|_, _, _, _| {});
+	let hill = test_map(|_, topo, veg, _| (*topo, *veg) = (200, 255));
+	let wins = |height_map: &HeightMap| {
+		(0..200)
+			.filter(|&seed| Battle::new(height_map, centre(), CountryId(1), CountryId(2), 13, 10).resolve(seed).attacker_won)
+			.count()
+	};
+	assert!(wins(&hill) < wins(&flat));
+}
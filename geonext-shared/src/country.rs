@@ -0,0 +1,141 @@
+use crate::map_loader::HexCoord;
+use crate::territories::{CountryId, Territories};
+use glam::{UVec2, Vec3};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+/// Information about a country that is sent along with the map
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Country {
+	/// The name shown to players, such as `United States`
+	pub name: String,
+	/// The ISO 3166-1 alpha-2 code, such as `US`
+	pub iso_code: String,
+	pub primary: [u8; 3],
+	pub secondary: [u8; 3],
+	/// The starting hex nearest the centre of the country
+	pub capital: HexCoord,
+}
+
+impl Country {
+	/// The primary and secondary colours, scaled to between 0 and 1 for rendering
+	pub fn colours(&self) -> [Vec3; 2] {
+		[self.primary, self.secondary].map(|[r, g, b]| Vec3::new(r as f32, g as f32, b as f32) / 255.)
+	}
+}
+
+/// Colours that are easy to tell apart, used before falling back to generated colours
+const PALETTE: [[u8; 3]; 12] = [
+	[128, 0, 0],
+	[255, 127, 80],
+	[255, 165, 0],
+	[255, 215, 0],
+	[128, 128, 0],
+	[0, 255, 0],
+	[0, 128, 128],
+	[100, 149, 237],
+	[0, 191, 255],
+	[65, 105, 225],
+	[147, 112, 219],
+	[255, 0, 255],
+];
+
+/// The colour for an index into the palette. Indices past the end of the palette are spread around the hue wheel by the golden angle.
+fn palette_colour(index: usize) -> [u8; 3] {
+	if let Some(&colour) = PALETTE.get(index) {
+		return colour;
+	}
+	let hue = ((index - PALETTE.len()) as f32 * 0.618_034).fract() * 6.;
+	let fall = 1. - (hue % 2. - 1.).abs();
+	let (r, g, b) = match hue as u32 {
+		0 => (1., fall, 0.),
+		1 => (fall, 1., 0.),
+		2 => (0., 1., fall),
+		3 => (0., fall, 1.),
+		4 => (fall, 0., 1.),
+		_ => (1., 0., fall),
+	};
+	[r, g, b].map(|channel: f32| (channel * 200.) as u8 + 40)
+}
+
+impl Territories {
+	/// For each country, the countries that own a hex next to one of its hexes. Sea is not counted as a country.
+	pub fn adjacent_countries(&self) -> Vec<BTreeSet<u8>> {
+		let mut adjacent = vec![BTreeSet::new(); self.countries().len()];
+		for y in 0..self.height() {
+			for x in 0..self.width() {
+				let country = self.country_id(UVec2::new(x, y));
+				let Some(neighbours) = adjacent.get_mut(country.0 as usize) else {
+					continue;
+				};
+				for neighbour in HexCoord::from_offset(x as i32, y as i32).neighbours() {
+					match self.hex_owner(neighbour) {
+						Some(owner) if owner != country && owner != CountryId::SEA => neighbours.insert(owner.0),
+						_ => continue,
+					};
+				}
+			}
+		}
+		adjacent
+	}
+
+	/// Gives every country a primary colour that differs from all of its neighbours, and a darker secondary colour.
+	///
+	/// Countries with the most neighbours are coloured first, taking the first palette colour not used by a neighbour.
+	pub fn assign_colours(&mut self) {
+		let adjacent = self.adjacent_countries();
+		let mut order = (0..adjacent.len()).collect::<Vec<_>>();
+		order.sort_by_key(|&country| (usize::MAX - adjacent[country].len(), country));
+
+		let mut assigned = vec![None; adjacent.len()];
+		for country in order {
+			let used = adjacent[country].iter().filter_map(|&neighbour| assigned[neighbour as usize]).collect::<BTreeSet<_>>();
+			assigned[country] = (0..).find(|index| !used.contains(index));
+		}
+
+		for (country, index) in self.countries_mut().iter_mut().zip(assigned) {
+			let primary = palette_colour(index.unwrap_or_default());
+			country.primary = primary;
+			country.secondary = primary.map(|channel| channel / 2);
+		}
+	}
+}
+
+#[test]
+fn adjacent_colours_differ() {
+	let mut territories: Territories = bincode::deserialize(include_bytes!("./../../assets/starting_game_map")).unwrap();
+	territories.assign_colours();
+	let adjacent = territories.adjacent_countries();
+	let countries = territories.countries();
+	for (country, neighbours) in countries.iter().zip(&adjacent) {
+		assert_ne!(country.primary, country.secondary);
+		for &neighbour in neighbours {
+			assert_ne!(country.primary, countries[neighbour as usize].primary, "{} and {}", country.name, countries[neighbour as usize].name);
+		}
+	}
+
+	// Zambia and the Democratic Republic of the Congo share a border
+	let congo = territories.hex_owner(HexCoord::new(100, 100)).unwrap();
+	let zambia = territories.hex_owner(HexCoord::new(101, 100)).unwrap();
+	assert!(adjacent[congo.0 as usize].contains(&zambia.0));
+}
+
+#[test]
+fn generated_colours_are_distinct() {
+	let colours = (0..64).map(palette_colour).collect::<BTreeSet<_>>();
+	assert_eq!(colours.len(), 64);
+}
+
+#[test]
+fn country_metadata() {
+	let territories: Territories = bincode::deserialize(include_bytes!("./../../assets/starting_game_map")).unwrap();
+	let chile = territories.hex_owner(HexCoord::new(10, 133)).unwrap();
+	let country = territories.country(chile).unwrap();
+	assert_eq!((country.name.as_str(), country.iso_code.as_str()), ("Chile", "CL"));
+	assert_eq!(territories.get_name(chile), "Chile");
+	for (index, country) in territories.countries().iter().enumerate() {
+		assert_eq!(country.iso_code.len(), 2, "{}", country.name);
+		assert!(country.iso_code.chars().all(|c| c.is_ascii_uppercase()), "{}", country.name);
+		assert_eq!(territories.hex_owner(country.capital), Some(CountryId(index as u8)), "{}", country.name);
+	}
+}
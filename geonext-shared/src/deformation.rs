@@ -0,0 +1,195 @@
+use crate::economy::Resources;
+use crate::map_loader::{HeightMap, HexCoord};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// A change to the terrain requested by a player. The server computes the resulting [`ElevationChange`]s and sends them to every client.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Deformation {
+	/// Lowers the land around a hex, most at the centre
+	Crater { centre: HexCoord, radius: u8, depth: u8 },
+	/// Drops a land hex below sea level
+	Flood { hex: HexCoord },
+	/// Wears down the land around a hex until no hex is more than `max_step` above its lowest land neighbour
+	CollapseCliffs { centre: HexCoord, radius: u8, max_step: u8 },
+}
+
+/// The new elevation of a single hex
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ElevationChange {
+	pub hex: HexCoord,
+	pub elevation: u8,
+}
+
+impl Deformation {
+	/// The largest radius that can be requested, to limit the size of each update
+	pub const MAX_RADIUS: u8 = 6;
+	/// The elevation given to flooded hexes
+	pub const SEA_ELEVATION: u8 = 255;
+
+	/// The hex the deformation is centred on
+	pub fn centre(&self) -> HexCoord {
+		match *self {
+			Self::Crater { centre, .. } | Self::CollapseCliffs { centre, .. } => centre,
+			Self::Flood { hex } => hex,
+		}
+	}
+
+	/// The ore needed to carry out the deformation, which grows with its size
+	pub fn cost(&self) -> Resources {
+		let ore = match *self {
+			Self::Crater { radius, depth, .. } => 10 + radius as u32 * 5 + depth as u32 / 4,
+			Self::Flood { .. } => 25,
+			Self::CollapseCliffs { radius, .. } => 5 + radius as u32 * 5,
+		};
+		Resources::new(0, 0, ore)
+	}
+
+	/// Checks that the deformation is on the map and not too large
+	pub fn validate(&self, height_map: &HeightMap) -> Result<(), String> {
+		let radius = match *self {
+			Self::Crater { radius, .. } | Self::CollapseCliffs { radius, .. } => radius,
+			Self::Flood { .. } => 0,
+		};
+		if radius > Self::MAX_RADIUS {
+			return Err(format!("Radius {radius} is larger than the maximum of {}", Self::MAX_RADIUS));
+		}
+		if height_map.elevation(self.centre()).is_none() {
+			return Err(format!("{:?} is outside of the map", self.centre()));
+		}
+		Ok(())
+	}
+
+	/// Computes the elevation changes without modifying the map. Only hexes that actually change are returned.
+	pub fn changes(&self, height_map: &HeightMap) -> Vec<ElevationChange> {
+		let land = |hex: HexCoord| height_map.elevation(hex).filter(|&elevation| !HeightMap::is_sea(elevation));
+		let mut changes = Vec::new();
+		match *self {
+			Self::Crater { centre, radius, depth } => {
+				for hex in centre.spiral(radius as u32) {
+					let Some(elevation) = land(hex) else { continue };
+					let falloff = radius as u32 + 1 - hex.distance(centre) as u32;
+					let drop = (depth as u32 * falloff / (radius as u32 + 1)) as u8;
+					if drop > 0 {
+						changes.push(ElevationChange {
+							hex,
+							elevation: elevation.saturating_sub(drop),
+						});
+					}
+				}
+			}
+			Self::Flood { hex } => {
+				if land(hex).is_some() {
+					changes.push(ElevationChange { hex, elevation: Self::SEA_ELEVATION });
+				}
+			}
+			Self::CollapseCliffs { centre, radius, max_step } => {
+				let mut working = HashMap::new();
+				let current = |working: &HashMap<HexCoord, u8>, hex| working.get(&hex).copied().or_else(|| land(hex));
+				// Each pass can only lower a hex to its neighbours' level, so repeat to let the collapse spread outwards
+				for _ in 0..=radius {
+					for hex in centre.spiral(radius as u32) {
+						let Some(elevation) = current(&working, hex) else { continue };
+						let Some(lowest) = hex.neighbours().filter_map(|neighbour| current(&working, neighbour)).min() else {
+							continue;
+						};
+						if elevation > lowest.saturating_add(max_step) {
+							working.insert(hex, lowest + max_step);
+						}
+					}
+				}
+				changes.extend(centre.spiral(radius as u32).filter_map(|hex| working.get(&hex).map(|&elevation| ElevationChange { hex, elevation })));
+			}
+		}
+		changes
+	}
+}
+
+impl HeightMap {
+	/// Applies changes computed by [`Deformation::changes`], returning the hexes that were modified
+	pub fn apply_changes(&mut self, changes: &[ElevationChange]) -> Vec<HexCoord> {
+		changes
+			.iter()
+			.filter(|change| self.set_elevation(change.hex, change.elevation).is_some())
+			.map(|change| change.hex)
+			.collect()
+	}
+}
+
+#[cfg(test)]
+use crate::test_utils;
+
+#[test]
+fn crater() {
+	let mut height_map = test_utils::height_map();
+	let centre = HexCoord::new(100, 100);
+	let before = height_map.elevation(centre).unwrap();
+	assert!(before >= 30);
+	let deformation = Deformation::Crater { centre, radius: 2, depth: 30 };
+	assert_eq!(deformation.validate(&height_map), Ok(()));
+
+	let changes = deformation.changes(&height_map);
+	assert!(changes.iter().all(|change| change.hex.distance(centre) <= 2));
+	let modified = height_map.apply_changes(&changes);
+	assert_eq!(modified.len(), changes.len());
+	assert_eq!(height_map.elevation(centre), Some(before - 30));
+	// Hexes further from the centre are lowered less, down to the lowest land elevation
+	let original = test_utils::height_map();
+	for change in &changes {
+		let drop = 30 * (3 - change.hex.distance(centre) as u8) / 3;
+		assert_eq!(change.elevation, original.elevation(change.hex).unwrap().saturating_sub(drop));
+	}
+
+	// The same request gives the same result on another copy of the map
+	let mut other = test_utils::height_map();
+	assert_eq!(deformation.changes(&other), changes);
+	other.apply_changes(&changes);
+	assert_eq!(other.elevation(centre), height_map.elevation(centre));
+}
+
+#[test]
+fn flood() {
+	let mut height_map = test_utils::height_map();
+	let hex = HexCoord::new(100, 100);
+	let changes = Deformation::Flood { hex }.changes(&height_map);
+	assert_eq!(
+		changes,
+		vec![ElevationChange {
+			hex,
+			elevation: Deformation::SEA_ELEVATION
+		}]
+	);
+	height_map.apply_changes(&changes);
+	assert!(HeightMap::is_sea(height_map.elevation(hex).unwrap()));
+	// Flooding the sea does nothing
+	assert_eq!(Deformation::Flood { hex }.changes(&height_map), vec![]);
+}
+
+#[test]
+fn collapse_cliffs() {
+	let mut height_map = test_utils::height_map();
+	let centre = HexCoord::new(100, 100);
+	let max_step = 2;
+	let deformation = Deformation::CollapseCliffs { centre, radius: 3, max_step };
+	let changes = deformation.changes(&height_map);
+	height_map.apply_changes(&changes);
+	for change in &changes {
+		let lowest = change
+			.hex
+			.neighbours()
+			.filter_map(|neighbour| height_map.elevation(neighbour))
+			.filter(|&elevation| !HeightMap::is_sea(elevation))
+			.min()
+			.unwrap();
+		assert!(change.elevation <= lowest + max_step);
+	}
+	assert!(!changes.is_empty());
+}
+
+#[test]
+fn invalid_deformations() {
+	let height_map = test_utils::height_map();
+	let centre = HexCoord::new(100, 100);
+	assert!(Deformation::Crater { centre, radius: 200, depth: 10 }.validate(&height_map).is_err());
+	assert!(Deformation::Flood { hex: HexCoord::new(-10, -10) }.validate(&height_map).is_err());
+}
@@ -0,0 +1,254 @@
+use crate::territories::CountryId;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Relation {
+	#[default]
+	Peace,
+	War,
+	Alliance,
+}
+
+impl core::fmt::Display for Relation {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		match self {
+			Self::Peace => write!(f, "peace"),
+			Self::War => write!(f, "war"),
+			Self::Alliance => write!(f, "alliance"),
+		}
+	}
+}
+
+/// A change of relation that needs both countries to agree
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Proposal {
+	Peace,
+	Alliance,
+}
+
+impl Proposal {
+	pub fn from(self) -> Relation {
+		match self {
+			Self::Peace => Relation::War,
+			Self::Alliance => Relation::Peace,
+		}
+	}
+	pub fn to(self) -> Relation {
+		match self {
+			Self::Peace => Relation::Peace,
+			Self::Alliance => Relation::Alliance,
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiplomaticAction {
+	/// Breaks any alliance
+	DeclareWar(CountryId),
+	Propose(CountryId, Proposal),
+	Accept(CountryId, Proposal),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiplomacyError {
+	SameCountry,
+	AlreadyAtWar(CountryId),
+	/// Such as peace between countries that are not at war
+	NotApplicable {
+		proposal: Proposal,
+		relation: Relation,
+	},
+	NoProposal {
+		from: CountryId,
+		proposal: Proposal,
+	},
+}
+
+impl core::fmt::Display for DiplomacyError {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		match self {
+			Self::SameCountry => write!(f, "A country cannot have relations with itself"),
+			Self::AlreadyAtWar(country) => write!(f, "Already at war with {country:?}"),
+			Self::NotApplicable { proposal, relation } => write!(f, "Cannot propose {proposal:?} while the relation is {relation}"),
+			Self::NoProposal { from, proposal } => write!(f, "{from:?} has not proposed {proposal:?}"),
+		}
+	}
+}
+
+impl std::error::Error for DiplomacyError {}
+
+/// With the lower id first
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RelationChange {
+	pub countries: (CountryId, CountryId),
+	pub relation: Relation,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Diplomacy {
+	/// Relations other than peace, keyed with the lower id first
+	relations: BTreeMap<(CountryId, CountryId), Relation>,
+	/// (from, to, proposal)
+	proposals: BTreeSet<(CountryId, CountryId, Proposal)>,
+}
+
+fn pair(a: CountryId, b: CountryId) -> (CountryId, CountryId) {
+	(a.min(b), a.max(b))
+}
+
+impl Diplomacy {
+	/// A country is at peace with itself
+	pub fn relation(&self, a: CountryId, b: CountryId) -> Relation {
+		self.relations.get(&pair(a, b)).copied().unwrap_or_default()
+	}
+	pub fn at_war(&self, a: CountryId, b: CountryId) -> bool {
+		self.relation(a, b) == Relation::War
+	}
+	pub fn allied(&self, a: CountryId, b: CountryId) -> bool {
+		self.relation(a, b) == Relation::Alliance
+	}
+	pub fn allies(&self, country: CountryId) -> impl Iterator<Item = CountryId> + '_ {
+		self.relations.iter().filter(|(_, &relation)| relation == Relation::Alliance).filter_map(move |(&(a, b), _)| {
+			if a == country {
+				Some(b)
+			} else if b == country {
+				Some(a)
+			} else {
+				None
+			}
+		})
+	}
+	pub fn relations(&self) -> impl Iterator<Item = RelationChange> + '_ {
+		self.relations.iter().map(|(&countries, &relation)| RelationChange { countries, relation })
+	}
+	/// As (from, proposal)
+	pub fn proposals_to(&self, country: CountryId) -> impl Iterator<Item = (CountryId, Proposal)> + '_ {
+		self.proposals.iter().filter(move |(_, to, _)| *to == country).map(|&(from, _, proposal)| (from, proposal))
+	}
+
+	/// Drops any proposals between the countries. Returns `None` if the relation has not changed.
+	pub fn set(&mut self, a: CountryId, b: CountryId, relation: Relation) -> Option<RelationChange> {
+		if a == b || self.relation(a, b) == relation {
+			return None;
+		}
+		let countries = pair(a, b);
+		match relation {
+			Relation::Peace => self.relations.remove(&countries),
+			relation => self.relations.insert(countries, relation),
+		};
+		self.proposals.retain(|&(from, to, _)| pair(from, to) != countries);
+		Some(RelationChange { countries, relation })
+	}
+
+	pub fn declare_war(&mut self, country: CountryId, target: CountryId) -> Result<RelationChange, DiplomacyError> {
+		if country == target {
+			return Err(DiplomacyError::SameCountry);
+		}
+		self.set(country, target, Relation::War).ok_or(DiplomacyError::AlreadyAtWar(target))
+	}
+
+	/// Proposing again has no effect
+	pub fn propose(&mut self, country: CountryId, target: CountryId, proposal: Proposal) -> Result<(), DiplomacyError> {
+		if country == target {
+			return Err(DiplomacyError::SameCountry);
+		}
+		let relation = self.relation(country, target);
+		if relation != proposal.from() {
+			return Err(DiplomacyError::NotApplicable { proposal, relation });
+		}
+		self.proposals.insert((country, target, proposal));
+		Ok(())
+	}
+
+	pub fn accept(&mut self, country: CountryId, from: CountryId, proposal: Proposal) -> Result<RelationChange, DiplomacyError> {
+		if !self.proposals.contains(&(from, country, proposal)) {
+			return Err(DiplomacyError::NoProposal { from, proposal });
+		}
+		// Proposals are dropped whenever the relation changes, so this always changes it
+		self.set(country, from, proposal.to()).ok_or(DiplomacyError::NoProposal { from, proposal })
+	}
+}
+
+#[test]
+fn war_and_peace() {
+	let (a, b, c) = (CountryId(1), CountryId(2), CountryId(3));
+	let mut diplomacy = Diplomacy::default();
+	assert_eq!(diplomacy.relation(a, b), Relation::Peace);
+	assert_eq!(diplomacy.declare_war(a, a), Err(DiplomacyError::SameCountry));
+	assert_eq!(
+		diplomacy.propose(a, b, Proposal::Peace),
+		Err(DiplomacyError::NotApplicable {
+			proposal: Proposal::Peace,
+			relation: Relation::Peace
+		})
+	);
+
+	let change = diplomacy.declare_war(b, a).unwrap();
+	assert_eq!(
+		change,
+		RelationChange {
+			countries: (a, b),
+			relation: Relation::War
+		}
+	);
+	assert!(diplomacy.at_war(a, b) && diplomacy.at_war(b, a) && !diplomacy.at_war(a, c));
+	assert_eq!(diplomacy.declare_war(a, b), Err(DiplomacyError::AlreadyAtWar(b)));
+
+	assert_eq!(diplomacy.accept(b, a, Proposal::Peace), Err(DiplomacyError::NoProposal { from: a, proposal: Proposal::Peace }));
+	diplomacy.propose(a, b, Proposal::Peace).unwrap();
+	assert!(diplomacy.proposals_to(b).eq([(a, Proposal::Peace)]));
+	assert!(diplomacy.proposals_to(a).next().is_none());
+	assert_eq!(
+		diplomacy.accept(a, b, Proposal::Peace),
+		Err(DiplomacyError::NoProposal { from: b, proposal: Proposal::Peace }),
+		"Countries cannot accept their own proposals"
+	);
+	assert_eq!(diplomacy.accept(b, a, Proposal::Peace).unwrap().relation, Relation::Peace);
+	assert!(diplomacy.proposals_to(b).next().is_none());
+	assert!(diplomacy.relations().next().is_none());
+}
+
+#[test]
+fn alliances() {
+	let (a, b, c) = (CountryId(1), CountryId(2), CountryId(3));
+	let mut diplomacy = Diplomacy::default();
+	diplomacy.declare_war(a, c).unwrap();
+	assert_eq!(
+		diplomacy.propose(a, c, Proposal::Alliance),
+		Err(DiplomacyError::NotApplicable {
+			proposal: Proposal::Alliance,
+			relation: Relation::War
+		})
+	);
+
+	diplomacy.propose(a, b, Proposal::Alliance).unwrap();
+	diplomacy.propose(c, b, Proposal::Alliance).unwrap();
+	diplomacy.accept(b, a, Proposal::Alliance).unwrap();
+	diplomacy.accept(b, c, Proposal::Alliance).unwrap();
+	assert!(diplomacy.allied(a, b) && diplomacy.allied(c, b) && !diplomacy.allied(a, c));
+	assert!(diplomacy.allies(b).eq([a, c]));
+	assert!(diplomacy.allies(a).eq([b]));
+
+	// Declaring war breaks the alliance
+	diplomacy.propose(a, b, Proposal::Alliance).unwrap_err();
+	diplomacy.declare_war(b, a).unwrap();
+	assert!(diplomacy.allies(a).next().is_none());
+	assert_eq!(
+		diplomacy.relations().collect::<Vec<_>>(),
+		[
+			RelationChange {
+				countries: (a, b),
+				relation: Relation::War
+			},
+			RelationChange {
+				countries: (a, c),
+				relation: Relation::War
+			},
+			RelationChange {
+				countries: (b, c),
+				relation: Relation::Alliance
+			},
+		]
+	);
+}
@@ -0,0 +1,240 @@
+use crate::map_loader::{Channel, HeightMap, HexCoord};
+use crate::territories::{CountryId, Territories};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// See [`HeightMap::is_sea`]
+const MAX_LAND_ELEVATION: u32 = 240;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Resource {
+	Wood,
+	Food,
+	Ore,
+}
+
+impl Resource {
+	pub const ALL: [Self; 3] = [Self::Wood, Self::Food, Self::Ore];
+
+	pub fn name(self) -> &'static str {
+		match self {
+			Self::Wood => "Wood",
+			Self::Food => "Food",
+			Self::Ore => "Ore",
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Resources {
+	pub wood: u32,
+	pub food: u32,
+	pub ore: u32,
+}
+
+impl Resources {
+	pub const fn new(wood: u32, food: u32, ore: u32) -> Self {
+		Self { wood, food, ore }
+	}
+	pub fn only(resource: Resource, amount: u32) -> Self {
+		let mut resources = Self::default();
+		*resources.get_mut(resource) = amount;
+		resources
+	}
+	pub fn get(&self, resource: Resource) -> u32 {
+		match resource {
+			Resource::Wood => self.wood,
+			Resource::Food => self.food,
+			Resource::Ore => self.ore,
+		}
+	}
+	pub fn get_mut(&mut self, resource: Resource) -> &mut u32 {
+		match resource {
+			Resource::Wood => &mut self.wood,
+			Resource::Food => &mut self.food,
+			Resource::Ore => &mut self.ore,
+		}
+	}
+	pub fn contains(&self, other: &Self) -> bool {
+		Resource::ALL.iter().all(|&resource| self.get(resource) >= other.get(resource))
+	}
+	pub fn checked_sub(self, other: Self) -> Option<Self> {
+		Some(Self::new(self.wood.checked_sub(other.wood)?, self.food.checked_sub(other.food)?, self.ore.checked_sub(other.ore)?))
+	}
+}
+
+impl core::ops::Add for Resources {
+	type Output = Self;
+	fn add(self, other: Self) -> Self {
+		Self::new(self.wood.saturating_add(other.wood), self.food.saturating_add(other.food), self.ore.saturating_add(other.ore))
+	}
+}
+
+impl core::ops::AddAssign for Resources {
+	fn add_assign(&mut self, other: Self) {
+		*self = *self + other;
+	}
+}
+
+impl core::fmt::Display for Resources {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		let [first, rest @ ..] = Resource::ALL;
+		write!(f, "{} {}", first.name(), self.get(first))?;
+		rest.iter().try_for_each(|&resource| write!(f, ", {} {}", resource.name(), self.get(resource)))
+	}
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum BuildingKind {
+	Sawmill,
+	Farm,
+	Mine,
+}
+
+impl BuildingKind {
+	pub const ALL: [Self; 3] = [Self::Sawmill, Self::Farm, Self::Mine];
+
+	pub fn resource(self) -> Resource {
+		match self {
+			Self::Sawmill => Resource::Wood,
+			Self::Farm => Resource::Food,
+			Self::Mine => Resource::Ore,
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Building {
+	pub kind: BuildingKind,
+	pub level: u8,
+}
+
+/// Sawmills produce more in dense vegetation, farms in fertile lowlands and mines in the mountains
+pub fn production(building: Building, hex: HexCoord, height_map: &HeightMap) -> Resources {
+	let Some(elevation) = height_map.elevation(hex).filter(|&elevation| !HeightMap::is_sea(elevation)) else {
+		return Resources::default();
+	};
+	let elevation = elevation as u32;
+	let vegetation = height_map.sample_at(Channel::VEG, hex.to_offset().as_uvec2()).unwrap_or(0) as u32;
+	let amount = match building.kind {
+		BuildingKind::Sawmill => 1 + vegetation * 3 / 255,
+		BuildingKind::Farm => 1 + vegetation * 2 / 255 + (MAX_LAND_ELEVATION - elevation) * 2 / MAX_LAND_ELEVATION,
+		BuildingKind::Mine => 1 + elevation * 3 / MAX_LAND_ELEVATION,
+	};
+	Resources::only(building.kind.resource(), amount * building.level as u32)
+}
+
+/// Sent only to the player controlling the country
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EconomyReport {
+	pub stockpile: Resources,
+	pub production: Resources,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Economy {
+	stockpiles: BTreeMap<CountryId, Resources>,
+	/// In the last tick
+	production: BTreeMap<CountryId, Resources>,
+}
+
+impl Economy {
+	pub fn stockpile(&self, country: CountryId) -> Resources {
+		self.stockpiles.get(&country).copied().unwrap_or_default()
+	}
+	/// A country is given a stockpile when it is first played
+	pub fn has_stockpile(&self, country: CountryId) -> bool {
+		self.stockpiles.contains_key(&country)
+	}
+	pub fn add(&mut self, country: CountryId, resources: Resources) {
+		*self.stockpiles.entry(country).or_default() += resources;
+	}
+	/// Returns false without changing the stockpile if there is not enough
+	pub fn spend(&mut self, country: CountryId, cost: Resources) -> bool {
+		let Some(remaining) = self.stockpile(country).checked_sub(cost) else {
+			return false;
+		};
+		self.stockpiles.insert(country, remaining);
+		true
+	}
+
+	pub fn tick(&mut self, buildings: impl IntoIterator<Item = (HexCoord, Building)>, territories: &Territories, height_map: &HeightMap) {
+		self.production.clear();
+		for (hex, building) in buildings {
+			let Some(owner) = territories.hex_owner(hex).filter(|&owner| owner != CountryId::SEA) else {
+				continue;
+			};
+			*self.production.entry(owner).or_default() += production(building, hex, height_map);
+		}
+		for (&country, &produced) in &self.production {
+			*self.stockpiles.entry(country).or_default() += produced;
+		}
+	}
+
+	pub fn report(&self, country: CountryId) -> EconomyReport {
+		EconomyReport {
+			stockpile: self.stockpile(country),
+			production: self.production.get(&country).copied().unwrap_or_default(),
+		}
+	}
+}
+
+#[cfg(test)]
+use crate::test_utils;
+
+#[test]
+fn production_depends_on_terrain() {
+	let height_map = test_utils::height_map();
+	let land = (0..height_map.height as i32)
+		.flat_map(|y| (0..height_map.width as i32).map(move |x| HexCoord::from_offset(x, y)))
+		.filter(|&hex| height_map.elevation(hex).is_some_and(|elevation| !HeightMap::is_sea(elevation)))
+		.collect::<Vec<_>>();
+	let vegetation = |hex: &HexCoord| height_map.sample_at(Channel::VEG, hex.to_offset().as_uvec2()).unwrap();
+	let elevation = |hex: &HexCoord| height_map.elevation(*hex).unwrap();
+	let (sparse, dense) = (land.iter().min_by_key(|hex| vegetation(hex)).unwrap(), land.iter().max_by_key(|hex| vegetation(hex)).unwrap());
+	let (low, high) = (land.iter().min_by_key(|hex| elevation(hex)).unwrap(), land.iter().max_by_key(|hex| elevation(hex)).unwrap());
+	let produce = |kind, hex: &HexCoord| production(Building { kind, level: 1 }, *hex, &height_map).get(kind.resource());
+
+	assert!(produce(BuildingKind::Sawmill, dense) > produce(BuildingKind::Sawmill, sparse));
+	assert!(produce(BuildingKind::Mine, high) > produce(BuildingKind::Mine, low));
+	assert!(produce(BuildingKind::Farm, low) > produce(BuildingKind::Farm, high));
+
+	let upgraded = production(Building { kind: BuildingKind::Mine, level: 3 }, *high, &height_map);
+	assert_eq!(upgraded, Resources::only(Resource::Ore, produce(BuildingKind::Mine, high) * 3));
+	let sea = production(Building { kind: BuildingKind::Farm, level: 1 }, HexCoord::from_offset(0, 0), &height_map);
+	assert_eq!(sea, Resources::default());
+}
+
+#[test]
+fn stockpiles_grow_each_tick() {
+	let (height_map, territories) = test_utils::map();
+	let test_utils::Border { congo_hex, congo, zambia, .. } = test_utils::border(&territories);
+	let farm = Building { kind: BuildingKind::Farm, level: 1 };
+	let mine = Building { kind: BuildingKind::Mine, level: 2 };
+	let buildings = [(congo_hex, farm), (congo_hex, mine), (HexCoord::from_offset(0, 0), farm)];
+	let produced = production(farm, congo_hex, &height_map) + production(mine, congo_hex, &height_map);
+
+	let mut economy = Economy::default();
+	economy.add(congo, Resources::new(5, 0, 0));
+	economy.tick(buildings, &territories, &height_map);
+	economy.tick(buildings, &territories, &height_map);
+	assert_eq!(
+		economy.report(congo),
+		EconomyReport {
+			stockpile: Resources::new(5, 0, 0) + produced + produced,
+			production: produced,
+		}
+	);
+	assert_eq!(economy.report(zambia), EconomyReport::default());
+}
+
+#[test]
+fn resource_arithmetic() {
+	let stockpile = Resources::new(10, 5, 0);
+	assert!(stockpile.contains(&Resources::new(10, 5, 0)));
+	assert!(!stockpile.contains(&Resources::only(Resource::Ore, 1)));
+	assert_eq!(stockpile.checked_sub(Resources::new(4, 5, 0)), Some(Resources::new(6, 0, 0)));
+	assert_eq!(stockpile.checked_sub(Resources::only(Resource::Food, 6)), None);
+	assert_eq!(Resources::new(u32::MAX, 1, 2) + Resources::new(1, 1, 1), Resources::new(u32::MAX, 2, 3));
+	assert_eq!(stockpile.to_string(), "Wood 10, Food 5, Ore 0");
+}
@@ -1,10 +1,30 @@
 #[macro_use]
 extern crate log;
 
+pub mod buildings;
+pub mod capture;
 mod client_message;
+pub mod combat;
+pub mod country;
+pub mod deformation;
+pub mod diplomacy;
+pub mod economy;
+pub mod lobby;
+pub mod map_format;
 pub mod map_loader;
+pub mod orders;
+pub mod pathfinding;
+pub mod regions;
 mod server_message;
 pub mod territories;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_utils;
+pub mod units;
+pub mod victory;
+pub mod visibility;
 
 pub use client_message::ClientMessage;
 pub use server_message::ServerMessage;
+
+/// The version of the websocket protocol. Increase this whenever the layout of [`ClientMessage`] or [`ServerMessage`] changes.
+pub const PROTOCOL_VERSION: u32 = 12;
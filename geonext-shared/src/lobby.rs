@@ -0,0 +1,34 @@
+use crate::victory::VictoryConditions;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct GameId(pub u32);
+
+/// How a game advances
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GameMode {
+	/// Actions are applied as soon as they are received
+	RealTime,
+	/// Players submit orders, which are resolved together once everyone has submitted or the turn runs out of time
+	TurnBased { turn_seconds: u32 },
+}
+
+/// What the lobby shows about a game
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct GameSummary {
+	pub id: GameId,
+	pub name: String,
+	/// The names of the players currently in the game
+	pub players: Vec<String>,
+	/// The most players that can be in the game at once
+	pub capacity: u8,
+	pub mode: GameMode,
+	/// How the game can end
+	pub victory: VictoryConditions,
+}
+
+impl GameSummary {
+	pub fn is_full(&self) -> bool {
+		self.players.len() >= self.capacity as usize
+	}
+}
@@ -0,0 +1,280 @@
+use crate::map_loader::HeightMapError;
+
+// Version 1 of the container used for `map.txt` is laid out as (all integers little endian):
+// - the magic bytes `GNXM`
+// - `u16` version, `u16` flags (bit 0 set if a checksum is present)
+// - `u16` width, `u16` height, `u16` channel count
+// - for each channel a `u8` name length, the utf8 name and a `u8` `ChannelType`
+// - a `u32` CRC-32 of the channel data, if the checksum flag is set
+// - the data for each channel in table order, stored column by column (`x * height + y`)
+//
+// Files without the magic bytes are read as the original raw format: a `u16` width, height and channel count
+// followed by `u8` channels in the order topo, vegetation, snow.
+pub const MAGIC: [u8; 4] = *b"GNXM";
+pub const VERSION: u16 = 1;
+const FLAG_CHECKSUM: u16 = 1;
+
+/// The names given to the channels of the raw format, in the order they are stored
+const RAW_CHANNEL_NAMES: [&str; 3] = ["topo", "veg", "snow"];
+
+/// How each sample in a channel is stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelType {
+	U8,
+	U16,
+}
+
+impl ChannelType {
+	pub fn size(&self) -> usize {
+		match self {
+			Self::U8 => 1,
+			Self::U16 => 2,
+		}
+	}
+	fn from_byte(byte: u8) -> Result<Self, HeightMapError> {
+		match byte {
+			0 => Ok(Self::U8),
+			1 => Ok(Self::U16),
+			data_type => Err(HeightMapError::UnknownDataType { data_type }),
+		}
+	}
+	fn to_byte(self) -> u8 {
+		match self {
+			Self::U8 => 0,
+			Self::U16 => 1,
+		}
+	}
+}
+
+/// A channel in the channel table, along with where its data starts in the file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelInfo {
+	pub name: String,
+	pub data_type: ChannelType,
+	pub offset: usize,
+}
+
+/// The parsed header of a map file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MapLayout {
+	/// The format version, or 0 for the raw format
+	pub version: u16,
+	pub width: u32,
+	pub height: u32,
+	pub channels: Vec<ChannelInfo>,
+}
+
+struct Reader<'a> {
+	data: &'a [u8],
+	index: usize,
+}
+
+impl Reader<'_> {
+	fn bytes(&mut self, count: usize) -> Option<&[u8]> {
+		let bytes = self.data.get(self.index..self.index + count)?;
+		self.index += count;
+		Some(bytes)
+	}
+	fn u8(&mut self) -> Option<u8> {
+		self.bytes(1).map(|bytes| bytes[0])
+	}
+	fn u16(&mut self) -> Option<u16> {
+		self.bytes(2).map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+	}
+	fn u32(&mut self) -> Option<u32> {
+		self.bytes(4).map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+	}
+}
+
+impl MapLayout {
+	/// Reads the header of either format and checks that the data is the expected length
+	pub fn parse(data: &[u8]) -> Result<Self, HeightMapError> {
+		if data.starts_with(&MAGIC) {
+			Self::parse_versioned(data)
+		} else {
+			Self::parse_raw(data)
+		}
+	}
+
+	fn parse_raw(data: &[u8]) -> Result<Self, HeightMapError> {
+		let short_header = HeightMapError::ShortHeader { length: data.len() };
+		let mut reader = Reader { data, index: 0 };
+		let width = reader.u16().ok_or(short_header.clone())? as u32;
+		let height = reader.u16().ok_or(short_header.clone())? as u32;
+		let channel_count = reader.u16().ok_or(short_header)? as usize;
+
+		let channel_len = (width * height) as usize;
+		let channels = (0..channel_count)
+			.map(|index| ChannelInfo {
+				name: RAW_CHANNEL_NAMES.get(index).map_or_else(|| format!("channel{index}"), |name| name.to_string()),
+				data_type: ChannelType::U8,
+				offset: reader.index + index * channel_len,
+			})
+			.collect();
+		let layout = Self { version: 0, width, height, channels };
+		layout.check_length(reader.index, data.len())?;
+		Ok(layout)
+	}
+
+	fn parse_versioned(data: &[u8]) -> Result<Self, HeightMapError> {
+		let short_header = HeightMapError::ShortHeader { length: data.len() };
+		let mut reader = Reader { data, index: MAGIC.len() };
+		let version = reader.u16().ok_or(short_header.clone())?;
+		if version != VERSION {
+			return Err(HeightMapError::UnsupportedVersion { found: version });
+		}
+		let flags = reader.u16().ok_or(short_header.clone())?;
+		let width = reader.u16().ok_or(short_header.clone())? as u32;
+		let height = reader.u16().ok_or(short_header.clone())? as u32;
+		let channel_count = reader.u16().ok_or(short_header.clone())?;
+
+		let mut channels = Vec::with_capacity(channel_count as usize);
+		for _ in 0..channel_count {
+			let name_len = reader.u8().ok_or(HeightMapError::MalformedChannelTable)?;
+			let name = reader.bytes(name_len as usize).ok_or(HeightMapError::MalformedChannelTable)?;
+			let name = core::str::from_utf8(name).map_err(|_| HeightMapError::MalformedChannelTable)?.to_string();
+			let data_type = ChannelType::from_byte(reader.u8().ok_or(HeightMapError::MalformedChannelTable)?)?;
+			channels.push(ChannelInfo { name, data_type, offset: 0 });
+		}
+		let checksum = if flags & FLAG_CHECKSUM != 0 { Some(reader.u32().ok_or(short_header)?) } else { None };
+
+		let data_start = reader.index;
+		let mut offset = data_start;
+		for channel in &mut channels {
+			channel.offset = offset;
+			offset += channel.data_type.size() * (width * height) as usize;
+		}
+		let layout = Self { version, width, height, channels };
+		layout.check_length(data_start, data.len())?;
+
+		if let Some(expected) = checksum {
+			let found = crc32(&data[data_start..]);
+			if found != expected {
+				return Err(HeightMapError::ChecksumMismatch { expected, found });
+			}
+		}
+		Ok(layout)
+	}
+
+	fn check_length(&self, data_start: usize, length: usize) -> Result<(), HeightMapError> {
+		let expected = data_start + self.channels.iter().map(|channel| channel.data_type.size() * (self.width * self.height) as usize).sum::<usize>();
+		if length != expected {
+			return Err(HeightMapError::DataLengthMismatch { expected, found: length });
+		}
+		Ok(())
+	}
+}
+
+/// A channel to be written with [`encode`]. The data must contain `width * height` samples of the given type.
+pub struct ChannelData<'a> {
+	pub name: &'a str,
+	pub data_type: ChannelType,
+	pub data: &'a [u8],
+}
+
+/// Writes a map in the current version of the format
+pub fn encode(width: u16, height: u16, channels: &[ChannelData], checksum: bool) -> Vec<u8> {
+	let mut result = Vec::new();
+	result.extend(MAGIC);
+	result.extend(VERSION.to_le_bytes());
+	result.extend((if checksum { FLAG_CHECKSUM } else { 0 }).to_le_bytes());
+	result.extend(width.to_le_bytes());
+	result.extend(height.to_le_bytes());
+	result.extend((channels.len() as u16).to_le_bytes());
+	for channel in channels {
+		assert_eq!(channel.data.len(), width as usize * height as usize * channel.data_type.size(), "Incorrect length for {}", channel.name);
+		let name = &channel.name.as_bytes()[..channel.name.len().min(u8::MAX as usize)];
+		result.push(name.len() as u8);
+		result.extend(name);
+		result.push(channel.data_type.to_byte());
+	}
+
+	let data = channels.iter().flat_map(|channel| channel.data.iter().copied()).collect::<Vec<_>>();
+	if checksum {
+		result.extend(crc32(&data).to_le_bytes());
+	}
+	result.extend(data);
+	result
+}
+
+/// The CRC-32 (IEEE) of some data, matching python's `zlib.crc32`
+pub fn crc32(data: &[u8]) -> u32 {
+	let mut crc = !0u32;
+	for &byte in data {
+		crc ^= byte as u32;
+		for _ in 0..8 {
+			crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+		}
+	}
+	!crc
+}
+
+#[test]
+fn checksum() {
+	assert_eq!(crc32(b""), 0);
+	assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+}
+
+#[test]
+fn raw_layout() {
+	let layout = MapLayout::parse(include_bytes!("./../../assets/map.txt")).unwrap();
+	assert_eq!(layout.version, 0);
+	assert_eq!(layout.channels.iter().map(|channel| channel.name.as_str()).collect::<Vec<_>>(), ["topo", "veg"]);
+	assert_eq!(layout.channels[1].offset, 6 + (layout.width * layout.height) as usize);
+}
+
+#[test]
+fn versioned_round_trip() {
+	let (width, height) = (3, 2);
+	let topo = [1, 2, 3, 4, 5, 6];
+	let heat = [0, 1, 0, 2, 0, 3, 0, 4, 0, 5, 0, 6];
+	let channels = [
+		ChannelData {
+			name: "topo",
+			data_type: ChannelType::U8,
+			data: &topo,
+		},
+		ChannelData {
+			name: "heat",
+			data_type: ChannelType::U16,
+			data: &heat,
+		},
+	];
+	for checksum in [false, true] {
+		let encoded = encode(width, height, &channels, checksum);
+		let layout = MapLayout::parse(&encoded).unwrap();
+		assert_eq!((layout.version, layout.width, layout.height), (VERSION, 3, 2));
+		assert_eq!(layout.channels[0].name, "topo");
+		assert_eq!(layout.channels[1].data_type, ChannelType::U16);
+		assert_eq!(&encoded[layout.channels[0].offset..][..6], &topo);
+		assert_eq!(&encoded[layout.channels[1].offset..], &heat);
+	}
+}
+
+#[test]
+fn corrupted_versioned() {
+	let topo = [7; 4];
+	let channels = [ChannelData {
+		name: "topo",
+		data_type: ChannelType::U8,
+		data: &topo,
+	}];
+	let valid = encode(2, 2, &channels, true);
+
+	let mut new_version = valid.clone();
+	new_version[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+	assert_eq!(MapLayout::parse(&new_version), Err(HeightMapError::UnsupportedVersion { found: VERSION + 1 }));
+
+	assert_eq!(MapLayout::parse(&valid[..8]), Err(HeightMapError::ShortHeader { length: 8 }));
+	assert_eq!(MapLayout::parse(&valid[..16]), Err(HeightMapError::MalformedChannelTable));
+
+	let mut bad_type = valid.clone();
+	bad_type[19] = 9;
+	assert_eq!(MapLayout::parse(&bad_type), Err(HeightMapError::UnknownDataType { data_type: 9 }));
+
+	let mut flipped = valid.clone();
+	*flipped.last_mut().unwrap() ^= 1;
+	assert!(matches!(MapLayout::parse(&flipped), Err(HeightMapError::ChecksumMismatch { .. })));
+
+	assert!(matches!(MapLayout::parse(&valid[..valid.len() - 1]), Err(HeightMapError::DataLengthMismatch { .. })));
+}
@@ -1,23 +1,103 @@
+use crate::map_format::{ChannelType, MapLayout};
 use core::ops::Range;
 use glam::{DVec2, IVec2, UVec2, Vec2, Vec3};
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct HeightMap {
 	map: Vec<u8>,
 	pub width: u32,
 	pub height: u32,
-	channels: u32,
-	data_start: usize,
+	/// The location of each known channel, indexed by [`Channel`]
+	channels: Vec<Option<ChannelLayout>>,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Debug, Clone, Copy)]
+struct ChannelLayout {
+	offset: usize,
+	data_type: ChannelType,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct Channel(u32);
 impl Channel {
 	pub const TOPO: Self = Self(0);
 	pub const VEG: Self = Self(1);
+	pub const SNOW: Self = Self(2);
+	/// Non-zero on hexes with a river
+	pub const RIVERS: Self = Self(3);
+	/// The name of each channel in the map file, indexed by channel
+	const NAMES: [&'static str; 4] = ["topo", "veg", "snow", "rivers"];
+	/// The number of channels that a map must have
+	const REQUIRED: u32 = 2;
+
+	pub fn name(&self) -> &'static str {
+		Self::NAMES[self.0 as usize]
+	}
+	pub fn from_name(name: &str) -> Option<Self> {
+		Self::NAMES.iter().position(|&channel| channel == name).map(|index| Self(index as u32))
+	}
+}
+
+/// The reasons that a height map can fail to load
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HeightMapError {
+	/// The file is too short to contain the width, height and channel count
+	ShortHeader { length: usize },
+	/// The header does not declare all of the channels that are needed
+	ChannelCountMismatch { expected: u32, found: u32 },
+	/// The data is not `width * height * channels` bytes long
+	DataLengthMismatch { expected: usize, found: usize },
+	/// The file was written by a newer (or unknown) version of the format
+	UnsupportedVersion { found: u16 },
+	/// The channel table ends early or has a name that is not utf8
+	MalformedChannelTable,
+	/// A channel has a data type that is not understood
+	UnknownDataType { data_type: u8 },
+	/// A channel that is needed is not in the channel table
+	MissingChannel { name: &'static str },
+	/// The stored checksum does not match the data
+	ChecksumMismatch { expected: u32, found: u32 },
 }
 
+impl core::fmt::Display for HeightMapError {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		match self {
+			Self::ShortHeader { length } => write!(f, "Height map header is truncated ({length} bytes)"),
+			Self::ChannelCountMismatch { expected, found } => write!(f, "Height map has {found} channels but at least {expected} are required"),
+			Self::DataLengthMismatch { expected, found } => write!(f, "Height map should be {expected} bytes but is {found} bytes"),
+			Self::UnsupportedVersion { found } => write!(f, "Height map version {found} is not supported (expected {})", crate::map_format::VERSION),
+			Self::MalformedChannelTable => write!(f, "Height map channel table is malformed"),
+			Self::UnknownDataType { data_type } => write!(f, "Height map channel has unknown data type {data_type}"),
+			Self::MissingChannel { name } => write!(f, "Height map is missing the {name} channel"),
+			Self::ChecksumMismatch { expected, found } => write!(f, "Height map checksum is {found:08x} but should be {expected:08x}"),
+		}
+	}
+}
+
+impl std::error::Error for HeightMapError {}
+
+/// A square block of hexes (in offset coordinates) that is meshed and rebuilt together
 #[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub struct ChunkId {
+	pub x: u32,
+	pub y: u32,
+}
+
+impl ChunkId {
+	/// The width and height of a chunk in hexes
+	pub const SIZE: u32 = 32;
+
+	/// The chunk containing an offset position
+	pub fn containing(pos: UVec2) -> Self {
+		Self {
+			x: pos.x / Self::SIZE,
+			y: pos.y / Self::SIZE,
+		}
+	}
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, Serialize, Deserialize)]
 pub struct HexCoord {
 	q: i32,
 	r: i32,
@@ -37,9 +117,19 @@ impl HexCoord {
 	pub const BOTTOM_RIGHT: Self = Self::new(0, 1);
 	pub const BOTTOM_LEFT: Self = Self::new(-1, 1);
 	pub const LEFT: Self = Self::new(-1, 0);
+	pub const ZERO: Self = Self::new(0, 0);
+	/// The six unit directions in clockwise order (each is the [`Self::rotate_clockwise`] of the previous)
+	pub const DIRECTIONS: [Self; 6] = [Self::TOP_LEFT, Self::TOP_RIGHT, Self::RIGHT, Self::BOTTOM_RIGHT, Self::BOTTOM_LEFT, Self::LEFT];
+
 	pub const fn new(q: i32, r: i32) -> Self {
 		Self { q, r }
 	}
+	pub const fn q(&self) -> i32 {
+		self.q
+	}
+	pub const fn r(&self) -> i32 {
+		self.r
+	}
 	pub fn from_offset(x: i32, y: i32) -> Self {
 		Self { q: x - (y + (y & 1)) / 2, r: y }
 	}
@@ -54,6 +144,13 @@ impl HexCoord {
 		self.q as f32 * 2. * HeightMap::APOTHEM * Vec2::X + self.r as f32 * Vec2::new(HeightMap::APOTHEM, HeightMap::RADII * 1.5)
 	}
 
+	/// Finds the hex containing a point on the xy plane (the inverse of [`Self::centre`])
+	pub fn from_world(point: Vec2) -> Self {
+		let r = point.y / (HeightMap::RADII * 1.5);
+		let q = (point.x - r * HeightMap::APOTHEM) / (2. * HeightMap::APOTHEM);
+		Self::from_fractional(q, r)
+	}
+
 	fn centre_with_offset(&self) -> Vec2 {
 		let offset = self.to_offset();
 		Vec2::new(((offset.x * 2 + 1 - (offset.y & 1)) as f32 - 1.) * HeightMap::APOTHEM, offset.y as f32 * (HeightMap::RADII * 3.) / 2.)
@@ -106,6 +203,117 @@ impl HexCoord {
 	pub fn rotate_anticlockwise(&self) -> Self {
 		Self::new(self.q + self.r, -self.q)
 	}
+
+	/// Rounds a fractional axial coordinate to the hex that contains it (by rounding in cube space)
+	pub fn from_fractional(q: f32, r: f32) -> Self {
+		Self::round_cubic(q as f64, r as f64, -(q as f64) - r as f64)
+	}
+
+	fn round_cubic(q: f64, r: f64, s: f64) -> Self {
+		let (mut rounded_q, mut rounded_r, rounded_s) = (q.round(), r.round(), s.round());
+		let (q_diff, r_diff, s_diff) = ((rounded_q - q).abs(), (rounded_r - r).abs(), (rounded_s - s).abs());
+
+		// The component with the largest rounding error is recomputed so that q + r + s = 0
+		if q_diff > r_diff && q_diff > s_diff {
+			rounded_q = -rounded_r - rounded_s;
+		} else if r_diff > s_diff {
+			rounded_r = -rounded_q - rounded_s;
+		}
+		Self::new(rounded_q as i32, rounded_r as i32)
+	}
+
+	/// The number of steps from the origin to this hex
+	pub fn length(&self) -> i32 {
+		let (q, r, s) = self.to_cubic();
+		(q.abs() + r.abs() + s.abs()) / 2
+	}
+
+	/// The number of steps between two hexes
+	pub fn distance(&self, other: Self) -> i32 {
+		(*self - other).length()
+	}
+
+	/// The six adjacent hexes, in the same order as [`Self::DIRECTIONS`]
+	pub fn neighbours(&self) -> impl Iterator<Item = Self> {
+		let centre = *self;
+		Self::DIRECTIONS.into_iter().map(move |direction| centre + direction)
+	}
+
+	pub fn is_neighbour(&self, other: Self) -> bool {
+		self.distance(other) == 1
+	}
+
+	/// All hexes exactly `radius` steps away, walking clockwise from the hex `radius` steps to the left.
+	///
+	/// A radius of zero yields only the centre.
+	pub fn ring(&self, radius: u32) -> impl Iterator<Item = Self> {
+		let start = *self + Self::LEFT * radius as i32;
+		let sides = if radius == 0 { 1 } else { 6 };
+		let steps = radius.max(1) as usize;
+		(0..sides)
+			.flat_map(move |side| core::iter::repeat_n(Self::DIRECTIONS[(side + 1) % 6], steps))
+			.scan(start, |current, direction| {
+				let hex = *current;
+				*current = *current + direction;
+				Some(hex)
+			})
+	}
+
+	/// All hexes within `radius` steps, ordered by ring from the centre outwards
+	pub fn spiral(&self, radius: u32) -> impl Iterator<Item = Self> {
+		let centre = *self;
+		(0..=radius).flat_map(move |ring| centre.ring(ring))
+	}
+
+	/// All hexes within `radius` steps, ordered by q then r
+	pub fn range(&self, radius: u32) -> impl Iterator<Item = Self> {
+		let centre = *self;
+		let radius = radius as i32;
+		(-radius..=radius).flat_map(move |q| ((-radius).max(-q - radius)..=radius.min(-q + radius)).map(move |r| centre + Self::new(q, r)))
+	}
+
+	/// The hexes on the straight line from this hex to `other`, including both ends
+	pub fn line_to(&self, other: Self) -> impl Iterator<Item = Self> {
+		let (start, distance) = (*self, self.distance(other));
+		let (q, r, s) = (other - start).to_cubic();
+		(0..=distance).map(move |step| {
+			let t = if distance == 0 { 0. } else { step as f64 / distance as f64 };
+			// Interpolating from the start keeps the numbers small, and the nudge moves points on hex edges the same way along the whole line
+			start + Self::round_cubic(q as f64 * t + 1e-6, r as f64 * t + 2e-6, s as f64 * t - 3e-6)
+		})
+	}
+
+	/// Every hex that the line between two points on the xy plane passes through, in order from `start`. Unlike [`Self::line_to`], hexes
+	/// that the line only clips near a corner are included.
+	pub fn supercover(start: Vec2, end: Vec2) -> Vec<Self> {
+		// Lines that pass this close to a corner touch every hex at it
+		const CORNER: f32 = 1e-4;
+		let direction = end - start;
+		let corner = CORNER / direction.length().max(CORNER);
+		let mut hex = Self::from_world(start);
+		let mut hexes = vec![hex];
+		loop {
+			// The edge between two hexes is halfway between their centres, so the line leaves a hex where it first crosses one of those edges.
+			// Edges parallel to the line are never crossed.
+			let exits = hex
+				.neighbours()
+				.filter_map(|neighbour| {
+					let normal = neighbour.centre() - hex.centre();
+					let speed = direction.dot(normal);
+					(speed > 1e-4 * direction.length()).then(|| (neighbour, (hex.centre() + normal / 2. - start).dot(normal) / speed))
+				})
+				.collect::<Vec<_>>();
+			let Some(&(next, exit)) = exits.iter().min_by(|a, b| a.1.total_cmp(&b.1)).filter(|&&(_, t)| t < 1.) else {
+				return hexes;
+			};
+			for &(neighbour, t) in &exits {
+				if t - exit < corner && !hexes.contains(&neighbour) {
+					hexes.push(neighbour);
+				}
+			}
+			hex = next;
+		}
+	}
 }
 
 impl core::ops::Add for HexCoord {
@@ -114,6 +322,27 @@ impl core::ops::Add for HexCoord {
 		Self { q: self.q + rhs.q, r: self.r + rhs.r }
 	}
 }
+
+impl core::ops::Sub for HexCoord {
+	type Output = Self;
+	fn sub(self, rhs: Self) -> Self::Output {
+		Self { q: self.q - rhs.q, r: self.r - rhs.r }
+	}
+}
+
+impl core::ops::Neg for HexCoord {
+	type Output = Self;
+	fn neg(self) -> Self::Output {
+		Self { q: -self.q, r: -self.r }
+	}
+}
+
+impl core::ops::Mul<i32> for HexCoord {
+	type Output = Self;
+	fn mul(self, rhs: i32) -> Self::Output {
+		Self { q: self.q * rhs, r: self.r * rhs }
+	}
+}
 #[test]
 fn hex_coords() {
 	let (offset_x, offset_y) = (0, -1);
@@ -139,57 +368,396 @@ fn rotate() {
 	assert_eq!(HexCoord::LEFT.rotate_anticlockwise(), HexCoord::BOTTOM_LEFT);
 }
 
+#[cfg(test)]
+fn test_hexes() -> impl Iterator<Item = HexCoord> {
+	(-7..=7).flat_map(|q| (-7..=7).map(move |r| HexCoord::new(q * 3, r * 2 - 1)))
+}
+
+#[test]
+fn hex_arithmetic() {
+	for a in test_hexes() {
+		assert_eq!(a - a, HexCoord::ZERO);
+		assert_eq!(-(-a), a);
+		assert_eq!(a + -a, HexCoord::ZERO);
+		assert_eq!(a * 2, a + a);
+		assert_eq!(a * -1, -a);
+		assert_eq!(a.rotate_clockwise().rotate_anticlockwise(), a);
+		for b in test_hexes().step_by(13) {
+			assert_eq!(a + b - b, a);
+		}
+	}
+	for (index, direction) in HexCoord::DIRECTIONS.into_iter().enumerate() {
+		assert_eq!(direction.rotate_clockwise(), HexCoord::DIRECTIONS[(index + 1) % 6]);
+		assert_eq!(-direction, HexCoord::DIRECTIONS[(index + 3) % 6]);
+	}
+}
+
+#[test]
+fn hex_distance() {
+	for a in test_hexes() {
+		assert_eq!(a.distance(a), 0);
+		assert_eq!(a.length(), a.distance(HexCoord::ZERO));
+		assert_eq!(a.rotate_clockwise().length(), a.length());
+		assert!(a.neighbours().all(|neighbour| a.distance(neighbour) == 1 && a.is_neighbour(neighbour)));
+		for b in test_hexes().step_by(7) {
+			assert_eq!(a.distance(b), b.distance(a));
+			for c in test_hexes().step_by(29) {
+				assert!(a.distance(c) <= a.distance(b) + b.distance(c));
+			}
+		}
+	}
+	assert_eq!(HexCoord::new(3, -1).distance(HexCoord::new(-2, 4)), 5);
+}
+
+#[test]
+fn hex_rings() {
+	use std::collections::HashSet;
+	for centre in test_hexes().step_by(11) {
+		assert_eq!(centre.ring(0).collect::<Vec<_>>(), vec![centre]);
+		for radius in 1..6 {
+			let ring = centre.ring(radius).collect::<Vec<_>>();
+			assert_eq!(ring.len(), 6 * radius as usize);
+			assert_eq!(ring.iter().collect::<HashSet<_>>().len(), ring.len());
+			assert!(ring.iter().all(|hex| hex.distance(centre) == radius as i32));
+			// The ring is walked as one continuous loop
+			assert!(ring.windows(2).all(|pair| pair[0].is_neighbour(pair[1])));
+			assert!(ring[0].is_neighbour(ring[ring.len() - 1]));
+
+			let spiral = centre.spiral(radius).collect::<Vec<_>>();
+			let range = centre.range(radius).collect::<HashSet<_>>();
+			assert_eq!(spiral.len(), (3 * radius * (radius + 1) + 1) as usize);
+			assert_eq!(spiral.iter().copied().collect::<HashSet<_>>(), range);
+			assert_eq!(range.len(), spiral.len());
+			assert!(spiral.windows(2).all(|pair| pair[0].distance(centre) <= pair[1].distance(centre)));
+		}
+	}
+}
+
+#[test]
+fn hex_lines() {
+	for a in test_hexes() {
+		for b in test_hexes().step_by(17) {
+			let line = a.line_to(b).collect::<Vec<_>>();
+			assert_eq!(line.len(), a.distance(b) as usize + 1);
+			assert_eq!(line.first(), Some(&a));
+			assert_eq!(line.last(), Some(&b));
+			assert!(line.windows(2).all(|pair| pair[0].is_neighbour(pair[1])));
+		}
+	}
+	for direction in HexCoord::DIRECTIONS {
+		let line = HexCoord::ZERO.line_to(direction * 4).collect::<Vec<_>>();
+		assert_eq!(line, (0..=4).map(|step| direction * step).collect::<Vec<_>>());
+	}
+	// Lines along hex edges pick the same side wherever they are on the map
+	let edge = HexCoord::ZERO.line_to(HexCoord::new(4, -2)).collect::<Vec<_>>();
+	for start in [HexCoord::new(150, 120), HexCoord::new(-300, 500)] {
+		assert_eq!(start.line_to(start + HexCoord::new(4, -2)).collect::<Vec<_>>(), edge.iter().map(|&hex| start + hex).collect::<Vec<_>>());
+	}
+}
+
+#[test]
+fn from_world() {
+	for hex in test_hexes() {
+		assert_eq!(HexCoord::from_world(hex.centre()), hex);
+		for direction in HexCoord::DIRECTIONS {
+			// Points just inside each corner and edge belong to the hex
+			assert_eq!(HexCoord::from_world(hex.centre().lerp(hex.corner(direction), 0.95)), hex);
+			assert_eq!(HexCoord::from_world(hex.centre().lerp((hex + direction).centre(), 0.49)), hex);
+			assert_eq!(HexCoord::from_world(hex.centre().lerp((hex + direction).centre(), 0.51)), hex + direction);
+		}
+	}
+}
+
+#[test]
+fn hex_supercover() {
+	for a in test_hexes().step_by(7) {
+		for direction in HexCoord::DIRECTIONS {
+			// Segments through a corner, just either side of it, and from one corner to the opposite one
+			let corner = a.corner(direction);
+			let offset = Vec2::new(0.3, -0.7) * 1e-3;
+			for (start, end) in [
+				(a.centre(), corner + (corner - a.centre()) * 2.),
+				(a.centre() + offset, corner + offset * 2. + (corner - a.centre()) * 3.),
+				(a.centre() - offset, corner - offset * 2. + (corner - a.centre()) * 3.),
+				(corner, a.corner(-direction)),
+				(a.centre(), (a + direction * 5 + HexCoord::RIGHT).centre() + offset),
+			] {
+				let hexes = HexCoord::supercover(start, end);
+				assert_eq!(hexes.first(), Some(&HexCoord::from_world(start)));
+				// The ends may be on a corner, which could round to any of the hexes at it
+				for step in 1..1000 {
+					let point = HexCoord::from_world(start.lerp(end, step as f32 / 1000.));
+					assert!(hexes.contains(&point), "{point:?} on the line from {start} to {end} is not in {hexes:?}");
+				}
+				assert!(hexes.windows(2).all(|pair| pair[0].distance(pair[1]) <= 2));
+			}
+		}
+	}
+	assert_eq!(HexCoord::supercover(Vec2::ZERO, Vec2::ZERO), [HexCoord::ZERO]);
+}
+
+#[test]
+fn ray_picking() {
+	let mut height_map = HeightMap::default();
+	height_map.load(include_bytes!("./../../assets/map.txt").to_vec()).unwrap();
+	for (x, y) in [(50, 20), (100, 100), (3, 7), (200, 60)] {
+		let hex = HexCoord::from_offset(x, y);
+		let height = HeightMap::elevation_to_z(height_map.elevation(hex).unwrap());
+		let target = hex.centre().extend(height);
+
+		// Straight down onto the centre
+		assert_eq!(height_map.intersect_ray(target + Vec3::Z * 50., -Vec3::Z), Some(hex));
+		// At an angle, as the camera looks at the map
+		let direction = Vec3::new(0.1, 0.5, -1.).normalize();
+		assert_eq!(height_map.intersect_ray(target - direction * 80., direction), Some(hex));
+	}
+	assert_eq!(height_map.intersect_ray(Vec3::new(-100., -100., 50.), -Vec3::Z), None);
+}
+
+#[test]
+fn load_corrupted_maps() {
+	let valid = include_bytes!("./../../assets/map.txt").to_vec();
+	let mut height_map = HeightMap::default();
+
+	assert_eq!(height_map.load(Vec::new()), Err(HeightMapError::ShortHeader { length: 0 }));
+	assert_eq!(height_map.load(valid[..5].to_vec()), Err(HeightMapError::ShortHeader { length: 5 }));
+
+	let mut one_channel = valid[..6 + (valid.len() - 6) / 2].to_vec();
+	one_channel[4..6].copy_from_slice(&1u16.to_le_bytes());
+	assert_eq!(height_map.load(one_channel), Err(HeightMapError::ChannelCountMismatch { expected: 2, found: 1 }));
+
+	let expected = valid.len();
+	assert_eq!(
+		height_map.load(valid[..expected - 10].to_vec()),
+		Err(HeightMapError::DataLengthMismatch { expected, found: expected - 10 })
+	);
+	let mut too_long = valid.clone();
+	too_long.push(0);
+	assert_eq!(height_map.load(too_long), Err(HeightMapError::DataLengthMismatch { expected, found: expected + 1 }));
+
+	let mut too_wide = valid.clone();
+	too_wide[0..2].copy_from_slice(&u16::MAX.to_le_bytes());
+	assert!(matches!(height_map.load(too_wide), Err(HeightMapError::DataLengthMismatch { .. })));
+
+	// A failed load leaves the map untouched rather than partially initalised
+	assert_eq!((height_map.width, height_map.height), (0, 0));
+	assert_eq!(height_map.sample_at(Channel::TOPO, UVec2::ZERO), None);
+
+	assert_eq!(height_map.load(valid), Ok(()));
+}
+
+#[test]
+fn bounds_checked_sampling() {
+	let mut height_map = HeightMap::default();
+	height_map.load(include_bytes!("./../../assets/map.txt").to_vec()).unwrap();
+	let (width, height) = (height_map.width, height_map.height);
+
+	let corner = UVec2::new(width - 1, height - 1);
+	assert_eq!(height_map.sample_at(Channel::TOPO, corner), Some(unsafe { height_map.sample_at_unchecked(Channel::TOPO, corner) }));
+	assert_eq!(height_map.sample_at(Channel::VEG, corner), Some(unsafe { height_map.sample_at_unchecked(Channel::VEG, corner) }));
+	assert_eq!(height_map.sample_at(Channel::TOPO, UVec2::new(width, 0)), None);
+	assert_eq!(height_map.sample_at(Channel::TOPO, UVec2::new(0, height)), None);
+	assert!(!height_map.has_channel(Channel::SNOW));
+	assert_eq!(height_map.sample_at(Channel::SNOW, UVec2::ZERO), None);
+
+	assert_eq!(height_map.sample_left(Channel::TOPO, UVec2::new(0, 5)), None);
+	assert_eq!(height_map.sample_up_left(Channel::TOPO, UVec2::new(0, 1)), None);
+	assert_eq!(height_map.sample_up_right(Channel::TOPO, UVec2::new(width - 1, 2)), None);
+	assert_eq!(height_map.sample_up_right(Channel::TOPO, UVec2::new(3, 0)), None);
+	assert_eq!(height_map.sample_up_left(Channel::TOPO, UVec2::new(3, 2)).map(|(_, pos)| pos), Some(UVec2::new(3, 1)));
+	assert!(!height_map.in_bounds(IVec2::new(width as i32, 0)));
+}
+
+#[test]
+fn load_versioned_map() {
+	use crate::map_format::{encode, ChannelData};
+	let raw = include_bytes!("./../../assets/map.txt").to_vec();
+	let mut raw_map = HeightMap::default();
+	raw_map.load(raw.clone()).unwrap();
+
+	let len = (raw_map.width * raw_map.height) as usize;
+	let (topo, veg) = (&raw[6..][..len], &raw[6 + len..][..len]);
+	let snow = topo.iter().flat_map(|&value| [0, value]).collect::<Vec<_>>();
+	let channels = [
+		ChannelData {
+			name: "snow",
+			data_type: ChannelType::U16,
+			data: &snow,
+		},
+		ChannelData {
+			name: "roads",
+			data_type: ChannelType::U8,
+			data: veg,
+		},
+		ChannelData {
+			name: "veg",
+			data_type: ChannelType::U8,
+			data: veg,
+		},
+		ChannelData {
+			name: "topo",
+			data_type: ChannelType::U8,
+			data: topo,
+		},
+	];
+	let mut versioned = HeightMap::default();
+	versioned.load(encode(raw_map.width as u16, raw_map.height as u16, &channels, true)).unwrap();
+	assert_eq!((versioned.width, versioned.height), (raw_map.width, raw_map.height));
+	for pos in [UVec2::ZERO, UVec2::new(50, 20), UVec2::new(raw_map.width - 1, raw_map.height - 1)] {
+		for channel in [Channel::TOPO, Channel::VEG] {
+			assert_eq!(versioned.sample_at(channel, pos), raw_map.sample_at(channel, pos));
+			assert_eq!(unsafe { versioned.sample_at_unchecked(channel, pos) }, raw_map.sample_at(channel, pos).unwrap());
+		}
+		assert_eq!(versioned.sample_at(Channel::SNOW, pos), raw_map.sample_at(Channel::TOPO, pos));
+	}
+
+	let without_veg = encode(raw_map.width as u16, raw_map.height as u16, &channels[3..], false);
+	assert_eq!(versioned.load(without_veg), Err(HeightMapError::MissingChannel { name: "veg" }));
+}
+
+#[test]
+fn chunked_terrain() {
+	let mut height_map = HeightMap::default();
+	height_map.load(include_bytes!("./../../assets/map.txt").to_vec()).unwrap();
+	let (whole_verts, whole_tris) = height_map.generate_terrain();
+
+	let chunks = height_map.chunks().collect::<Vec<_>>();
+	assert_eq!(chunks.len(), (height_map.width.div_ceil(ChunkId::SIZE) * height_map.height.div_ceil(ChunkId::SIZE)) as usize);
+	let mut hexes = 0;
+	let (mut verts, mut tris) = (0, 0);
+	for &chunk in &chunks {
+		let (x, y) = height_map.chunk_bounds(chunk);
+		hexes += x.len() * y.len();
+		let (chunk_verts, chunk_tris) = height_map.generate_chunk(chunk);
+		assert!(chunk_tris.iter().all(|&index| (index as usize) < chunk_verts.len() / 9));
+		verts += chunk_verts.len();
+		tris += chunk_tris.len();
+	}
+	assert_eq!(hexes, (height_map.width * height_map.height) as usize);
+	assert_eq!((verts, tris), (whole_verts.len(), whole_tris.len()));
+
+	// Shading only changes the colours
+	let chunk = ChunkId { x: 1, y: 1 };
+	let (plain, plain_tris) = height_map.generate_chunk(chunk);
+	let (shaded, shaded_tris) = height_map.generate_chunk_shaded(chunk, |_| 0.5);
+	assert_eq!((plain.len(), plain_tris), (shaded.len(), shaded_tris));
+	for (plain, shaded) in plain.chunks(9).zip(shaded.chunks(9)) {
+		assert_eq!(plain[..6], shaded[..6]);
+		assert!(plain[6..].iter().zip(&shaded[6..]).all(|(plain, shaded)| (plain * 0.5 - shaded).abs() < 1e-6));
+	}
+
+	// Changing a hex on the corner of a chunk touches the chunks of its neighbours
+	let corner = HexCoord::from_offset(ChunkId::SIZE as i32, ChunkId::SIZE as i32);
+	let touched = height_map.chunks_touched([corner]);
+	assert!(touched.contains(&ChunkId { x: 1, y: 1 }));
+	assert!(touched.contains(&ChunkId { x: 0, y: 1 }));
+	assert!(touched.contains(&ChunkId { x: 1, y: 0 }));
+	assert_eq!(height_map.chunks_touched([HexCoord::from_offset(5, 5), HexCoord::from_offset(6, 5)]), vec![ChunkId { x: 0, y: 0 }]);
+	assert_eq!(height_map.chunks_touched([HexCoord::new(-50, -50)]), vec![]);
+}
+
 impl HeightMap {
 	const RADII: f32 = 1.;
 	const APOTHEM: f32 = 0.8660254037844386;
+	/// The height of the highest terrain
+	pub const MAX_Z: f32 = 5.;
+
+	/// Loads a map in either the versioned or raw format (see [`crate::map_format`]), checking that the header matches the data
+	pub fn load(&mut self, map: Vec<u8>) -> Result<(), HeightMapError> {
+		let layout = MapLayout::parse(&map)?;
+		if layout.version == 0 && (layout.channels.len() as u32) < Channel::REQUIRED {
+			return Err(HeightMapError::ChannelCountMismatch {
+				expected: Channel::REQUIRED,
+				found: layout.channels.len() as u32,
+			});
+		}
 
-	pub fn load(&mut self, map: Vec<u8>) {
-		self.map = map;
-		struct Index<'a>(usize, &'a [u8]);
-		impl Index<'_> {
-			fn next(&mut self) -> u8 {
-				self.0 += 1;
-				self.1[self.0 - 1]
+		let mut channels = vec![None; Channel::NAMES.len()];
+		for info in &layout.channels {
+			match Channel::from_name(&info.name) {
+				Some(channel) => {
+					channels[channel.0 as usize] = Some(ChannelLayout {
+						offset: info.offset,
+						data_type: info.data_type,
+					})
+				}
+				None => warn!("Ignoring unknown height map channel {:?}", info.name),
 			}
 		}
-		let mut index = Index(0, &self.map);
-		self.width = u16::from_le_bytes([index.next(), index.next()]) as u32;
-		self.height = u16::from_le_bytes([index.next(), index.next()]) as u32;
-		self.channels = u16::from_le_bytes([index.next(), index.next()]) as u32;
+		if let Some(missing) = (0..Channel::REQUIRED).find(|&index| channels[index as usize].is_none()) {
+			return Err(HeightMapError::MissingChannel { name: Channel(missing).name() });
+		}
 
-		self.data_start = index.0;
+		*self = Self {
+			map,
+			width: layout.width,
+			height: layout.height,
+			channels,
+		};
+		info!(
+			"Loaded {}x{} height map (version {}) with channels {:?}",
+			self.width,
+			self.height,
+			layout.version,
+			layout.channels.iter().map(|channel| &channel.name).collect::<Vec<_>>()
+		);
+		Ok(())
+	}
 
-		info!("data start {} Topo {}", self.data_start, self.sample_at(Channel::TOPO, UVec2::new(50, 20)));
+	/// Returns true if the map has data for a channel
+	pub fn has_channel(&self, channel: Channel) -> bool {
+		self.channel_layout(channel).is_some()
 	}
-	pub fn sample_at(&self, channel: Channel, pos: UVec2) -> u8 {
-		unsafe {
-			*self
-				.map
-				.get_unchecked(self.data_start + ((pos.x * self.height + pos.y) + channel.0 * self.width * self.height) as usize)
+
+	fn channel_layout(&self, channel: Channel) -> Option<ChannelLayout> {
+		self.channels.get(channel.0 as usize).copied().flatten()
+	}
+
+	/// Samples a channel at an offset position, or `None` if the position or channel is outside of the map.
+	///
+	/// Wider channels are reduced to their most significant byte.
+	pub fn sample_at(&self, channel: Channel, pos: UVec2) -> Option<u8> {
+		if pos.x >= self.width || pos.y >= self.height {
+			return None;
+		}
+		let layout = self.channel_layout(channel)?;
+		let index = self.index(layout, pos);
+		match layout.data_type {
+			ChannelType::U8 => self.map.get(index).copied(),
+			ChannelType::U16 => self.map.get(index + 1).copied(),
+		}
+	}
+
+	/// Samples a channel without checking bounds, for use in hot loops.
+	///
+	/// # Safety
+	/// `pos` must be within the width and height of a loaded map and `channel` must be one of its channels.
+	pub unsafe fn sample_at_unchecked(&self, channel: Channel, pos: UVec2) -> u8 {
+		debug_assert!(pos.x < self.width && pos.y < self.height && self.has_channel(channel));
+		let layout = self.channels.get_unchecked(channel.0 as usize).unwrap_unchecked();
+		let index = self.index(layout, pos);
+		match layout.data_type {
+			ChannelType::U8 => *self.map.get_unchecked(index),
+			ChannelType::U16 => *self.map.get_unchecked(index + 1),
 		}
 	}
 
+	fn index(&self, layout: ChannelLayout, pos: UVec2) -> usize {
+		layout.offset + (pos.x * self.height + pos.y) as usize * layout.data_type.size()
+	}
+
 	pub fn sample_left(&self, channel: Channel, pos: UVec2) -> Option<(u8, UVec2)> {
-		(pos.x > 0)
-			.then(|| self.sample_at(channel, UVec2::new(pos.x - 1, pos.y)))
-			.map(|height| (height, UVec2::new(pos.x - 1, pos.y)))
+		let pos = UVec2::new(pos.x.checked_sub(1)?, pos.y);
+		Some((self.sample_at(channel, pos)?, pos))
 	}
 
 	pub fn sample_up_left(&self, channel: Channel, pos: UVec2) -> Option<(u8, UVec2)> {
-		if pos.y > 0 && pos.x >= pos.y % 2 {
-			let pos = UVec2::new(pos.x - (pos.y % 2), pos.y - 1);
-			Some((self.sample_at(channel, pos), pos))
-		} else {
-			None
-		}
+		let pos = UVec2::new(pos.x.checked_sub(pos.y % 2)?, pos.y.checked_sub(1)?);
+		Some((self.sample_at(channel, pos)?, pos))
 	}
 	pub fn sample_up_right(&self, channel: Channel, pos: UVec2) -> Option<(u8, UVec2)> {
-		if pos.y > 0 && pos.x < self.width - 1 + pos.y % 2 {
-			let pos = UVec2::new(pos.x + 1 - (pos.y % 2), pos.y - 1);
-			Some((self.sample_at(channel, pos), pos))
-		} else {
-			None
-		}
+		let pos = UVec2::new((pos.x + 1).checked_sub(pos.y % 2)?, pos.y.checked_sub(1)?);
+		Some((self.sample_at(channel, pos)?, pos))
 	}
 
 	fn xor_rand(mut x: u32) -> u32 {
@@ -199,23 +767,60 @@ impl HeightMap {
 		x
 	}
 
+	/// Sea hexes are stored with an elevation above 240
+	pub fn is_sea(elevation: u8) -> bool {
+		elevation > 240
+	}
+
 	pub fn elevation_to_z(elevation: u8) -> f32 {
-		(if elevation > 240 { -0.01 } else { elevation as f32 / 255. }) * 5.
+		(if elevation > 240 { -0.01 } else { elevation as f32 / 255. }) * Self::MAX_Z
 	}
 
 	pub fn hex_centre(&self, x: u32, y: u32) -> Vec3 {
 		HexCoord::from_offset(x as i32, y as i32)
 			.centre()
-			.extend(Self::elevation_to_z(self.sample_at(Channel::TOPO, UVec2::new(x, y))))
+			.extend(Self::elevation_to_z(self.sample_at(Channel::TOPO, UVec2::new(x, y)).unwrap_or_default()))
+	}
+
+	/// The colour of the top of a hex
+	fn hex_colour(&self, pos: UVec2, elevation: u8, vegitation: u8) -> Vec3 {
+		let to_float = |a, b, c| Vec3::new(a as f32, b as f32, c as f32) / 255.;
+		if elevation > 240 {
+			to_float(29, 65, 99)
+		} else {
+			let lerp = |a, b, t| ((a * (1. - t as f32 / 255.)) + (b * (t as f32 / 255.)));
+
+			let vegitation = lerp(to_float(211, 175, 149), to_float(63, 92, 42), if pos.y < 20 { 0 } else { vegitation });
+			let offset = (Self::xor_rand((pos.x * pos.y) as u32) as f32 / u32::MAX as f32) * 0.8 - 0.3;
+			let up = ((pos.x as f32 / self.width as f32) - 0.5).abs().sqrt() * 0.4;
+			let t = ((1. - ((pos.y as f32 / 30. - offset - up).min(1.)).powi(4)) * 255.) as u8;
+			lerp(vegitation, Vec3::ONE, t.saturating_add(((elevation as f32 / 255.).powi(4) * 255.) as u8))
+		}
 	}
 
+	/// Generates the mesh for the whole map
 	pub fn generate_terrain(&self) -> (Vec<f32>, Vec<u32>) {
+		self.generate_range(0..self.width, 0..self.height, &|_| 1.)
+	}
+
+	/// Generates the mesh for the hexes in a chunk. Chunks share no vertices so each can be rebuilt on its own.
+	pub fn generate_chunk(&self, chunk: ChunkId) -> (Vec<f32>, Vec<u32>) {
+		self.generate_chunk_shaded(chunk, |_| 1.)
+	}
+
+	/// Generates the mesh for a chunk with the colour of each hex multiplied by its brightness, for example to darken hexes that cannot be seen
+	pub fn generate_chunk_shaded(&self, chunk: ChunkId, brightness: impl Fn(HexCoord) -> f32) -> (Vec<f32>, Vec<u32>) {
+		let (x, y) = self.chunk_bounds(chunk);
+		self.generate_range(x, y, &brightness)
+	}
+
+	fn generate_range(&self, x_range: Range<u32>, y_range: Range<u32>, brightness: &dyn Fn(HexCoord) -> f32) -> (Vec<f32>, Vec<u32>) {
 		assert!(!self.map.is_empty(), "Map should be populated");
+		assert!(x_range.end <= self.width && y_range.end <= self.height, "Range should be within the map");
 
-		let vertex_count = (self.width * self.height) as usize * 6 * 6;
-		let tri_count = (self.width * self.height) as usize * 4 * 3;
-		let mut verticies = Vec::with_capacity(vertex_count);
-		let mut tris = Vec::with_capacity(tri_count);
+		let hexes = x_range.len() * y_range.len();
+		let mut verticies = Vec::with_capacity(hexes * 6 * 9);
+		let mut tris = Vec::with_capacity(hexes * 4 * 3);
 
 		let push_vert = |colour: Vec3, verticies: &mut Vec<f32>, pos: Vec3| {
 			verticies.extend(pos.to_array());
@@ -225,26 +830,15 @@ impl HeightMap {
 
 		let topo = Channel::TOPO;
 
-		for pos in (0..self.height).flat_map(|y| (0..self.width).map(move |x| UVec2::new(x as u32, y as u32))) {
+		for pos in y_range.flat_map(|y| x_range.clone().map(move |x| UVec2::new(x, y))) {
 			let hex = HexCoord::from_offset(pos.x as i32, pos.y as i32);
-			let vegitation = self.sample_at(Channel::VEG, pos);
-			let elevation = self.sample_at(topo, pos);
-			let to_float = |a, b, c| Vec3::new(a as f32, b as f32, c as f32) / 255.;
-			let colour = if elevation > 240 {
-				to_float(29, 65, 99)
-			} else {
-				let lerp = |a, b, t| ((a * (1. - t as f32 / 255.)) + (b * (t as f32 / 255.)));
-
-				let vegitation = lerp(to_float(211, 175, 149), to_float(63, 92, 42), if pos.y < 20 { 0 } else { vegitation });
-				let offset = (Self::xor_rand((pos.x * pos.y) as u32) as f32 / u32::MAX as f32) * 0.8 - 0.3;
-				let up = ((pos.x as f32 / self.width as f32) - 0.5).abs().sqrt() * 0.4;
-				let t = ((1. - ((pos.y as f32 / 30. - offset - up).min(1.)).powi(4)) * 255.) as u8;
-				lerp(vegitation, Vec3::ONE, t.saturating_add(((elevation as f32 / 255.).powi(4) * 255.) as u8))
-			};
-			//let colour = [0., 0., 0.];
+			// Safety: `pos` is within the width and height, and the channels were checked on load
+			let (vegitation, elevation) = unsafe { (self.sample_at_unchecked(Channel::VEG, pos), self.sample_at_unchecked(topo, pos)) };
+			let colour = self.hex_colour(pos, elevation, vegitation) * brightness(hex);
 			let height = Self::elevation_to_z(elevation);
 
 			let hex_corners = hex.world_space(height);
+			let offset = (verticies.len() / 9) as u32;
 			push_vert(colour, &mut verticies, hex_corners.top);
 			push_vert(colour, &mut verticies, hex_corners.top_left);
 			push_vert(colour, &mut verticies, hex_corners.top_right);
@@ -252,40 +846,133 @@ impl HeightMap {
 			push_vert(colour, &mut verticies, hex_corners.bottom_right);
 			push_vert(colour, &mut verticies, hex_corners.bottom);
 
-			let offset = ((pos.y * self.width) + pos.x) * 6;
 			let [top, top_left, top_right, bottom_left, bottom_right, bottom] = [offset + 0, offset + 1, offset + 2, offset + 3, offset + 4, offset + 5];
 			tris.extend([top, top_left, top_right]);
 			tris.extend([top_right, top_left, bottom_right]);
 			tris.extend([bottom_right, top_left, bottom_left]);
 			tris.extend([bottom_left, bottom, bottom_right]);
 
-			if let Some((_, _)) = self.sample_left(topo, pos).filter(|next_value| next_value.0 != elevation) {
-				let [upper1, upper2] = [top_left, bottom_left];
-				let [lower1, lower2] = [top_right - 6, bottom_right - 6];
+			// Walls down (or up) to the neighbouring hexes have their own vertices, as the neighbour may be in another chunk
+			let mut push_wall = |upper: [Vec3; 2], neighbour: UVec2, lower: fn(HexCorners) -> [Vec3; 2]| {
+				// Safety: the neighbour was returned by a bounds checked sample
+				let (neighbour_vegitation, neighbour_elevation) = unsafe { (self.sample_at_unchecked(Channel::VEG, neighbour), self.sample_at_unchecked(topo, neighbour)) };
+				let neighbour_hex = HexCoord::from_offset(neighbour.x as i32, neighbour.y as i32);
+				let neighbour_colour = self.hex_colour(neighbour, neighbour_elevation, neighbour_vegitation) * brightness(neighbour_hex);
+				let neighbour_corners = neighbour_hex.world_space(Self::elevation_to_z(neighbour_elevation));
+
+				let offset = (verticies.len() / 9) as u32;
+				let [lower1, lower2] = lower(neighbour_corners);
+				push_vert(colour, &mut verticies, upper[0]);
+				push_vert(colour, &mut verticies, upper[1]);
+				push_vert(neighbour_colour, &mut verticies, lower1);
+				push_vert(neighbour_colour, &mut verticies, lower2);
+				let [upper1, upper2, lower1, lower2] = [offset, offset + 1, offset + 2, offset + 3];
 				tris.extend([upper2, upper1, lower2]);
 				tris.extend([lower2, upper1, lower1]);
+			};
+
+			if let Some((_, pos)) = self.sample_left(topo, pos).filter(|next_value| next_value.0 != elevation) {
+				push_wall([hex_corners.top_left, hex_corners.bottom_left], pos, |corners| [corners.top_right, corners.bottom_right]);
 			}
 			if let Some((_, pos)) = self.sample_up_left(topo, pos).filter(|next_value| next_value.0 != elevation) {
-				let [upper1, upper2] = [top, top_left];
-				let [lower1, lower2] = [((pos.y * self.width) + pos.x) * 6 + 4, ((pos.y * self.width) + pos.x) * 6 + 5];
-				tris.extend([upper2, upper1, lower2]);
-				tris.extend([lower2, upper1, lower1]);
+				push_wall([hex_corners.top, hex_corners.top_left], pos, |corners| [corners.bottom_right, corners.bottom]);
 			}
 			if let Some((_, pos)) = self.sample_up_right(topo, pos).filter(|next_value| next_value.0 != elevation) {
-				let [upper1, upper2] = [top_right, top];
-				let [lower1, lower2] = [((pos.y * self.width) + pos.x) * 6 + 5, ((pos.y * self.width) + pos.x) * 6 + 3];
-				tris.extend([upper2, upper1, lower2]);
-				tris.extend([lower2, upper1, lower1]);
+				push_wall([hex_corners.top_right, hex_corners.top], pos, |corners| [corners.bottom, corners.bottom_left]);
 			}
 		}
 
-		//assert_eq!(verticies.len(), vertex_count, "Incorrect vert heuristic");
-		//assert_eq!(tris.len(), tri_count, "Incorrect tri heuristic");
 		(verticies, tris)
 	}
 
+	/// All of the chunks covering the map, in row order
+	pub fn chunks(&self) -> impl Iterator<Item = ChunkId> {
+		let (chunks_x, chunks_y) = (self.width.div_ceil(ChunkId::SIZE), self.height.div_ceil(ChunkId::SIZE));
+		(0..chunks_y).flat_map(move |y| (0..chunks_x).map(move |x| ChunkId { x, y }))
+	}
+
+	/// The offset x and y ranges of the hexes in a chunk (chunks at the edge of the map may be smaller)
+	pub fn chunk_bounds(&self, chunk: ChunkId) -> (Range<u32>, Range<u32>) {
+		let start = UVec2::new(chunk.x, chunk.y) * ChunkId::SIZE;
+		let end = (start + ChunkId::SIZE).min(UVec2::new(self.width, self.height));
+		(start.x..end.x, start.y..end.y)
+	}
+
+	/// The chunks whose meshes change when the elevation of the given hexes change.
+	///
+	/// This includes the chunks of neighbouring hexes, as their walls reach down to the changed hexes.
+	pub fn chunks_touched(&self, changed: impl IntoIterator<Item = HexCoord>) -> Vec<ChunkId> {
+		let mut chunks = changed
+			.into_iter()
+			.flat_map(|hex| core::iter::once(hex).chain(hex.neighbours()))
+			.filter(|hex| self.elevation(*hex).is_some())
+			.map(|hex| ChunkId::containing(hex.to_offset().as_uvec2()))
+			.collect::<Vec<_>>();
+		chunks.sort_unstable_by_key(|chunk| (chunk.y, chunk.x));
+		chunks.dedup();
+		chunks
+	}
+
+	/// The elevation of a hex, or `None` if it is outside of the map
+	pub fn elevation(&self, hex: HexCoord) -> Option<u8> {
+		let offset = hex.to_offset();
+		if offset.x < 0 || offset.y < 0 {
+			return None;
+		}
+		self.sample_at(Channel::TOPO, offset.as_uvec2())
+	}
+
+	/// Sets the elevation of a hex, returning the previous elevation or `None` if the hex is outside of the map
+	pub fn set_elevation(&mut self, hex: HexCoord, elevation: u8) -> Option<u8> {
+		let previous = self.elevation(hex)?;
+		let layout = self.channel_layout(Channel::TOPO)?;
+		let index = self.index(layout, hex.to_offset().as_uvec2());
+		match layout.data_type {
+			ChannelType::U8 => self.map[index] = elevation,
+			ChannelType::U16 => self.map[index..index + 2].copy_from_slice(&((elevation as u16) << 8).to_le_bytes()),
+		}
+		Some(previous)
+	}
+
+	/// Finds the first hex hit by a ray, taking the elevation of the terrain into account.
+	///
+	/// Only the hexes under the section of the ray between the highest and lowest possible terrain are tested,
+	/// so the cost depends on the camera angle rather than the size of the map.
+	pub fn intersect_ray(&self, ray_origin: Vec3, ray_direction: Vec3) -> Option<HexCoord> {
+		let min_z = Self::elevation_to_z(u8::MAX);
+		if ray_direction.z.abs() < f32::EPSILON {
+			return None;
+		}
+		let t_top = (Self::MAX_Z - ray_origin.z) / ray_direction.z;
+		let t_bottom = (min_z - ray_origin.z) / ray_direction.z;
+		let (t_enter, t_exit) = (t_top.min(t_bottom).max(0.), t_top.max(t_bottom));
+		if t_exit < 0. {
+			return None;
+		}
+		let enter = (ray_origin + ray_direction * t_enter).truncate();
+		let exit = (ray_origin + ray_direction * t_exit).truncate();
+
+		let horizontal = ray_direction.truncate();
+		HexCoord::supercover(enter, exit).into_iter().find(|hex| {
+			let Some(elevation) = self.elevation(*hex) else {
+				return false;
+			};
+			let height = Self::elevation_to_z(elevation);
+			if hex.intersect_ray(height, ray_origin, ray_direction).is_some() {
+				return true;
+			}
+			// The ray may instead pass into the side of a raised hex
+			let t_closest = if horizontal.length_squared() < f32::EPSILON {
+				t_enter
+			} else {
+				(hex.centre() - ray_origin.truncate()).dot(horizontal) / horizontal.length_squared()
+			};
+			ray_origin.z + ray_direction.z * t_closest.clamp(t_enter, t_exit) < height
+		})
+	}
+
 	pub fn in_bounds(&self, pos: IVec2) -> bool {
-		pos.x >= 0 && pos.x <= self.width as i32 && pos.y >= 0 && pos.y < self.height as i32
+		pos.x >= 0 && pos.x < self.width as i32 && pos.y >= 0 && pos.y < self.height as i32
 	}
 }
 
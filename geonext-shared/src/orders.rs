@@ -0,0 +1,37 @@
+use crate::deformation::Deformation;
+use crate::economy::BuildingKind;
+use crate::map_loader::HexCoord;
+use crate::units::UnitId;
+use serde::{Deserialize, Serialize};
+
+/// The most orders a player can submit in one turn
+pub const MAX_ORDERS: usize = 32;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum Order {
+	Deform(Deformation),
+	/// Captures a hex, or the whole region containing it, with an adjacent army
+	Capture {
+		hex: HexCoord,
+		whole_region: bool,
+	},
+	/// Places a building on a hex owned by the player's country
+	PlaceBuilding {
+		hex: HexCoord,
+		kind: BuildingKind,
+	},
+	/// Moves one of the player's units along a path, which does not include the hex the unit is on
+	Move {
+		unit: UnitId,
+		path: Vec<HexCoord>,
+	},
+}
+
+/// What happened to an order when its turn was resolved
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct OrderResult {
+	pub player: String,
+	pub order: Order,
+	/// Why the order could not be carried out, if it failed
+	pub error: Option<String>,
+}
@@ -0,0 +1,195 @@
+use crate::diplomacy::Diplomacy;
+use crate::map_loader::{Channel, HeightMap, HexCoord};
+use crate::territories::{CountryId, Territories};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+
+/// The cost of moving between hexes. All costs are integers so that the server and client find identical paths.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MovementCosts {
+	/// The cost of any step onto a land hex
+	pub base: u32,
+	/// Extra cost per unit of elevation gained
+	pub climb: u32,
+	/// Extra cost per unit of elevation lost
+	pub descend: u32,
+	/// Extra cost for the densest vegetation, scaled down for sparser vegetation
+	pub vegetation: u32,
+	/// Extra cost for entering a hex owned by a country the moving country is at war with
+	pub foreign: u32,
+	/// The cost of a step onto a sea hex, or `None` if the sea is impassable
+	pub sea: Option<u32>,
+}
+
+impl Default for MovementCosts {
+	fn default() -> Self {
+		Self {
+			base: 10,
+			climb: 2,
+			descend: 1,
+			vegetation: 10,
+			foreign: 20,
+			sea: None,
+		}
+	}
+}
+
+impl MovementCosts {
+	/// The cost of moving from one hex to an adjacent hex, or `None` if the move is impossible.
+	///
+	/// Armies can enter the territory of their own country, its allies and its enemies, but not of countries it is at peace with.
+	pub fn step_cost(&self, height_map: &HeightMap, territories: &Territories, diplomacy: &Diplomacy, country: CountryId, from: HexCoord, to: HexCoord) -> Option<u32> {
+		let from_elevation = height_map.elevation(from)?;
+		let to_elevation = height_map.elevation(to)?;
+		let owner = territories.hex_owner(to)?;
+
+		if owner == CountryId::SEA || HeightMap::is_sea(to_elevation) {
+			return self.sea;
+		}
+
+		// Sea hexes are all at the same level, so only compare the elevation of land
+		let from_elevation = if HeightMap::is_sea(from_elevation) { 0 } else { from_elevation as u32 };
+		let to_elevation = to_elevation as u32;
+		let elevation = if to_elevation > from_elevation {
+			(to_elevation - from_elevation) * self.climb
+		} else {
+			(from_elevation - to_elevation) * self.descend
+		};
+		let vegetation = height_map.sample_at(Channel::VEG, to.to_offset().as_uvec2())? as u32 * self.vegetation / 255;
+		let foreign = if owner == country || diplomacy.allied(owner, country) {
+			0
+		} else if diplomacy.at_war(owner, country) {
+			self.foreign
+		} else {
+			return None;
+		};
+
+		Some(self.base + elevation + vegetation + foreign)
+	}
+
+	/// A lower bound on the cost of moving between two hexes
+	fn heuristic(&self, from: HexCoord, to: HexCoord) -> u32 {
+		from.distance(to) as u32 * self.sea.map_or(self.base, |sea| sea.min(self.base))
+	}
+}
+
+/// A route between two hexes
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Path {
+	/// Every hex along the route, including the start and goal
+	pub hexes: Vec<HexCoord>,
+	pub cost: u32,
+}
+
+/// Finds the cheapest path between two hexes using A*.
+///
+/// Ties are broken by the distance to the goal and then by coordinate, so the result only depends on the inputs.
+pub fn find_path(height_map: &HeightMap, territories: &Territories, diplomacy: &Diplomacy, country: CountryId, start: HexCoord, goal: HexCoord, costs: &MovementCosts) -> Option<Path> {
+	height_map.elevation(start)?;
+	height_map.elevation(goal)?;
+
+	let mut open = BinaryHeap::new();
+	let mut came_from = HashMap::new();
+	let mut best_cost = HashMap::new();
+
+	best_cost.insert(start, 0);
+	let h = costs.heuristic(start, goal);
+	open.push(Reverse((h, h, start.q(), start.r(), 0)));
+
+	while let Some(Reverse((_, _, q, r, cost))) = open.pop() {
+		let current = HexCoord::new(q, r);
+		if current == goal {
+			let mut hexes = vec![goal];
+			while let Some(&previous) = came_from.get(hexes.last().unwrap()) {
+				hexes.push(previous);
+			}
+			hexes.reverse();
+			return Some(Path { hexes, cost });
+		}
+		// Skip stale entries that have since been reached more cheaply
+		if best_cost.get(&current).is_some_and(|&best| best < cost) {
+			continue;
+		}
+
+		for neighbour in current.neighbours() {
+			let Some(step) = costs.step_cost(height_map, territories, diplomacy, country, current, neighbour) else {
+				continue;
+			};
+			let next_cost = cost + step;
+			if best_cost.get(&neighbour).is_some_and(|&best| best <= next_cost) {
+				continue;
+			}
+			best_cost.insert(neighbour, next_cost);
+			came_from.insert(neighbour, current);
+			let h = costs.heuristic(neighbour, goal);
+			open.push(Reverse((next_cost + h, h, neighbour.q(), neighbour.r(), next_cost)));
+		}
+	}
+	None
+}
+
+#[cfg(test)]
+use crate::test_utils;
+
+#[test]
+fn path_over_land() {
+	let (height_map, territories) = test_utils::map();
+	let costs = MovementCosts::default();
+	let (start, goal) = (HexCoord::new(100, 100), HexCoord::new(104, 97));
+	let country = territories.hex_owner(start).unwrap();
+	// The path crosses the border, which needs the countries to be at war or allied
+	let mut diplomacy = Diplomacy::default();
+	diplomacy.declare_war(country, territories.hex_owner(goal).unwrap()).unwrap();
+
+	let path = find_path(&height_map, &territories, &diplomacy, country, start, goal, &costs).expect("Path should exist");
+	assert_eq!(path.hexes.first(), Some(&start));
+	assert_eq!(path.hexes.last(), Some(&goal));
+	assert!(path.hexes.len() > start.distance(goal) as usize);
+	assert!(path.hexes.windows(2).all(|pair| pair[0].is_neighbour(pair[1])));
+	let total = path
+		.hexes
+		.windows(2)
+		.map(|pair| costs.step_cost(&height_map, &territories, &diplomacy, country, pair[0], pair[1]).unwrap())
+		.sum::<u32>();
+	assert_eq!(total, path.cost);
+
+	// Paths must be identical on the client and server
+	assert_eq!(find_path(&height_map, &territories, &diplomacy, country, start, goal, &costs), Some(path));
+	assert_eq!(
+		find_path(&height_map, &territories, &diplomacy, country, start, start, &costs).map(|path| path.hexes),
+		Some(vec![start])
+	);
+}
+
+#[test]
+fn sea_is_impassable() {
+	let (height_map, territories) = test_utils::map();
+	let sea = HexCoord::from_offset(0, 0);
+	let land = HexCoord::new(100, 100);
+	assert_eq!(territories.hex_owner(sea), Some(CountryId::SEA));
+	let country = territories.hex_owner(land).unwrap();
+
+	let costs = MovementCosts::default();
+	assert_eq!(costs.step_cost(&height_map, &territories, &Diplomacy::default(), country, sea + HexCoord::RIGHT, sea), None);
+	assert_eq!(find_path(&height_map, &territories, &Diplomacy::default(), country, land, sea, &costs), None);
+
+	let naval = MovementCosts { sea: Some(5), ..Default::default() };
+	assert_eq!(naval.step_cost(&height_map, &territories, &Diplomacy::default(), country, sea + HexCoord::RIGHT, sea), Some(5));
+}
+
+#[test]
+fn passage_depends_on_relations() {
+	let (height_map, territories) = test_utils::map();
+	let costs = MovementCosts::default();
+	let from = HexCoord::new(100, 100);
+	let to = from + HexCoord::RIGHT;
+	let (owner, other) = (territories.hex_owner(to).unwrap(), territories.hex_owner(from).unwrap());
+	let mut diplomacy = Diplomacy::default();
+	let friendly = costs.step_cost(&height_map, &territories, &diplomacy, owner, from, to).unwrap();
+	assert_eq!(costs.step_cost(&height_map, &territories, &diplomacy, other, from, to), None, "Borders are closed in peace");
+	diplomacy.declare_war(other, owner).unwrap();
+	let foreign = costs.step_cost(&height_map, &territories, &diplomacy, other, from, to).unwrap();
+	assert_eq!(foreign, friendly + costs.foreign);
+	diplomacy.set(other, owner, crate::diplomacy::Relation::Alliance);
+	assert_eq!(costs.step_cost(&height_map, &territories, &diplomacy, other, from, to), Some(friendly), "Allies have free passage");
+}
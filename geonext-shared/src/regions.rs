@@ -0,0 +1,207 @@
+use crate::map_loader::HexCoord;
+use crate::territories::{CountryId, Territories, TerritoryDelta};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RegionId(pub u16);
+
+/// The reasons the region files can fail to load
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegionsError {
+	Json(String),
+	/// A key in the coordinates file was not of the form `(q, r)`
+	MalformedCoordinate(String),
+	/// The neighbours file refers to a region that has no hexes
+	UnknownRegion(String),
+	TooManyRegions,
+}
+
+impl core::fmt::Display for RegionsError {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		match self {
+			Self::Json(e) => write!(f, "Failed to parse region file: {e}"),
+			Self::MalformedCoordinate(key) => write!(f, "Region coordinate {key:?} should be of the form (q, r)"),
+			Self::UnknownRegion(name) => write!(f, "Region {name:?} has no hexes"),
+			Self::TooManyRegions => write!(f, "There are more than {} regions", u16::MAX - 1),
+		}
+	}
+}
+
+impl std::error::Error for RegionsError {}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Regions {
+	names: Vec<String>,
+	hexes: Vec<Vec<HexCoord>>,
+	adjacent: Vec<Vec<RegionId>>,
+	owners: Vec<CountryId>,
+	hex_lookup: HashMap<HexCoord, RegionId>,
+}
+
+fn parse_coordinate(key: &str) -> Option<HexCoord> {
+	let (q, r) = key.strip_prefix('(')?.strip_suffix(')')?.split_once(',')?;
+	Some(HexCoord::new(q.trim().parse().ok()?, r.trim().parse().ok()?))
+}
+
+impl Regions {
+	/// Builds the regions from the contents of the coordinate and neighbour files.
+	///
+	/// Each region starts owned by whichever country owns its first hex in `territories`.
+	pub fn from_json(axial_coordinates: &str, neighbours: &str, territories: &Territories) -> Result<Self, RegionsError> {
+		let axial_coordinates: HashMap<String, String> = serde_json::from_str(axial_coordinates).map_err(|e| RegionsError::Json(e.to_string()))?;
+		let neighbours: HashMap<String, Vec<String>> = serde_json::from_str(neighbours).map_err(|e| RegionsError::Json(e.to_string()))?;
+
+		// Sort so that ids do not depend on the order of the files
+		let mut hexes = axial_coordinates
+			.iter()
+			.map(|(key, name)| parse_coordinate(key).map(|hex| (name.as_str(), hex)).ok_or_else(|| RegionsError::MalformedCoordinate(key.clone())))
+			.collect::<Result<Vec<_>, _>>()?;
+		hexes.sort_unstable_by_key(|&(name, hex)| (name, hex.r(), hex.q()));
+
+		let mut regions = Self::default();
+		let mut ids = HashMap::new();
+		for (name, hex) in hexes {
+			let id = match ids.get(name) {
+				Some(&id) => id,
+				None => {
+					let id = RegionId(u16::try_from(regions.names.len()).ok().filter(|&id| id < u16::MAX).ok_or(RegionsError::TooManyRegions)?);
+					regions.names.push(name.to_string());
+					regions.hexes.push(Vec::new());
+					ids.insert(name, id);
+					id
+				}
+			};
+			regions.hexes[id.0 as usize].push(hex);
+			regions.hex_lookup.insert(hex, id);
+		}
+
+		regions.adjacent = vec![Vec::new(); regions.names.len()];
+		for (name, adjacent) in &neighbours {
+			let id = *ids.get(name.as_str()).ok_or_else(|| RegionsError::UnknownRegion(name.clone()))?;
+			for neighbour in adjacent {
+				let neighbour = *ids.get(neighbour.as_str()).ok_or_else(|| RegionsError::UnknownRegion(neighbour.clone()))?;
+				if neighbour != id && !regions.adjacent[id.0 as usize].contains(&neighbour) {
+					regions.adjacent[id.0 as usize].push(neighbour);
+				}
+			}
+		}
+		regions.adjacent.iter_mut().for_each(|adjacent| adjacent.sort_unstable());
+
+		regions.owners = regions.hexes.iter().map(|hexes| territories.hex_owner(hexes[0]).unwrap_or(CountryId::SEA)).collect();
+		Ok(regions)
+	}
+
+	pub fn len(&self) -> usize {
+		self.names.len()
+	}
+	pub fn is_empty(&self) -> bool {
+		self.names.is_empty()
+	}
+	pub fn ids(&self) -> impl Iterator<Item = RegionId> {
+		(0..self.names.len() as u16).map(RegionId)
+	}
+
+	/// The region containing a hex, or `None` for sea hexes
+	pub fn region_at(&self, hex: HexCoord) -> Option<RegionId> {
+		self.hex_lookup.get(&hex).copied()
+	}
+	pub fn name(&self, region: RegionId) -> Option<&str> {
+		self.names.get(region.0 as usize).map(String::as_str)
+	}
+	/// Every hex in a region
+	pub fn hexes(&self, region: RegionId) -> &[HexCoord] {
+		self.hexes.get(region.0 as usize).map_or(&[], Vec::as_slice)
+	}
+	/// The regions that border a region, in id order
+	pub fn neighbours(&self, region: RegionId) -> &[RegionId] {
+		self.adjacent.get(region.0 as usize).map_or(&[], Vec::as_slice)
+	}
+	pub fn owner(&self, region: RegionId) -> Option<CountryId> {
+		self.owners.get(region.0 as usize).copied()
+	}
+	/// The regions owned by a country
+	pub fn owned_by(&self, country: CountryId) -> impl Iterator<Item = RegionId> + '_ {
+		self.ids().filter(move |&region| self.owner(region) == Some(country))
+	}
+
+	/// Gives a region to a country, updating the owner of each of its hexes.
+	///
+	/// Returns the delta to send to clients, or `None` if the region does not exist.
+	pub fn set_owner(&mut self, region: RegionId, country: CountryId, territories: &mut Territories) -> Option<TerritoryDelta> {
+		*self.owners.get_mut(region.0 as usize)? = country;
+		let changes = self.hexes(region).iter().map(|hex| hex.to_offset().as_uvec2()).map(|offset| ((offset.x, offset.y), country));
+		Some(territories.change_owners(changes.collect::<Vec<_>>()))
+	}
+}
+
+#[cfg(test)]
+fn load_test_regions() -> (Regions, Territories) {
+	let territories: Territories = bincode::deserialize(include_bytes!("./../../assets/starting_game_map")).unwrap();
+	let regions = Regions::from_json(include_str!("./../../assets/axial-coordinates.json"), include_str!("./../../assets/neighbours.json"), &territories).unwrap();
+	(regions, territories)
+}
+
+#[test]
+fn region_lookup() {
+	let (regions, territories) = load_test_regions();
+	let hex = HexCoord::new(10, 133);
+	let chile = regions.region_at(hex).unwrap();
+	assert_eq!(regions.name(chile), Some("Chile_CL_25"));
+	assert_eq!(regions.hexes(chile), &[hex]);
+	assert_eq!(regions.owner(chile), territories.hex_owner(hex));
+	assert_eq!(regions.region_at(HexCoord::from_offset(0, 0)), None);
+
+	for region in regions.ids() {
+		assert!(regions.hexes(region).iter().all(|&hex| regions.region_at(hex) == Some(region)));
+		assert_eq!(regions.owner(region), territories.hex_owner(regions.hexes(region)[0]));
+	}
+	assert!(regions.owned_by(territories.hex_owner(hex).unwrap()).any(|region| region == chile));
+}
+
+#[test]
+fn region_adjacency() {
+	let (regions, _) = load_test_regions();
+	for region in regions.ids() {
+		for &neighbour in regions.neighbours(region) {
+			assert!(regions.neighbours(neighbour).contains(&region), "{:?} and {:?}", regions.name(region), regions.name(neighbour));
+			let touching = regions.hexes(region).iter().any(|hex| regions.hexes(neighbour).iter().any(|other| hex.is_neighbour(*other)));
+			assert!(touching);
+		}
+	}
+	let afghanistan = regions.ids().find(|&region| regions.name(region) == Some("Afghanistan_AF_1")).unwrap();
+	let names = regions.neighbours(afghanistan).iter().map(|&region| regions.name(region).unwrap()).collect::<Vec<_>>();
+	assert!(names.contains(&"Pakistan_PK_40"));
+}
+
+#[test]
+fn region_ownership_drives_territories() {
+	let (mut regions, mut territories) = load_test_regions();
+	let region = regions.region_at(HexCoord::new(100, 100)).unwrap();
+	let zambia = territories.hex_owner(HexCoord::new(101, 100)).unwrap();
+
+	let delta = regions.set_owner(region, zambia, &mut territories).unwrap();
+	assert_eq!(delta.changes.len(), regions.hexes(region).len());
+	assert_eq!(regions.owner(region), Some(zambia));
+	assert!(regions.hexes(region).iter().all(|&hex| territories.hex_owner(hex) == Some(zambia)));
+	assert!(regions.set_owner(RegionId(u16::MAX), zambia, &mut territories).is_none());
+}
+
+#[test]
+fn malformed_region_files() {
+	let territories = Territories::default();
+	assert!(matches!(Regions::from_json("{", "{}", &territories), Err(RegionsError::Json(_))));
+	assert_eq!(
+		Regions::from_json(r#"{"1, 2": "A_AA_1"}"#, "{}", &territories),
+		Err(RegionsError::MalformedCoordinate("1, 2".to_string()))
+	);
+	assert_eq!(
+		Regions::from_json(r#"{"(1, 2)": "A_AA_1"}"#, r#"{"A_AA_1": ["B_BB_1"]}"#, &territories),
+		Err(RegionsError::UnknownRegion("B_BB_1".to_string()))
+	);
+	let regions = Regions::from_json(r#"{"(1, 2)": "A_AA_1", "(2, 2)": "A_AA_1", "(-3, 2)": "B_BB_1"}"#, r#"{"A_AA_1": ["B_BB_1"]}"#, &territories).unwrap();
+	assert_eq!(regions.len(), 2);
+	assert_eq!(regions.hexes(RegionId(0)), &[HexCoord::new(1, 2), HexCoord::new(2, 2)]);
+	assert_eq!(regions.region_at(HexCoord::new(-3, 2)), Some(RegionId(1)));
+	assert_eq!(regions.owner(RegionId(0)), Some(CountryId::SEA));
+}
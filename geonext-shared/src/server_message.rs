@@ -1,10 +1,130 @@
 use serde::{Deserialize, Serialize};
 
-use crate::territories::TerritoriesRLE;
+use crate::combat::BattleResult;
+use crate::deformation::ElevationChange;
+use crate::diplomacy::{Proposal, RelationChange};
+use crate::economy::{Building, EconomyReport};
+use crate::lobby::{GameId, GameSummary};
+use crate::map_loader::HexCoord;
+use crate::orders::OrderResult;
+use crate::territories::CountryId;
+use crate::territories::{TerritoriesRLE, TerritoryDelta};
+use crate::units::{Unit, UnitId};
+use crate::victory::GameResult;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum ServerMessage {
-	AuthAccepted { username: String },
+	/// The handshake succeeded and other messages can now be sent.
+	///
+	/// This and [`ServerMessage::HelloRejected`] must remain the first variants so that any client can read them.
+	HelloAccepted {
+		protocol_version: u32,
+		server_build: String,
+	},
+	/// The handshake failed, for example because the client is out of date. The server closes the connection after sending this.
+	HelloRejected {
+		reason: String,
+	},
+	AuthAccepted {
+		username: String,
+	},
 	Map(TerritoriesRLE),
-	Error { message: String },
+	/// Ownership changes since the last map or delta
+	TerritoryDelta(TerritoryDelta),
+	/// The terrain has been deformed and every client should apply these changes to its height map
+	TerrainChanged {
+		changes: Vec<ElevationChange>,
+	},
+	Error {
+		message: String,
+	},
+	/// The token that can be used to resume this session after reconnecting
+	Session {
+		resume_token: u64,
+	},
+	/// The reply to [`crate::ClientMessage::Ping`]
+	Pong {
+		timestamp: f32,
+	},
+	/// The reply to [`crate::ClientMessage::ListGames`]
+	GameList {
+		games: Vec<GameSummary>,
+	},
+	/// A game has been created or players have joined or left it
+	GameUpdated(GameSummary),
+	/// The last player left a game so it no longer exists
+	GameClosed {
+		game: GameId,
+	},
+	/// The client is now in a game and will be sent its state
+	Joined {
+		game: GameId,
+	},
+	/// The client is no longer in a game, either because it asked to leave or because its session could not be resumed
+	Left,
+	/// The country this player controls in the game they joined
+	CountryAssigned {
+		country: CountryId,
+	},
+	/// A turn of a turn-based game has begun
+	TurnStarted {
+		turn: u32,
+		/// The time left to submit orders when this was sent, in milliseconds
+		remaining_ms: u64,
+	},
+	/// A player has submitted their orders for the current turn
+	OrdersSubmitted {
+		turn: u32,
+		player: String,
+	},
+	/// Every order from a turn has been carried out. The changes they made are sent separately.
+	TurnResolved {
+		turn: u32,
+		results: Vec<OrderResult>,
+	},
+	/// The stockpile and production of this player's country, sent regularly
+	Economy(EconomyReport),
+	/// Buildings that have been placed, or every building when sent with the map
+	BuildingsChanged {
+		buildings: Vec<(HexCoord, Building)>,
+	},
+	/// Units that have been created, moved or damaged, or every unit when sent with the map
+	UnitsChanged {
+		units: Vec<Unit>,
+		removed: Vec<UnitId>,
+	},
+	/// An attack was fought. The changes to units and territory are sent separately.
+	BattleResult(BattleResult),
+	/// The hexes this player's country has started or stopped seeing. Nothing is sent about what happens on hexes it cannot see.
+	VisibilityChanged {
+		visible: Vec<HexCoord>,
+		hidden: Vec<HexCoord>,
+	},
+	/// Two countries have gone to war, made peace or formed an alliance. Every relation other than peace is sent with the map.
+	RelationChanged(RelationChange),
+	/// Another country has made a proposal to this player's country, which can be accepted with [`crate::diplomacy::DiplomaticAction::Accept`]
+	ProposalReceived {
+		from: CountryId,
+		proposal: Proposal,
+	},
+	/// The game has ended and no more orders are accepted. This is also sent to clients that join afterwards.
+	GameOver(GameResult),
+}
+
+#[test]
+fn handshake_encoding_is_stable() {
+	use crate::ClientMessage;
+	// Clients and servers of any version must be able to read the handshake, so these bytes should never change
+	let hello = ClientMessage::Hello {
+		protocol_version: 7,
+		client_build: "a".to_string(),
+	};
+	assert_eq!(bincode::serialize(&hello).unwrap(), [0, 0, 0, 0, 7, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, b'a']);
+	let rejected = ServerMessage::HelloRejected { reason: "b".to_string() };
+	assert_eq!(bincode::serialize(&rejected).unwrap(), [1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, b'b']);
+	let accepted = ServerMessage::HelloAccepted {
+		protocol_version: 7,
+		server_build: String::new(),
+	};
+	assert_eq!(bincode::serialize(&accepted).unwrap()[..8], [0, 0, 0, 0, 7, 0, 0, 0]);
 }
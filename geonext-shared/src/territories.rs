@@ -1,69 +1,178 @@
+use crate::country::Country;
+use crate::map_loader::HexCoord;
 use glam::{UVec2, Vec3};
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct CountryId(pub u8);
 
 impl CountryId {
 	pub const SEA: Self = CountryId(254);
-	pub fn colours(&self) -> [Vec3; 2] {
-		const COLOURS: [(u8, u8, u8); 12] = [
-			(128, 0, 0),
-			(255, 127, 80),
-			(255, 165, 0),
-			(255, 215, 0),
-			(128, 128, 0),
-			(0, 255, 0),
-			(0, 128, 128),
-			(100, 149, 237),
-			(0, 191, 255),
-			(65, 105, 225),
-			(147, 112, 219),
-			(255, 0, 255),
-		];
-		let (r, g, b) = COLOURS[self.0 as usize % COLOURS.len()];
-		let primary = Vec3::new(r as f32, g as f32, b as f32) / 255.;
-		let secondary = Vec3::new(0., 0., 1.);
-		[primary, secondary]
-	}
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
 pub struct Territories {
 	width: u32,
 	hexes: Vec<CountryId>,
-	country_names: Vec<String>,
+	countries: Vec<Country>,
+	/// The sequence number of the last [`TerritoryDelta`] applied. Not stored in map files or saves.
+	#[serde(skip)]
+	seq: u32,
 }
 impl Territories {
 	pub fn get_name(&self, country: CountryId) -> &str {
-		if let Some(name) = self.country_names.get(country.0 as usize) {
-			&name
+		if let Some(country) = self.country(country) {
+			&country.name
 		} else {
 			"Water"
 		}
 	}
+	pub fn country(&self, country: CountryId) -> Option<&Country> {
+		self.countries.get(country.0 as usize)
+	}
+	pub fn countries(&self) -> &[Country] {
+		&self.countries
+	}
+	pub fn countries_mut(&mut self) -> &mut [Country] {
+		&mut self.countries
+	}
+	/// The primary and secondary colours of a country, or grey if it has no metadata
+	pub fn colours(&self, country: CountryId) -> [Vec3; 2] {
+		self.country(country).map_or([Vec3::splat(0.5), Vec3::splat(0.25)], Country::colours)
+	}
 	pub fn country_id(&self, pos: UVec2) -> CountryId {
 		unsafe { *self.hexes.get_unchecked((pos.y * self.width + pos.x) as usize) }
 	}
+	/// The owner of a hex, or `None` if it is outside of the map
+	pub fn hex_owner(&self, hex: HexCoord) -> Option<CountryId> {
+		let offset = hex.to_offset();
+		if offset.x < 0 || offset.y < 0 || offset.x as u32 >= self.width {
+			return None;
+		}
+		self.hexes.get((offset.y as u32 * self.width + offset.x as u32) as usize).copied()
+	}
 	pub fn height(&self) -> u32 {
 		(self.hexes.len() as u32).checked_div(self.width).unwrap_or_default()
 	}
 	pub fn width(&self) -> u32 {
 		self.width
 	}
+	pub fn seq(&self) -> u32 {
+		self.seq
+	}
+
+	fn offset_index(&self, (x, y): (u32, u32)) -> Option<usize> {
+		(x < self.width && y < self.height()).then_some((y * self.width + x) as usize)
+	}
+
+	/// Changes the owners of some hexes, returning the delta that should be sent to clients.
+	///
+	/// Changes outside of the map or to the current owner are left out of the delta. The sequence number is only advanced
+	/// if something changed, so empty deltas do not need to be sent.
+	pub fn change_owners(&mut self, changes: impl IntoIterator<Item = ((u32, u32), CountryId)>) -> TerritoryDelta {
+		let mut delta = TerritoryDelta {
+			seq: self.seq + 1,
+			changes: Vec::new(),
+		};
+		for (offset, country) in changes {
+			let Some(index) = self.offset_index(offset) else { continue };
+			if self.hexes[index] != country {
+				self.hexes[index] = country;
+				delta.changes.push((offset, country));
+			}
+		}
+		if !delta.changes.is_empty() {
+			self.seq = delta.seq;
+		}
+		delta
+	}
+
+	/// Applies a delta received from the server, returning every country that gained or lost hexes.
+	///
+	/// Deltas must be applied in order. Nothing is changed if the delta is rejected.
+	pub fn apply(&mut self, delta: &TerritoryDelta) -> Result<Vec<CountryId>, TerritoryDeltaError> {
+		if delta.seq <= self.seq {
+			return Err(TerritoryDeltaError::Stale { seq: delta.seq, current: self.seq });
+		}
+		if delta.seq != self.seq + 1 {
+			return Err(TerritoryDeltaError::Missed {
+				expected: self.seq + 1,
+				found: delta.seq,
+			});
+		}
+		let indices = delta
+			.changes
+			.iter()
+			.map(|&(offset, _)| self.offset_index(offset).ok_or(TerritoryDeltaError::OutOfBounds { offset }))
+			.collect::<Result<Vec<_>, _>>()?;
+
+		let mut affected = Vec::new();
+		for (index, &(_, country)) in indices.into_iter().zip(&delta.changes) {
+			for changed in [self.hexes[index], country] {
+				if !affected.contains(&changed) {
+					affected.push(changed);
+				}
+			}
+			self.hexes[index] = country;
+		}
+		self.seq = delta.seq;
+		Ok(affected)
+	}
+}
+
+/// A set of ownership changes, sent instead of resending the whole map
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct TerritoryDelta {
+	/// Increases by one for each delta, so that clients can detect missed updates
+	pub seq: u32,
+	/// The new owner of each changed hex, by offset position
+	pub changes: Vec<((u32, u32), CountryId)>,
+}
+
+/// The reasons a [`TerritoryDelta`] can be rejected
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TerritoryDeltaError {
+	/// The delta has already been applied
+	Stale {
+		seq: u32,
+		current: u32,
+	},
+	/// One or more deltas were missed, so the whole map must be requested again
+	Missed {
+		expected: u32,
+		found: u32,
+	},
+	OutOfBounds {
+		offset: (u32, u32),
+	},
+}
+
+impl core::fmt::Display for TerritoryDeltaError {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		match self {
+			Self::Stale { seq, current } => write!(f, "Territory delta {seq} is older than the current map ({current})"),
+			Self::Missed { expected, found } => write!(f, "Expected territory delta {expected} but received {found}"),
+			Self::OutOfBounds { offset } => write!(f, "Territory change at {offset:?} is outside of the map"),
+		}
+	}
 }
-#[derive(Serialize, Deserialize, Clone, Debug)]
+
+impl std::error::Error for TerritoryDeltaError {}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct TerritoriesRLE {
 	width: u32,
 	hexes: Vec<(u16, CountryId)>,
-	country_names: Vec<String>,
+	countries: Vec<Country>,
+	seq: u32,
 }
 
 impl Territories {
 	pub fn to_rle(&self) -> TerritoriesRLE {
 		let mut hexes: Vec<(u16, CountryId)> = Vec::new();
 		for &item in &self.hexes {
-			let Some(last) = hexes.last_mut().filter(|last| last.1 == item) else {
+			// Runs longer than a u16 are split into several runs of the same country
+			let Some(last) = hexes.last_mut().filter(|last| last.1 == item && last.0 < u16::MAX) else {
 				hexes.push((1, item));
 				continue;
 			};
@@ -72,15 +181,184 @@ impl Territories {
 		TerritoriesRLE {
 			width: self.width,
 			hexes,
-			country_names: self.country_names.clone(),
+			countries: self.countries.clone(),
+			seq: self.seq,
 		}
 	}
 	pub fn from_rle(rle: &TerritoriesRLE) -> Self {
 		Self {
 			width: rle.width,
 			hexes: rle.hexes.iter().flat_map(|(count, val)| core::iter::repeat(*val).take(*count as usize)).collect(),
-			country_names: rle.country_names.clone(),
+			countries: rle.countries.clone(),
+			seq: rle.seq,
+		}
+	}
+}
+
+/// The most hexes a decoded map can have, so that malformed data cannot claim an enormous map
+pub const MAX_HEXES: u32 = 1 << 24;
+
+/// The reasons a varint encoded map can fail to decode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RleDecodeError {
+	/// The data ended part way through a value
+	Truncated,
+	/// A varint did not fit in a u32
+	Overflow,
+	InvalidName,
+	/// There were bytes left over after the map
+	TrailingData,
+	ZeroWidth,
+	/// The runs add up to more than [`MAX_HEXES`]
+	TooLarge,
+	/// The runs do not fill a whole number of rows
+	PartialRow,
+}
+
+impl core::fmt::Display for RleDecodeError {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		match self {
+			Self::Truncated => write!(f, "Territories data is truncated"),
+			Self::Overflow => write!(f, "Territories data contains a varint that is too large"),
+			Self::InvalidName => write!(f, "Territories data contains a country name that is not utf8"),
+			Self::TrailingData => write!(f, "Territories data has unexpected bytes after the end"),
+			Self::ZeroWidth => write!(f, "Territories data has a width of zero"),
+			Self::TooLarge => write!(f, "Territories data has more than {MAX_HEXES} hexes"),
+			Self::PartialRow => write!(f, "Territories data does not fill a whole number of rows"),
+		}
+	}
+}
+
+impl std::error::Error for RleDecodeError {}
+
+fn write_varint(result: &mut Vec<u8>, mut value: u32) {
+	while value >= 0x80 {
+		result.push(value as u8 | 0x80);
+		value >>= 7;
+	}
+	result.push(value as u8);
+}
+
+fn read_varint(data: &mut &[u8]) -> Result<u32, RleDecodeError> {
+	let mut value = 0u32;
+	for shift in (0..35).step_by(7) {
+		let (&byte, rest) = data.split_first().ok_or(RleDecodeError::Truncated)?;
+		*data = rest;
+		let bits = (byte & 0x7f) as u32;
+		if shift == 28 && bits > 0xf {
+			return Err(RleDecodeError::Overflow);
+		}
+		value |= bits << shift;
+		if byte & 0x80 == 0 {
+			return Ok(value);
+		}
+	}
+	Err(RleDecodeError::Overflow)
+}
+
+/// Maps signed integers to unsigned so that small negative numbers are also short varints
+fn zigzag(value: i32) -> u32 {
+	((value << 1) ^ (value >> 31)) as u32
+}
+
+fn unzigzag(value: u32) -> i32 {
+	(value >> 1) as i32 ^ -((value & 1) as i32)
+}
+
+fn read_bytes<'a>(data: &mut &'a [u8], count: usize) -> Result<&'a [u8], RleDecodeError> {
+	if data.len() < count {
+		return Err(RleDecodeError::Truncated);
+	}
+	let (bytes, rest) = data.split_at(count);
+	*data = rest;
+	Ok(bytes)
+}
+
+impl TerritoriesRLE {
+	/// Encodes the map with variable length integers, which is smaller than bincode as most runs are short.
+	///
+	/// The layout is the width, sequence number and country count as varints, each name as a varint length and utf8,
+	/// then the run count followed by a varint length and `u8` country for each run. Adjacent runs of the same
+	/// country are merged, so no run is limited to a u16.
+	pub fn encode_varint(&self) -> Vec<u8> {
+		let mut runs: Vec<(u32, CountryId)> = Vec::new();
+		for &(count, country) in &self.hexes {
+			match runs.last_mut() {
+				Some(last) if last.1 == country => last.0 += count as u32,
+				_ => runs.push((count as u32, country)),
+			}
 		}
+
+		let mut result = Vec::new();
+		write_varint(&mut result, self.width);
+		write_varint(&mut result, self.seq);
+		write_varint(&mut result, self.countries.len() as u32);
+		for country in &self.countries {
+			for text in [&country.name, &country.iso_code] {
+				write_varint(&mut result, text.len() as u32);
+				result.extend(text.as_bytes());
+			}
+			result.extend(country.primary);
+			result.extend(country.secondary);
+			write_varint(&mut result, zigzag(country.capital.q()));
+			write_varint(&mut result, zigzag(country.capital.r()));
+		}
+		write_varint(&mut result, runs.len() as u32);
+		for (count, country) in runs {
+			write_varint(&mut result, count);
+			result.push(country.0);
+		}
+		result
+	}
+
+	/// Decodes a map written by [`Self::encode_varint`]
+	pub fn decode_varint(mut data: &[u8]) -> Result<Self, RleDecodeError> {
+		let data = &mut data;
+		let width = read_varint(data)?;
+		if width == 0 {
+			return Err(RleDecodeError::ZeroWidth);
+		}
+		let seq = read_varint(data)?;
+		let country_count = read_varint(data)?;
+		let mut countries = Vec::new();
+		for _ in 0..country_count {
+			let mut read_text = || {
+				let len = read_varint(data)? as usize;
+				core::str::from_utf8(read_bytes(data, len)?).map(str::to_string).map_err(|_| RleDecodeError::InvalidName)
+			};
+			let name = read_text()?;
+			let iso_code = read_text()?;
+			let mut read_colour = || read_bytes(data, 3).map(|bytes| [bytes[0], bytes[1], bytes[2]]);
+			let primary = read_colour()?;
+			let secondary = read_colour()?;
+			let capital = HexCoord::new(unzigzag(read_varint(data)?), unzigzag(read_varint(data)?));
+			countries.push(Country {
+				name,
+				iso_code,
+				primary,
+				secondary,
+				capital,
+			});
+		}
+		let run_count = read_varint(data)?;
+		let (mut hexes, mut total) = (Vec::new(), 0u32);
+		for _ in 0..run_count {
+			let mut count = read_varint(data)?;
+			let country = CountryId(read_bytes(data, 1)?[0]);
+			total = total.checked_add(count).filter(|&total| total <= MAX_HEXES).ok_or(RleDecodeError::TooLarge)?;
+			while count > 0 {
+				let run = count.min(u16::MAX as u32);
+				hexes.push((run as u16, country));
+				count -= run;
+			}
+		}
+		if !data.is_empty() {
+			return Err(RleDecodeError::TrailingData);
+		}
+		if total % width != 0 {
+			return Err(RleDecodeError::PartialRow);
+		}
+		Ok(Self { width, hexes, countries, seq })
 	}
 }
 
@@ -92,3 +370,157 @@ fn load_territories() {
 	assert_eq!(x, y);
 	println!("{:?}", x.width);
 }
+
+#[test]
+fn territory_deltas() {
+	let mut server: Territories = bincode::deserialize(include_bytes!("./../../assets/starting_game_map")).unwrap();
+	let mut client = Territories::from_rle(&server.to_rle());
+	let zambia = server.hex_owner(HexCoord::new(101, 100)).unwrap();
+	let congo = server.hex_owner(HexCoord::new(100, 100)).unwrap();
+	let captured = HexCoord::new(100, 100).to_offset().as_uvec2();
+
+	let delta = server.change_owners([((captured.x, captured.y), zambia), ((captured.x, captured.y + 1000), zambia)]);
+	assert_eq!(
+		delta,
+		TerritoryDelta {
+			seq: 1,
+			changes: vec![((captured.x, captured.y), zambia)]
+		}
+	);
+	let mut affected = client.apply(&delta).unwrap();
+	affected.sort_by_key(|country| country.0);
+	let mut expected = vec![congo, zambia];
+	expected.sort_by_key(|country| country.0);
+	assert_eq!(affected, expected);
+	assert_eq!(client, server);
+
+	assert_eq!(client.apply(&delta), Err(TerritoryDeltaError::Stale { seq: 1, current: 1 }));
+	assert_eq!(server.change_owners([((captured.x, captured.y), zambia)]).changes, vec![]);
+	server.change_owners([((captured.x, captured.y), congo)]);
+	let skipped = server.change_owners([((captured.x, captured.y), zambia)]);
+	assert_eq!(client.apply(&skipped), Err(TerritoryDeltaError::Missed { expected: 2, found: 3 }));
+	let out_of_bounds = TerritoryDelta {
+		seq: 2,
+		changes: vec![((captured.x, captured.y), congo), ((0, 1000), congo)],
+	};
+	assert_eq!(client.apply(&out_of_bounds), Err(TerritoryDeltaError::OutOfBounds { offset: (0, 1000) }));
+	assert_eq!(client.country_id(captured), zambia);
+}
+
+/// Generates maps with a mix of short runs and runs longer than a u16, using a fixed seed so failures are repeatable
+#[cfg(test)]
+fn random_territories(seed: u32) -> impl Iterator<Item = Territories> {
+	let mut state = seed;
+	let mut next = move |max: u32| {
+		state ^= state << 13;
+		state ^= state >> 17;
+		state ^= state << 5;
+		state % max
+	};
+	core::iter::repeat_with(move || {
+		let width = 1 + next(400);
+		let height = 1 + next(400);
+		let mut hexes = Vec::with_capacity((width * height) as usize);
+		while hexes.len() < (width * height) as usize {
+			let run = match next(4) {
+				0 => 70_000 + next(70_000),
+				_ => 1 + next(50),
+			};
+			let country = if next(3) == 0 { CountryId::SEA } else { CountryId(next(20) as u8) };
+			hexes.extend(core::iter::repeat_n(country, run as usize));
+		}
+		hexes.truncate((width * height) as usize);
+		Territories {
+			width,
+			hexes,
+			countries: (0..next(5))
+				.map(|index| Country {
+					name: format!("Country {index} ☃"),
+					iso_code: "ZZ".to_string(),
+					primary: [next(256) as u8, 0, 255],
+					secondary: [0, next(256) as u8, 0],
+					capital: HexCoord::new(next(200) as i32 - 100, next(200) as i32 - 100),
+				})
+				.collect(),
+			seq: next(1000),
+		}
+	})
+}
+
+#[test]
+fn long_runs_are_split() {
+	// 263 wide, like the real map, so the u16 boundary falls part way through a row
+	let ocean = Territories {
+		width: 263,
+		hexes: vec![CountryId::SEA; 263 * 500],
+		countries: Vec::new(),
+		seq: 0,
+	};
+	let rle = ocean.to_rle();
+	assert_eq!(
+		rle.hexes,
+		vec![(u16::MAX, CountryId::SEA), (u16::MAX, CountryId::SEA), ((263 * 500 - 2 * u16::MAX as u32) as u16, CountryId::SEA)]
+	);
+	assert_eq!(Territories::from_rle(&rle), ocean);
+	assert_eq!(TerritoriesRLE::decode_varint(&rle.encode_varint()), Ok(rle));
+}
+
+#[test]
+fn rle_round_trip() {
+	for territories in random_territories(0x5eed).take(40) {
+		let rle = territories.to_rle();
+		assert!(rle.hexes.iter().all(|&(count, _)| count > 0));
+		assert_eq!(Territories::from_rle(&rle), territories);
+
+		let bincode_rle = bincode::deserialize(&bincode::serialize(&rle).unwrap()).unwrap();
+		assert_eq!(Territories::from_rle(&bincode_rle), territories);
+
+		let encoded = rle.encode_varint();
+		assert_eq!(Territories::from_rle(&TerritoriesRLE::decode_varint(&encoded).unwrap()), territories);
+	}
+}
+
+#[test]
+fn varint_encoding() {
+	let territories: Territories = bincode::deserialize(include_bytes!("./../../assets/starting_game_map")).unwrap();
+	let rle = territories.to_rle();
+	let encoded = rle.encode_varint();
+	assert!(encoded.len() < bincode::serialize(&rle).unwrap().len());
+	assert_eq!(TerritoriesRLE::decode_varint(&encoded), Ok(rle));
+
+	for len in 0..encoded.len() {
+		assert!(TerritoriesRLE::decode_varint(&encoded[..len]).is_err());
+	}
+	let mut trailing = encoded.clone();
+	trailing.push(0);
+	assert_eq!(TerritoriesRLE::decode_varint(&trailing), Err(RleDecodeError::TrailingData));
+	assert_eq!(TerritoriesRLE::decode_varint(&[0xff, 0xff, 0xff, 0xff, 0x1f]), Err(RleDecodeError::Overflow));
+
+	// Width, sequence number, country count and run count, followed by the runs
+	let header = |width: u32, runs: &[u32]| {
+		let mut data = Vec::new();
+		for value in [width, 0, 0, runs.len() as u32] {
+			write_varint(&mut data, value);
+		}
+		for &run in runs {
+			write_varint(&mut data, run);
+			data.push(CountryId::SEA.0);
+		}
+		data
+	};
+	assert_eq!(TerritoriesRLE::decode_varint(&header(0, &[])), Err(RleDecodeError::ZeroWidth));
+	assert_eq!(TerritoriesRLE::decode_varint(&header(10, &[15])), Err(RleDecodeError::PartialRow));
+	assert_eq!(TerritoriesRLE::decode_varint(&header(1, &[MAX_HEXES, 1])), Err(RleDecodeError::TooLarge));
+	assert_eq!(TerritoriesRLE::decode_varint(&header(1, &[u32::MAX, u32::MAX])), Err(RleDecodeError::TooLarge));
+	assert!(TerritoriesRLE::decode_varint(&header(10, &[5, 15])).is_ok());
+
+	let mut varint = Vec::new();
+	for value in [i32::MIN, -65, -1, 0, 1, 64, i32::MAX] {
+		assert_eq!(unzigzag(zigzag(value)), value);
+	}
+	for value in [0, 1, 127, 128, 16_383, 16_384, u32::MAX] {
+		varint.clear();
+		write_varint(&mut varint, value);
+		assert_eq!(read_varint(&mut varint.as_slice()), Ok(value));
+	}
+}
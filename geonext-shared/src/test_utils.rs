@@ -0,0 +1,66 @@
+use crate::map_format::{encode, ChannelData, ChannelType};
+use crate::map_loader::{HeightMap, HexCoord};
+use crate::regions::Regions;
+use crate::territories::{CountryId, Territories};
+
+pub fn height_map() -> HeightMap {
+	let mut height_map = HeightMap::default();
+	height_map.load(include_bytes!("./../../assets/map.txt").to_vec()).unwrap();
+	height_map
+}
+
+pub fn territories() -> Territories {
+	bincode::deserialize(include_bytes!("./../../assets/starting_game_map")).unwrap()
+}
+
+/// The height map and starting territories of the real map
+pub fn map() -> (HeightMap, Territories) {
+	(height_map(), territories())
+}
+
+pub fn regions(territories: &Territories) -> Regions {
+	Regions::from_json(include_str!("./../../assets/axial-coordinates.json"), include_str!("./../../assets/neighbours.json"), territories).unwrap()
+}
+
+/// Two neighbouring countries on the starting map, and a distant one
+pub struct Border {
+	pub congo_hex: HexCoord,
+	pub zambia_hex: HexCoord,
+	pub chile_hex: HexCoord,
+	pub congo: CountryId,
+	pub zambia: CountryId,
+	pub chile: CountryId,
+}
+
+pub fn border(territories: &Territories) -> Border {
+	let (congo_hex, zambia_hex, chile_hex) = (HexCoord::new(100, 100), HexCoord::new(101, 100), HexCoord::new(10, 133));
+	Border {
+		congo_hex,
+		zambia_hex,
+		chile_hex,
+		congo: territories.hex_owner(congo_hex).unwrap(),
+		zambia: territories.hex_owner(zambia_hex).unwrap(),
+		chile: territories.hex_owner(chile_hex).unwrap(),
+	}
+}
+
+/// A square map of `size` hexes along each side, with the topography, vegetation and rivers of each hex given by `channels`
+pub fn synthetic_map(size: u16, channels: impl Fn(HexCoord) -> (u8, u8, u8)) -> HeightMap {
+	let len = size as usize * size as usize;
+	let (mut topo, mut veg, mut rivers) = (vec![0; len], vec![0; len], vec![0; len]);
+	for index in 0..len {
+		// Channels are stored column by column
+		let hex = HexCoord::from_offset((index / size as usize) as i32, (index % size as usize) as i32);
+		(topo[index], veg[index], rivers[index]) = channels(hex);
+	}
+	let channel = |name, data| ChannelData {
+		name,
+		data_type: ChannelType::U8,
+		data,
+	};
+	let mut height_map = HeightMap::default();
+	height_map
+		.load(encode(size, size, &[channel("topo", &topo), channel("veg", &veg), channel("rivers", &rivers)], false))
+		.unwrap();
+	height_map
+}
@@ -0,0 +1,198 @@
+use crate::diplomacy::Diplomacy;
+use crate::map_loader::{HeightMap, HexCoord};
+use crate::pathfinding::MovementCosts;
+use crate::territories::{CountryId, Territories};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+
+/// Entering flat land costs 10, so this is half a second per hex
+pub const MOVEMENT_PER_TICK: u32 = 2;
+pub const MAX_PATH_LEN: usize = 256;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct UnitId(pub u32);
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Unit {
+	pub id: UnitId,
+	pub owner: CountryId,
+	pub position: HexCoord,
+	/// Armies are removed when this reaches 0
+	pub strength: u16,
+	/// Next hex first
+	pub path: VecDeque<HexCoord>,
+	pub progress: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MoveError {
+	UnknownUnit(UnitId),
+	NotOwned(UnitId),
+	TooLong(usize),
+	NotAdjacent { from: HexCoord, to: HexCoord },
+	Impassable(HexCoord),
+}
+
+impl core::fmt::Display for MoveError {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		match self {
+			Self::UnknownUnit(unit) => write!(f, "Unit {} does not exist", unit.0),
+			Self::NotOwned(unit) => write!(f, "Unit {} belongs to another country", unit.0),
+			Self::TooLong(len) => write!(f, "Paths can be at most {MAX_PATH_LEN} hexes, not {len}"),
+			Self::NotAdjacent { from, to } => write!(f, "Hex {to:?} is not next to hex {from:?}"),
+			Self::Impassable(hex) => write!(f, "Hex {hex:?} cannot be entered"),
+		}
+	}
+}
+
+impl std::error::Error for MoveError {}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Units {
+	units: BTreeMap<UnitId, Unit>,
+	next_id: u32,
+}
+
+impl Units {
+	pub fn spawn(&mut self, owner: CountryId, position: HexCoord, strength: u16) -> UnitId {
+		let id = UnitId(self.next_id);
+		self.next_id += 1;
+		self.units.insert(
+			id,
+			Unit {
+				id,
+				owner,
+				position,
+				strength,
+				path: VecDeque::new(),
+				progress: 0,
+			},
+		);
+		id
+	}
+	/// Without any checks, for applying changes from the server
+	pub fn insert(&mut self, unit: Unit) {
+		self.next_id = self.next_id.max(unit.id.0 + 1);
+		self.units.insert(unit.id, unit);
+	}
+	pub fn get(&self, id: UnitId) -> Option<&Unit> {
+		self.units.get(&id)
+	}
+	pub fn get_mut(&mut self, id: UnitId) -> Option<&mut Unit> {
+		self.units.get_mut(&id)
+	}
+	pub fn remove(&mut self, id: UnitId) -> Option<Unit> {
+		self.units.remove(&id)
+	}
+	pub fn clear(&mut self) {
+		self.units.clear();
+	}
+	pub fn iter(&self) -> impl Iterator<Item = &Unit> {
+		self.units.values()
+	}
+	pub fn at(&self, hex: HexCoord) -> impl Iterator<Item = &Unit> {
+		self.iter().filter(move |unit| unit.position == hex)
+	}
+	pub fn owned_by(&self, country: CountryId) -> impl Iterator<Item = &Unit> {
+		self.iter().filter(move |unit| unit.owner == country)
+	}
+
+	/// The path does not include the hex the unit is on, and an empty path stops the unit
+	pub fn order_move(&mut self, country: CountryId, id: UnitId, path: Vec<HexCoord>, height_map: &HeightMap, territories: &Territories, diplomacy: &Diplomacy) -> Result<(), MoveError> {
+		let unit = self.units.get_mut(&id).ok_or(MoveError::UnknownUnit(id))?;
+		if unit.owner != country {
+			return Err(MoveError::NotOwned(id));
+		}
+		if path.len() > MAX_PATH_LEN {
+			return Err(MoveError::TooLong(path.len()));
+		}
+		let costs = MovementCosts::default();
+		let mut from = unit.position;
+		for &to in &path {
+			if !from.is_neighbour(to) {
+				return Err(MoveError::NotAdjacent { from, to });
+			}
+			costs.step_cost(height_map, territories, diplomacy, country, from, to).ok_or(MoveError::Impassable(to))?;
+			from = to;
+		}
+		unit.path = path.into();
+		unit.progress = 0;
+		Ok(())
+	}
+
+	/// Returns the units that entered a new hex, or stopped because their next step can no longer be taken
+	pub fn advance(&mut self, height_map: &HeightMap, territories: &Territories, diplomacy: &Diplomacy) -> Vec<Unit> {
+		let costs = MovementCosts::default();
+		let mut changed = Vec::new();
+		for unit in self.units.values_mut() {
+			let Some(&next) = unit.path.front() else { continue };
+			let step = Some(next)
+				.filter(|next| next.is_neighbour(unit.position))
+				.and_then(|next| costs.step_cost(height_map, territories, diplomacy, unit.owner, unit.position, next));
+			let Some(step) = step else {
+				unit.path.clear();
+				unit.progress = 0;
+				changed.push(unit.clone());
+				continue;
+			};
+			unit.progress += MOVEMENT_PER_TICK;
+			if unit.progress >= step {
+				unit.progress = 0;
+				unit.position = next;
+				unit.path.pop_front();
+				changed.push(unit.clone());
+			}
+		}
+		changed
+	}
+}
+
+#[cfg(test)]
+use crate::test_utils;
+
+#[test]
+fn units_follow_their_path() {
+	let (height_map, territories) = test_utils::map();
+	let test_utils::Border { congo_hex: start, congo: country, .. } = test_utils::border(&territories);
+	let mut units = Units::default();
+	let army = units.spawn(country, start, 5);
+	let goal = HexCoord::new(103, 99);
+	let mut diplomacy = Diplomacy::default();
+	diplomacy.declare_war(country, territories.hex_owner(goal).unwrap()).unwrap();
+	let path = crate::pathfinding::find_path(&height_map, &territories, &diplomacy, country, start, goal, &MovementCosts::default()).unwrap();
+	units.order_move(country, army, path.hexes[1..].to_vec(), &height_map, &territories, &diplomacy).unwrap();
+
+	let mut entered = Vec::new();
+	let mut ticks = 0;
+	while units.get(army).is_some_and(|unit| !unit.path.is_empty()) {
+		entered.extend(units.advance(&height_map, &territories, &diplomacy).into_iter().map(|unit| unit.position));
+		ticks += 1;
+	}
+	assert_eq!(entered, path.hexes[1..]);
+	// Each step takes as many ticks as its cost needs
+	assert!(ticks >= path.cost / MOVEMENT_PER_TICK);
+	assert!(units.advance(&height_map, &territories, &diplomacy).is_empty(), "Units without a path stay put");
+}
+
+#[test]
+fn invalid_paths() {
+	let (height_map, territories) = test_utils::map();
+	let test_utils::Border { congo_hex: start, congo: country, .. } = test_utils::border(&territories);
+	let mut units = Units::default();
+	let army = units.spawn(country, start, 5);
+	let diplomacy = Diplomacy::default();
+	let mut order = |country, id, path: &[HexCoord]| units.order_move(country, id, path.to_vec(), &height_map, &territories, &diplomacy);
+
+	let far = HexCoord::new(105, 100);
+	assert_eq!(order(country, army, &[far]), Err(MoveError::NotAdjacent { from: start, to: far }));
+	assert_eq!(order(CountryId(country.0 + 1), army, &[start + HexCoord::RIGHT]), Err(MoveError::NotOwned(army)));
+	assert_eq!(order(country, UnitId(7), &[]), Err(MoveError::UnknownUnit(UnitId(7))));
+	let long = (1..=MAX_PATH_LEN as i32 + 1).map(|q| start + HexCoord::new(q, 0)).collect::<Vec<_>>();
+	assert_eq!(order(country, army, &long), Err(MoveError::TooLong(MAX_PATH_LEN + 1)));
+	assert_eq!(order(country, army, &[]), Ok(()), "An empty path stops the unit");
+
+	let sea = HexCoord::from_offset(0, 0);
+	let mut units = Units::default();
+	let coastal = units.spawn(country, sea + HexCoord::RIGHT, 5);
+	assert_eq!(units.order_move(country, coastal, vec![sea], &height_map, &territories, &diplomacy), Err(MoveError::Impassable(sea)));
+}
@@ -0,0 +1,255 @@
+use crate::territories::{CountryId, Territories};
+use crate::units::Units;
+use glam::UVec2;
+use serde::{Deserialize, Serialize};
+
+/// Conditions that are `None` or `false` are not checked
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VictoryConditions {
+	/// Percentage of the land
+	pub domination_percent: Option<u8>,
+	/// Holding the capital of every other player's country, when there are any
+	pub capitals: bool,
+	/// Only for turn-based games, where the highest score wins
+	pub turn_limit: Option<u32>,
+	pub score_limit: Option<u32>,
+}
+
+impl Default for VictoryConditions {
+	fn default() -> Self {
+		Self {
+			domination_percent: Some(50),
+			capitals: true,
+			turn_limit: None,
+			score_limit: None,
+		}
+	}
+}
+
+impl VictoryConditions {
+	pub const NONE: Self = Self {
+		domination_percent: None,
+		capitals: false,
+		turn_limit: None,
+		score_limit: None,
+	};
+
+	/// `turns_played` is `None` if the game is not turn-based
+	pub fn check(&self, counts: &mut HexCounts, territories: &Territories, units: &Units, players: &[CountryId], turns_played: Option<u32>) -> Option<GameResult> {
+		if players.is_empty() || *self == Self::NONE {
+			return None;
+		}
+		counts.update(territories);
+		let standings = standings(counts, units, players);
+		let over = |reason, winner| {
+			Some(GameResult {
+				reason,
+				winner,
+				standings: standings.clone(),
+			})
+		};
+
+		if let Some(percent) = self.domination_percent {
+			let land = counts.land;
+			if let Some(winner) = standings.iter().find(|standing| land > 0 && standing.hexes as u64 * 100 >= percent as u64 * land as u64) {
+				return over(VictoryReason::Domination, Some(winner.country));
+			}
+		}
+		if self.capitals && players.len() > 1 {
+			let capitals = players.iter().map(|&country| territories.country(country).map(|country| territories.hex_owner(country.capital)));
+			let owners = capitals.collect::<Option<Vec<_>>>().unwrap_or_default();
+			if let Some(Some(winner)) = owners.first().filter(|&&first| owners.iter().all(|&owner| owner == first)) {
+				if players.contains(winner) {
+					return over(VictoryReason::Capitals, Some(*winner));
+				}
+			}
+		}
+		if let Some(limit) = self.score_limit {
+			if standings[0].score >= limit {
+				return over(VictoryReason::ScoreLimit, Some(standings[0].country));
+			}
+		}
+		if let (Some(limit), Some(turns)) = (self.turn_limit, turns_played) {
+			if turns >= limit {
+				// Countries with the same score draw
+				let winner = Some(standings[0].country).filter(|_| standings.get(1).is_none_or(|second| second.score < standings[0].score));
+				return over(VictoryReason::TurnLimit, winner);
+			}
+		}
+		None
+	}
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VictoryReason {
+	Domination,
+	Capitals,
+	TurnLimit,
+	ScoreLimit,
+}
+
+impl core::fmt::Display for VictoryReason {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		match self {
+			Self::Domination => write!(f, "domination"),
+			Self::Capitals => write!(f, "capturing every capital"),
+			Self::TurnLimit => write!(f, "reaching the turn limit"),
+			Self::ScoreLimit => write!(f, "reaching the score limit"),
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Standing {
+	pub country: CountryId,
+	/// Land hexes
+	pub hexes: u32,
+	pub strength: u32,
+	/// Hexes plus strength
+	pub score: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct GameResult {
+	pub reason: VictoryReason,
+	/// `None` for a draw
+	pub winner: Option<CountryId>,
+	/// Best first
+	pub standings: Vec<Standing>,
+}
+
+/// Only counted again once the territories have changed
+#[derive(Clone, Debug, Default)]
+pub struct HexCounts {
+	seq: Option<u32>,
+	/// By country id
+	hexes: Vec<u32>,
+	land: u32,
+}
+
+impl HexCounts {
+	pub fn update(&mut self, territories: &Territories) {
+		if self.seq == Some(territories.seq()) {
+			return;
+		}
+		self.hexes = vec![0; u8::MAX as usize + 1];
+		for y in 0..territories.height() {
+			for x in 0..territories.width() {
+				self.hexes[territories.country_id(UVec2::new(x, y)).0 as usize] += 1;
+			}
+		}
+		self.land = self.hexes.iter().sum::<u32>() - self.hexes[CountryId::SEA.0 as usize];
+		self.seq = Some(territories.seq());
+	}
+
+	pub fn hexes(&self, country: CountryId) -> u32 {
+		self.hexes.get(country.0 as usize).copied().unwrap_or(0)
+	}
+}
+
+/// Sorted by score and then by id
+pub fn standings(counts: &HexCounts, units: &Units, players: &[CountryId]) -> Vec<Standing> {
+	let mut standings = players
+		.iter()
+		.map(|&country| {
+			let hexes = counts.hexes(country);
+			let strength = units.owned_by(country).map(|unit| unit.strength as u32).sum::<u32>();
+			Standing {
+				country,
+				hexes,
+				strength,
+				score: hexes + strength,
+			}
+		})
+		.collect::<Vec<_>>();
+	standings.sort_by_key(|standing| (core::cmp::Reverse(standing.score), standing.country));
+	standings
+}
+
+#[cfg(test)]
+use crate::test_utils;
+
+#[cfg(test)]
+use crate::map_loader::HexCoord;
+
+#[cfg(test)]
+fn offset(hex: HexCoord) -> (u32, u32) {
+	let offset = hex.to_offset().as_uvec2();
+	(offset.x, offset.y)
+}
+
+#[test]
+fn domination_and_score() {
+	let mut territories = test_utils::territories();
+	let test_utils::Border { zambia_hex, congo, zambia, .. } = test_utils::border(&territories);
+	let mut units = Units::default();
+	units.spawn(zambia, zambia_hex, 30);
+	let players = [congo, zambia];
+	let mut counts = HexCounts::default();
+	assert_eq!(VictoryConditions::NONE.check(&mut counts, &territories, &units, &players, Some(1000)), None);
+	assert_eq!(counts.seq, None, "Nothing is counted for games that cannot end");
+
+	counts.update(&territories);
+	let standings = standings(&counts, &units, &players);
+	assert_eq!(standings.len(), 2);
+	assert!(standings[0].score >= standings[1].score);
+	let zambia_standing = standings.iter().find(|standing| standing.country == zambia).unwrap();
+	assert_eq!(zambia_standing.strength, 30);
+	assert_eq!(zambia_standing.score, zambia_standing.hexes + 30);
+
+	let conditions = VictoryConditions::default();
+	assert_eq!(conditions.check(&mut counts, &territories, &units, &players, None), None);
+	let limit = VictoryConditions {
+		score_limit: Some(zambia_standing.score),
+		..VictoryConditions::NONE
+	};
+	assert!(matches!(
+		limit.check(&mut counts, &territories, &units, &players, None),
+		Some(GameResult {
+			reason: VictoryReason::ScoreLimit,
+			winner: Some(_),
+			..
+		})
+	));
+
+	// Give Congo every land hex
+	let land = (0..territories.height())
+		.flat_map(|y| (0..territories.width()).map(move |x| (x, y)))
+		.filter(|&(x, y)| territories.country_id(UVec2::new(x, y)) != CountryId::SEA)
+		.map(|position| (position, congo))
+		.collect::<Vec<_>>();
+	territories.change_owners(land);
+	let result = conditions.check(&mut counts, &territories, &units, &players, None).unwrap();
+	assert_eq!((result.reason, result.winner), (VictoryReason::Domination, Some(congo)));
+	assert_eq!(result.standings[0].country, congo);
+	assert_eq!(result.standings[0].hexes, counts.land, "The hexes are counted again once they change hands");
+}
+
+#[test]
+fn capitals_and_turn_limit() {
+	let mut territories = test_utils::territories();
+	let (a, b) = (CountryId(1), CountryId(2));
+	let capital = territories.country(b).unwrap().capital;
+	let units = Units::default();
+	let mut counts = HexCounts::default();
+	let conditions = VictoryConditions {
+		domination_percent: None,
+		..Default::default()
+	};
+	assert_eq!(conditions.check(&mut counts, &territories, &units, &[a, b], None), None);
+	assert_eq!(conditions.check(&mut counts, &territories, &units, &[a], None), None, "Capitals only end games with other players");
+
+	territories.change_owners([(offset(capital), a)]);
+	let result = conditions.check(&mut counts, &territories, &units, &[a, b], None).unwrap();
+	assert_eq!((result.reason, result.winner), (VictoryReason::Capitals, Some(a)));
+
+	let turns = VictoryConditions {
+		turn_limit: Some(5),
+		..VictoryConditions::NONE
+	};
+	assert_eq!(turns.check(&mut counts, &territories, &units, &[a, b], Some(4)), None);
+	assert_eq!(turns.check(&mut counts, &territories, &units, &[a, b], None), None, "Turn limits only apply to turn-based games");
+	let result = turns.check(&mut counts, &territories, &units, &[a, b], Some(5)).unwrap();
+	assert_eq!(result.reason, VictoryReason::TurnLimit);
+	assert_eq!(result.winner, Some(result.standings[0].country));
+}
@@ -0,0 +1,143 @@
+use crate::map_loader::{HeightMap, HexCoord};
+use crate::territories::{CountryId, Territories};
+use crate::units::Units;
+use glam::UVec2;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+pub const TERRITORY_SIGHT: u32 = 1;
+pub const UNIT_SIGHT: u32 = 3;
+/// In the same units as [`HeightMap::elevation_to_z`]
+const EYE_HEIGHT: f32 = 0.5;
+
+/// The line runs from [`EYE_HEIGHT`] above `from` to the ground at `to`, and is blocked by any hex in between that rises above it
+pub fn line_of_sight(height_map: &HeightMap, from: HexCoord, to: HexCoord) -> bool {
+	let height = |hex| height_map.elevation(hex).map(HeightMap::elevation_to_z);
+	let (Some(eye), Some(target)) = (height(from).map(|z| z + EYE_HEIGHT), height(to)) else {
+		return false;
+	};
+	let steps = from.distance(to);
+	from.line_to(to)
+		.enumerate()
+		.skip(1)
+		.take((steps - 1).max(0) as usize)
+		.all(|(step, hex)| height(hex).is_some_and(|z| z <= eye + (target - eye) * step as f32 / steps as f32))
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Visibility {
+	hexes: HashSet<HexCoord>,
+}
+
+impl Visibility {
+	/// Allies share what they see, so this takes every country whose sight is pooled
+	pub fn compute(countries: &[CountryId], territories: &Territories, units: &Units, height_map: &HeightMap) -> Self {
+		let mut visibility = Self::territory(countries, territories);
+		visibility.add_armies(countries, units, height_map);
+		visibility
+	}
+
+	/// This only changes when territory changes hands, so it can be kept between ticks
+	pub fn territory(countries: &[CountryId], territories: &Territories) -> Self {
+		let in_map = |hex: &HexCoord| territories.hex_owner(*hex).is_some();
+		let owned = (0..territories.height())
+			.flat_map(|y| (0..territories.width()).map(move |x| UVec2::new(x, y)))
+			.filter(|&offset| countries.contains(&territories.country_id(offset)))
+			.map(|offset| HexCoord::from_offset(offset.x as i32, offset.y as i32));
+		Self {
+			hexes: owned.flat_map(|hex| hex.spiral(TERRITORY_SIGHT)).filter(in_map).collect(),
+		}
+	}
+
+	pub fn add_armies(&mut self, countries: &[CountryId], units: &Units, height_map: &HeightMap) {
+		for unit in units.iter().filter(|unit| countries.contains(&unit.owner)) {
+			self.hexes.extend(unit.position.spiral(UNIT_SIGHT).filter(|&hex| line_of_sight(height_map, unit.position, hex)));
+		}
+	}
+
+	pub fn add(&mut self, other: &Self) {
+		self.hexes.extend(&other.hexes);
+	}
+
+	pub fn contains(&self, hex: HexCoord) -> bool {
+		self.hexes.contains(&hex)
+	}
+	/// In no particular order
+	pub fn iter(&self) -> impl Iterator<Item = HexCoord> + '_ {
+		self.hexes.iter().copied()
+	}
+	pub fn len(&self) -> usize {
+		self.hexes.len()
+	}
+	pub fn is_empty(&self) -> bool {
+		self.hexes.is_empty()
+	}
+
+	/// Returns the newly visible and newly hidden hexes, each sorted by row
+	pub fn changes_since(&self, previous: &Self) -> (Vec<HexCoord>, Vec<HexCoord>) {
+		let mut visible = self.hexes.difference(&previous.hexes).copied().collect::<Vec<_>>();
+		let mut hidden = previous.hexes.difference(&self.hexes).copied().collect::<Vec<_>>();
+		visible.sort_unstable_by_key(|hex| (hex.r(), hex.q()));
+		hidden.sort_unstable_by_key(|hex| (hex.r(), hex.q()));
+		(visible, hidden)
+	}
+
+	pub fn apply(&mut self, visible: &[HexCoord], hidden: &[HexCoord]) {
+		for hex in hidden {
+			self.hexes.remove(hex);
+		}
+		self.hexes.extend(visible);
+	}
+}
+
+#[cfg(test)]
+use crate::test_utils;
+
+#[test]
+fn territory_and_armies_reveal_hexes() {
+	let (height_map, territories) = test_utils::map();
+	let test_utils::Border {
+		congo_hex,
+		zambia_hex,
+		chile_hex,
+		congo,
+		..
+	} = test_utils::border(&territories);
+	let mut units = Units::default();
+
+	let visibility = Visibility::compute(&[congo], &territories, &units, &height_map);
+	assert!(visibility.contains(congo_hex));
+	assert!(visibility.contains(zambia_hex), "Hexes next to the border are visible");
+	assert!(!visibility.contains(chile_hex));
+	assert!(visibility.iter().all(|hex| territories.hex_owner(hex).is_some()));
+
+	units.spawn(congo, chile_hex, 1);
+	let with_army = Visibility::compute(&[congo], &territories, &units, &height_map);
+	assert!(with_army.contains(chile_hex));
+	assert!(with_army.iter().filter(|hex| !visibility.contains(*hex)).all(|hex| hex.distance(chile_hex) <= UNIT_SIGHT as i32));
+	let (visible, hidden) = with_army.changes_since(&visibility);
+	assert!(visible.contains(&chile_hex) && hidden.is_empty());
+	let mut applied = visibility.clone();
+	applied.apply(&visible, &hidden);
+	assert_eq!(applied, with_army);
+	assert_eq!(visibility.changes_since(&with_army), (hidden, visible));
+
+	let chile = territories.hex_owner(chile_hex).unwrap();
+	assert!(
+		Visibility::compute(&[congo, chile], &territories, &Units::default(), &height_map).contains(chile_hex),
+		"Allies share their sight"
+	);
+}
+
+#[test]
+fn hills_block_line_of_sight() {
+	let (from, hill, behind) = (HexCoord::from_offset(1, 4), HexCoord::from_offset(3, 4), HexCoord::from_offset(5, 4));
+	let height_map = test_utils::synthetic_map(8, |hex| if hex == hill { (200, 0, 0) } else { (10, 0, 0) });
+
+	assert!(line_of_sight(&height_map, from, hill), "The hill itself can be seen");
+	assert!(!line_of_sight(&height_map, from, behind));
+	assert!(line_of_sight(&height_map, hill, behind), "Armies on the hill can see past it");
+	assert!(line_of_sight(&height_map, from, from + HexCoord::BOTTOM_RIGHT));
+	assert!(line_of_sight(&height_map, from, from));
+	assert!(!line_of_sight(&height_map, from, HexCoord::new(-100, 0)));
+}
@@ -0,0 +1,1179 @@
+use crate::view::View;
+use anyhow::anyhow;
+use geonext_shared::buildings::Buildings;
+use geonext_shared::capture::plan_capture;
+use geonext_shared::deformation::{Deformation, ElevationChange};
+use geonext_shared::diplomacy::{Diplomacy, DiplomaticAction};
+use geonext_shared::economy::{Building, BuildingKind, Economy, Resources};
+use geonext_shared::lobby::GameMode;
+use geonext_shared::map_loader::{HeightMap, HexCoord};
+use geonext_shared::orders::{Order, OrderResult, MAX_ORDERS};
+use geonext_shared::regions::Regions;
+use geonext_shared::territories::{CountryId, Territories};
+use geonext_shared::units::{UnitId, Units};
+use geonext_shared::victory::{GameResult, HexCounts, VictoryConditions};
+use geonext_shared::ServerMessage;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::time::{Instant, MissedTickBehavior};
+
+/// The number of recent updates kept so that reconnecting clients can catch up without resending the whole map
+const HISTORY_LEN: usize = 256;
+/// The time between ticks, when player actions are applied
+pub const TICK_INTERVAL: Duration = Duration::from_millis(100);
+/// The strength of the army each player starts with at their capital
+const STARTING_ARMY: u16 = 10;
+/// The resources each player starts with
+const STARTING_RESOURCES: Resources = Resources::new(100, 100, 50);
+/// The number of ticks between economy reports to each player
+const REPORT_INTERVAL: u64 = 10;
+
+/// Which clients an update is for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Audience {
+	Everyone,
+	/// Only the player controlling this country
+	Country(CountryId),
+	/// Only clients without a country, who can see the whole map
+	Spectators,
+}
+
+impl Audience {
+	/// Whether a client controlling `country` should be sent the update
+	pub fn includes(self, country: Option<CountryId>) -> bool {
+		match self {
+			Self::Everyone => true,
+			Self::Country(audience) => country == Some(audience),
+			Self::Spectators => country.is_none(),
+		}
+	}
+}
+
+/// A message sent to clients in a game, numbered so that clients can tell which updates they have seen
+pub type Update = (u64, Audience, ServerMessage);
+
+/// An order from a real-time game waiting for the next tick, with the player who gave it and where to send the result
+type PendingOrder = (String, Order, oneshot::Sender<Result<(), String>>);
+
+/// A request from a connection to the task running its game
+pub enum Command {
+	/// Starts sending updates to a player's connection, replying with what it needs to catch up
+	Subscribe {
+		player: String,
+		resume_token: Option<u64>,
+		reply: oneshot::Sender<Subscription>,
+	},
+	/// Remembers a disconnected session so that it can be resumed
+	Suspend { resume_token: u64, last_seen: u64 },
+	/// Carries out a player's order on the next tick, replying with an error if it failed
+	Act {
+		player: String,
+		order: Order,
+		reply: oneshot::Sender<Result<(), String>>,
+	},
+	/// Stores a player's orders until the end of the turn
+	SubmitOrders {
+		player: String,
+		turn: u32,
+		orders: Vec<Order>,
+		reply: oneshot::Sender<Result<(), String>>,
+	},
+	/// Changes the relations of a player's country straight away, in both real-time and turn-based games
+	Diplomacy {
+		player: String,
+		action: DiplomaticAction,
+		reply: oneshot::Sender<Result<(), String>>,
+	},
+	/// Copies the state of the game so that it can be saved
+	Snapshot { reply: oneshot::Sender<GameSnapshot> },
+	/// Frees the country of a player who has left for good
+	Release { player: String },
+}
+
+/// The map that every game starts with
+#[derive(Clone, Default)]
+pub struct StartingMap {
+	pub territories: Territories,
+	pub regions: Regions,
+	pub height_map: HeightMap,
+}
+
+/// What a connection receives when it subscribes to a game
+pub struct Subscription {
+	pub updates: broadcast::Receiver<Update>,
+	/// The messages needed to catch up, either the full state or the updates missed since the session was suspended
+	pub catch_up: Vec<ServerMessage>,
+	/// The index of the last update included in `catch_up`
+	pub last_update: u64,
+	pub resume_token: u64,
+	/// The country the player controls, which decides the updates they are sent
+	pub country: Option<CountryId>,
+}
+
+/// Sends commands to a running game
+#[derive(Clone)]
+pub struct GameHandle {
+	commands: mpsc::Sender<Command>,
+}
+
+impl GameHandle {
+	/// A handle to a game whose task has already ended
+	#[cfg(test)]
+	pub fn ended() -> Self {
+		let (commands, _) = mpsc::channel(1);
+		Self { commands }
+	}
+
+	async fn request<T>(&self, command: impl FnOnce(oneshot::Sender<T>) -> Command) -> anyhow::Result<T> {
+		let (reply, response) = oneshot::channel();
+		self.commands.send(command(reply)).await.map_err(|_| anyhow!("The game has ended"))?;
+		response.await.map_err(|_| anyhow!("The game has ended"))
+	}
+
+	pub async fn subscribe(&self, player: String, resume_token: Option<u64>) -> anyhow::Result<Subscription> {
+		self.request(|reply| Command::Subscribe { player, resume_token, reply }).await
+	}
+
+	pub async fn suspend(&self, resume_token: u64, last_seen: u64) {
+		let _ = self.commands.send(Command::Suspend { resume_token, last_seen }).await;
+	}
+
+	/// Waits for the order to be carried out on the next tick
+	pub async fn act(&self, player: String, order: Order) -> anyhow::Result<()> {
+		self.request(|reply| Command::Act { player, order, reply }).await?.map_err(|e| anyhow!(e))
+	}
+
+	pub async fn submit_orders(&self, player: String, turn: u32, orders: Vec<Order>) -> anyhow::Result<()> {
+		self.request(|reply| Command::SubmitOrders { player, turn, orders, reply }).await?.map_err(|e| anyhow!(e))
+	}
+
+	pub async fn diplomacy(&self, player: String, action: DiplomaticAction) -> anyhow::Result<()> {
+		self.request(|reply| Command::Diplomacy { player, action, reply }).await?.map_err(|e| anyhow!(e))
+	}
+
+	pub async fn snapshot(&self) -> anyhow::Result<GameSnapshot> {
+		self.request(|reply| Command::Snapshot { reply }).await
+	}
+
+	/// Sent without waiting, as the lobby is locked while players leave. Commands are queued in order, so a player who rejoins afterwards
+	/// is given a country again.
+	pub fn release(&self, player: String) {
+		if let Err(mpsc::error::TrySendError::Full(command)) = self.commands.try_send(Command::Release { player }) {
+			let commands = self.commands.clone();
+			tokio::spawn(async move {
+				let _ = commands.send(command).await;
+			});
+		}
+	}
+}
+
+/// Everything needed to restore a game after the server restarts. Connections, sessions and the update history are not kept, as every
+/// client has to rejoin anyway.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct GameSnapshot {
+	territories: Territories,
+	regions: Regions,
+	/// The elevation of every deformed hex, which is applied to the starting height map
+	deformed: HashMap<HexCoord, u8>,
+	units: Units,
+	economy: Economy,
+	buildings: Buildings,
+	diplomacy: Diplomacy,
+	countries: BTreeMap<String, CountryId>,
+	views: BTreeMap<CountryId, View>,
+	tick: u64,
+	mode: GameMode,
+	turn: u32,
+	/// The time that was left in the current turn
+	turn_remaining: Option<Duration>,
+	players: BTreeSet<String>,
+	orders: BTreeMap<String, Vec<Order>>,
+	seed: u64,
+	battles: u64,
+	victory: VictoryConditions,
+	result: Option<GameResult>,
+}
+
+/// The authoritative state of a game, owned by the task that runs it
+pub struct Game {
+	territories: Territories,
+	/// The owners of hexes when the game started, which is all that countries know of the hexes they have not seen
+	starting_territories: Territories,
+	regions: Regions,
+	height_map: HeightMap,
+	units: Units,
+	economy: Economy,
+	buildings: Buildings,
+	diplomacy: Diplomacy,
+	/// The country controlled by each player. Players keep their country until they leave and their session can no longer be resumed.
+	countries: BTreeMap<String, CountryId>,
+	/// What each country controlled by a player can see and knows about
+	views: BTreeMap<CountryId, View>,
+	/// Messages that are sent to every client connected to the game
+	updates: broadcast::Sender<Update>,
+	/// The number of the most recent update
+	update_index: u64,
+	history: VecDeque<Update>,
+	/// Every hex that has been deformed, with its current elevation, so that new clients can be sent the changes
+	deformed: HashMap<HexCoord, u8>,
+	/// The last update seen by each disconnected session, by resume token
+	sessions: HashMap<u64, u64>,
+	/// The number of ticks since the game started
+	tick: u64,
+	/// Orders from real-time games waiting for the next tick
+	pending_orders: Vec<PendingOrder>,
+	mode: GameMode,
+	/// The current turn of a turn-based game, starting at 1
+	turn: u32,
+	/// When the current turn ends. The timer starts when the first player joins.
+	deadline: Option<Instant>,
+	/// The players controlling a country in a turn-based game. A turn ends early once all of them have submitted orders.
+	players: BTreeSet<String>,
+	/// The orders submitted for the current turn, by player
+	orders: BTreeMap<String, Vec<Order>>,
+	/// Chosen when the game is created. Each battle is resolved with this plus the number of earlier battles.
+	seed: u64,
+	battles: u64,
+	victory: VictoryConditions,
+	hex_counts: HexCounts,
+	/// Set once the game has ended, after which orders are rejected and nothing changes
+	result: Option<GameResult>,
+}
+
+impl Game {
+	pub fn new(map: StartingMap, mode: GameMode, victory: VictoryConditions) -> Self {
+		let (updates, _) = broadcast::channel(64);
+		Self {
+			starting_territories: map.territories.clone(),
+			territories: map.territories,
+			regions: map.regions,
+			height_map: map.height_map,
+			units: Units::default(),
+			economy: Economy::default(),
+			buildings: Buildings::default(),
+			diplomacy: Diplomacy::default(),
+			countries: BTreeMap::new(),
+			views: BTreeMap::new(),
+			updates,
+			update_index: 0,
+			history: VecDeque::new(),
+			deformed: HashMap::new(),
+			sessions: HashMap::new(),
+			tick: 0,
+			pending_orders: Vec::new(),
+			mode,
+			turn: 1,
+			deadline: None,
+			players: BTreeSet::new(),
+			orders: BTreeMap::new(),
+			seed: random_u64(),
+			battles: 0,
+			victory,
+			hex_counts: HexCounts::default(),
+			result: None,
+		}
+	}
+
+	/// Copies everything that should survive a restart
+	pub fn snapshot(&self) -> GameSnapshot {
+		GameSnapshot {
+			territories: self.territories.clone(),
+			regions: self.regions.clone(),
+			deformed: self.deformed.clone(),
+			units: self.units.clone(),
+			economy: self.economy.clone(),
+			buildings: self.buildings.clone(),
+			diplomacy: self.diplomacy.clone(),
+			countries: self.countries.clone(),
+			views: self.views.clone(),
+			tick: self.tick,
+			mode: self.mode,
+			turn: self.turn,
+			turn_remaining: self.deadline.map(|deadline| deadline.saturating_duration_since(Instant::now())),
+			players: self.players.clone(),
+			orders: self.orders.clone(),
+			seed: self.seed,
+			battles: self.battles,
+			victory: self.victory,
+			result: self.result.clone(),
+		}
+	}
+
+	/// Restores a saved game on the map it started with. The turn timer carries on from where it was.
+	pub fn restore(map: StartingMap, snapshot: GameSnapshot) -> Self {
+		let mut game = Self::new(map, snapshot.mode, snapshot.victory);
+		let changes = snapshot.deformed.iter().map(|(&hex, &elevation)| ElevationChange { hex, elevation }).collect::<Vec<_>>();
+		game.height_map.apply_changes(&changes);
+		Self {
+			territories: snapshot.territories,
+			regions: snapshot.regions,
+			deformed: snapshot.deformed,
+			units: snapshot.units,
+			economy: snapshot.economy,
+			buildings: snapshot.buildings,
+			diplomacy: snapshot.diplomacy,
+			countries: snapshot.countries,
+			views: snapshot.views,
+			tick: snapshot.tick,
+			turn: snapshot.turn,
+			deadline: snapshot.turn_remaining.map(|remaining| Instant::now() + remaining),
+			players: snapshot.players,
+			orders: snapshot.orders,
+			seed: snapshot.seed,
+			battles: snapshot.battles,
+			result: snapshot.result,
+			..game
+		}
+	}
+
+	/// Runs the game on its own task until every handle has been dropped
+	pub fn spawn(mut self) -> GameHandle {
+		let (commands, mut receiver) = mpsc::channel(256);
+		tokio::spawn(async move {
+			let mut interval = tokio::time::interval(TICK_INTERVAL);
+			interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+			loop {
+				tokio::select! {
+					_ = interval.tick() => self.tick(),
+					command = receiver.recv() => match command {
+						Some(command) => self.handle(command),
+						None => break,
+					},
+				}
+			}
+			info!("Game ended after {} ticks", self.tick);
+		});
+		GameHandle { commands }
+	}
+
+	/// Answers requests straight away, but queues player actions for the next tick
+	fn handle(&mut self, command: Command) {
+		match command {
+			Command::Subscribe { player, resume_token, reply } => {
+				let _ = reply.send(self.subscribe(player, resume_token));
+			}
+			Command::Suspend { resume_token, last_seen } => self.suspend_session(resume_token, last_seen),
+			Command::Act { reply, .. } if self.turn_length().is_some() => {
+				let _ = reply.send(Err("In turn-based games orders must be submitted for the turn".to_string()));
+			}
+			Command::Act { player, order, reply } => self.pending_orders.push((player, order, reply)),
+			Command::SubmitOrders { player, turn, orders, reply } => {
+				let _ = reply.send(self.submit_orders(player, turn, orders));
+			}
+			Command::Diplomacy { player, action, reply } => {
+				let _ = reply.send(self.diplomacy(&player, action));
+			}
+			Command::Snapshot { reply } => {
+				let _ = reply.send(self.snapshot());
+			}
+			Command::Release { player } => self.release(&player),
+		}
+	}
+
+	/// Applies the actions received since the last tick
+	fn tick(&mut self) {
+		self.tick += 1;
+		for (player, order, reply) in core::mem::take(&mut self.pending_orders) {
+			let _ = reply.send(self.carry_out(&player, &order));
+		}
+		if self.result.is_some() {
+			return;
+		}
+		let moved = self.units.advance(&self.height_map, &self.territories, &self.diplomacy);
+		if !moved.is_empty() {
+			self.send(Audience::Spectators, ServerMessage::UnitsChanged { units: moved, removed: Vec::new() });
+		}
+		self.economy.tick(self.buildings.iter(), &self.territories, &self.height_map);
+		if self.tick.is_multiple_of(REPORT_INTERVAL) {
+			let countries = self.countries.values().copied().collect::<Vec<_>>();
+			for country in countries {
+				self.send_to(country, ServerMessage::Economy(self.economy.report(country)));
+			}
+		}
+		let out_of_time = self.deadline.is_some_and(|deadline| Instant::now() >= deadline);
+		let everyone_submitted = !self.orders.is_empty() && self.players.iter().all(|player| self.orders.contains_key(player));
+		if out_of_time || everyone_submitted {
+			self.resolve_turn();
+		}
+		self.update_views();
+		self.check_victory();
+	}
+
+	/// Ends the game if any of its victory conditions have been met
+	fn check_victory(&mut self) {
+		let players = self.countries.values().copied().collect::<BTreeSet<_>>().into_iter().collect::<Vec<_>>();
+		let turns_played = self.turn_length().map(|_| self.turn - 1);
+		let Some(result) = self.victory.check(&mut self.hex_counts, &self.territories, &self.units, &players, turns_played) else {
+			return;
+		};
+		info!("Game over after {} ticks: {:?} won by {}", self.tick, result.winner, result.reason);
+		self.result = Some(result.clone());
+		self.deadline = None;
+		self.broadcast(ServerMessage::GameOver(result));
+	}
+
+	fn check_not_over(&self) -> Result<(), String> {
+		match self.result {
+			Some(_) => Err("The game is over".to_string()),
+			None => Ok(()),
+		}
+	}
+
+	/// Tells each player about the changes their country can see
+	fn update_views(&mut self) {
+		let mut views = core::mem::take(&mut self.views);
+		for (&country, view) in &mut views {
+			for message in view.update(&self.territories, &self.units, &self.buildings, &self.diplomacy, &self.height_map) {
+				self.send(Audience::Country(country), message);
+			}
+		}
+		self.views = views;
+	}
+
+	/// The length of a turn, or `None` if the game is not turn-based
+	fn turn_length(&self) -> Option<Duration> {
+		match self.mode {
+			GameMode::RealTime => None,
+			GameMode::TurnBased { turn_seconds } => Some(Duration::from_secs(turn_seconds as u64)),
+		}
+	}
+
+	/// Tells a client which turn it is and how long is left, if the turn timer has started
+	fn turn_started(&self) -> Option<ServerMessage> {
+		let deadline = self.deadline?;
+		Some(ServerMessage::TurnStarted {
+			turn: self.turn,
+			remaining_ms: deadline.saturating_duration_since(Instant::now()).as_millis() as u64,
+		})
+	}
+
+	fn submit_orders(&mut self, player: String, turn: u32, orders: Vec<Order>) -> Result<(), String> {
+		self.check_not_over()?;
+		if self.turn_length().is_none() {
+			return Err("Orders can only be submitted in turn-based games".to_string());
+		}
+		if turn != self.turn {
+			return Err(format!("The orders are for turn {turn} but it is turn {}", self.turn));
+		}
+		if orders.len() > MAX_ORDERS {
+			return Err(format!("At most {MAX_ORDERS} orders can be submitted each turn"));
+		}
+		self.country_of(&player)?;
+		// Orders are checked again when they are carried out, as earlier orders may change the map
+		for order in &orders {
+			match order {
+				Order::Deform(deformation) => deformation.validate(&self.height_map)?,
+				Order::Capture { .. } | Order::PlaceBuilding { .. } | Order::Move { .. } => {}
+			}
+		}
+		self.players.insert(player.clone());
+		self.orders.insert(player.clone(), orders);
+		self.broadcast(ServerMessage::OrdersSubmitted { turn, player });
+		Ok(())
+	}
+
+	/// Carries out every order submitted this turn and starts the next turn.
+	///
+	/// Players take turns to have one order carried out, in name order, so that no player's orders all go first.
+	fn resolve_turn(&mut self) {
+		let mut queues = core::mem::take(&mut self.orders).into_iter().map(|(player, orders)| (player, orders.into_iter())).collect::<Vec<_>>();
+		let mut results = Vec::new();
+		while !queues.is_empty() {
+			queues.retain_mut(|(player, orders)| {
+				let Some(order) = orders.next() else { return false };
+				let error = self.carry_out(player, &order).err();
+				results.push(OrderResult { player: player.clone(), order, error });
+				true
+			});
+		}
+		info!("Resolved turn {} with {} orders", self.turn, results.len());
+		// Players are only told the results of their own country's orders
+		let countries = self.views.keys().copied().collect::<Vec<_>>();
+		for country in countries {
+			let results = results.iter().filter(|result| self.countries.get(&result.player) == Some(&country)).cloned().collect();
+			self.send(Audience::Country(country), ServerMessage::TurnResolved { turn: self.turn, results });
+		}
+		self.send(Audience::Spectators, ServerMessage::TurnResolved { turn: self.turn, results });
+
+		self.turn += 1;
+		self.deadline = self.turn_length().map(|length| Instant::now() + length);
+		if let Some(turn_started) = self.turn_started() {
+			self.broadcast(turn_started);
+		}
+	}
+
+	/// Carries out a player's order. Players are sent the changes they can see on the next tick, see [`Self::update_views`].
+	fn carry_out(&mut self, player: &str, order: &Order) -> Result<(), String> {
+		self.check_not_over()?;
+		match *order {
+			Order::Deform(deformation) => self.deform(player, deformation),
+			Order::Capture { hex, whole_region } => self.capture(player, hex, whole_region),
+			Order::PlaceBuilding { hex, kind } => self.place_building(player, hex, kind),
+			Order::Move { unit, ref path } => self.move_unit(player, unit, path.clone()),
+		}
+	}
+
+	/// Gives a player the first country that nobody else controls. A country is given an army and a farm at its capital the first time it
+	/// is played, and is handed to later players as it was left.
+	fn assign_country(&mut self, player: &str) -> Option<CountryId> {
+		if let Some(&country) = self.countries.get(player) {
+			return Some(country);
+		}
+		let (country, capital) = (0..self.territories.countries().len())
+			.map(|index| CountryId(index as u8))
+			.filter(|country| *country != CountryId::SEA && !self.countries.values().any(|taken| taken == country))
+			.map(|country| (country, self.territories.countries()[country.0 as usize].capital))
+			.next()?;
+		self.countries.insert(player.to_string(), country);
+		if !self.economy.has_stockpile(country) {
+			let army = self.units.spawn(country, capital, STARTING_ARMY);
+			self.units_changed([army], Vec::new());
+			let farm = Building { kind: BuildingKind::Farm, level: 1 };
+			self.buildings.insert(capital, farm);
+			self.send(Audience::Spectators, ServerMessage::BuildingsChanged { buildings: vec![(capital, farm)] });
+			self.economy.add(country, STARTING_RESOURCES);
+		}
+		// The player is sent the whole view when they subscribe, so the changes are not needed
+		let mut view = View::new(country, self.starting_territories.clone());
+		view.update(&self.territories, &self.units, &self.buildings, &self.diplomacy, &self.height_map);
+		self.views.insert(country, view);
+		Some(country)
+	}
+
+	/// Frees a player's country for the next player to join. Its view is dropped, as a new player starts knowing only the starting map.
+	fn release(&mut self, player: &str) {
+		// The turn no longer waits for them
+		self.players.remove(player);
+		self.orders.remove(player);
+		let Some(country) = self.countries.remove(player) else { return };
+		self.views.remove(&country);
+		info!("{player} has given up {country:?}");
+	}
+
+	/// Releases the countries of every player that `keep` rejects, such as players who cannot resume their session after a restart
+	pub fn release_unless(&mut self, keep: impl Fn(&str) -> bool) {
+		let players = self.countries.keys().filter(|player| !keep(player)).cloned().collect::<Vec<_>>();
+		for player in players {
+			self.release(&player);
+		}
+	}
+
+	/// Subscribes to updates, replaying only the missed updates if the session is being resumed
+	fn subscribe(&mut self, player: String, resume_token: Option<u64>) -> Subscription {
+		let country = self.assign_country(&player);
+		if let (Some(length), Some(_)) = (self.turn_length(), country) {
+			self.players.insert(player);
+			self.deadline.get_or_insert_with(|| Instant::now() + length);
+		}
+		let missed = resume_token.and_then(|token| self.resume_session(token)).and_then(|last_seen| self.updates_since(last_seen, country));
+		let mut catch_up = match missed {
+			Some(missed) => {
+				info!("Resuming session, replaying {} updates", missed.len());
+				missed
+			}
+			None => self.full_state(country),
+		};
+		if let Some(country) = country {
+			catch_up.push(ServerMessage::CountryAssigned { country });
+			catch_up.push(ServerMessage::Economy(self.economy.report(country)));
+		}
+		// Sent even when resuming, as the time left in any replayed turn would be out of date
+		catch_up.extend(self.turn_started());
+		// Updates are only sent from this task, so the receiver starts exactly after `catch_up`
+		Subscription {
+			updates: self.updates.subscribe(),
+			catch_up,
+			last_update: self.update_index,
+			resume_token: resume_token.unwrap_or_else(random_u64),
+			country,
+		}
+	}
+
+	/// Sends a message to every client and remembers it for clients that reconnect
+	fn broadcast(&mut self, message: ServerMessage) {
+		self.send(Audience::Everyone, message);
+	}
+
+	/// Sends a message to some clients and remembers it for those that reconnect
+	fn send(&mut self, audience: Audience, message: ServerMessage) {
+		self.update_index += 1;
+		if self.history.len() == HISTORY_LEN {
+			self.history.pop_front();
+		}
+		self.history.push_back((self.update_index, audience, message.clone()));
+		let _ = self.updates.send((self.update_index, audience, message));
+	}
+
+	/// Sends a message to the player controlling a country. These messages are resent regularly, so they are not kept in the history and do not
+	/// use up an update number.
+	fn send_to(&mut self, country: CountryId, message: ServerMessage) {
+		let _ = self.updates.send((self.update_index, Audience::Country(country), message));
+	}
+
+	/// Applies a deformation to the height map and sends the resulting changes to every client. Countries can only deform their own land
+	/// and the land of countries they are at war with, and pay for it with ore.
+	fn deform(&mut self, player: &str, deformation: Deformation) -> Result<(), String> {
+		let country = self.country_of(player)?;
+		deformation.validate(&self.height_map)?;
+		let changes = deformation.changes(&self.height_map);
+		let protected = |owner: CountryId| owner != country && owner != CountryId::SEA && !self.diplomacy.at_war(country, owner);
+		if let Some(change) = changes.iter().find(|change| self.territories.hex_owner(change.hex).is_some_and(protected)) {
+			return Err(format!("Hex {:?} belongs to a country you are not at war with", change.hex));
+		}
+		if changes.is_empty() {
+			return Ok(());
+		}
+		let cost = deformation.cost();
+		if !self.economy.spend(country, cost) {
+			return Err(format!("Deforming costs {cost} but you only have {}", self.economy.stockpile(country)));
+		}
+		// Flooded hexes are no longer owned by anyone
+		let flooded = changes
+			.iter()
+			.filter(|change| HeightMap::is_sea(change.elevation))
+			.map(|change| change.hex.to_offset().as_uvec2())
+			.map(|offset| ((offset.x, offset.y), CountryId::SEA))
+			.collect::<Vec<_>>();
+		self.change_terrain(changes);
+		self.change_owners(flooded);
+		Ok(())
+	}
+
+	/// Attacks a hex, or the region containing it, with the player's strongest adjacent army. The hexes are captured if the battle is won.
+	fn capture(&mut self, player: &str, hex: HexCoord, whole_region: bool) -> Result<(), String> {
+		let attacker = self.country_of(player)?;
+		let capture = plan_capture(&self.territories, &self.regions, &self.units, &self.diplomacy, attacker, hex, whole_region).map_err(|e| e.to_string())?;
+		let result = capture.battle(&self.height_map).resolve(self.seed.wrapping_add(self.battles));
+		self.battles += 1;
+		let delta = capture.apply(&result, &mut self.territories, &mut self.regions, &mut self.units);
+		// Both sides hear about the battle, as does anyone who can see it
+		let witnesses = self
+			.views
+			.iter()
+			.filter(|(&country, view)| country == capture.attacker || country == capture.defender || view.can_see(capture.target))
+			.map(|(&country, _)| country)
+			.collect::<Vec<_>>();
+		for country in witnesses {
+			self.send(Audience::Country(country), ServerMessage::BattleResult(result));
+		}
+		self.send(Audience::Spectators, ServerMessage::BattleResult(result));
+		if let Some(delta) = delta.filter(|delta| !delta.changes.is_empty()) {
+			self.send(Audience::Spectators, ServerMessage::TerritoryDelta(delta));
+		}
+		let (survivors, removed) = core::iter::once(capture.army).chain(capture.defenders).partition::<Vec<_>, _>(|&id| self.units.get(id).is_some());
+		self.units_changed(survivors, removed);
+		Ok(())
+	}
+
+	/// Sets one of the player's units moving. It moves a hex at a time on later ticks.
+	fn move_unit(&mut self, player: &str, unit: UnitId, path: Vec<HexCoord>) -> Result<(), String> {
+		let country = self.country_of(player)?;
+		self.units
+			.order_move(country, unit, path, &self.height_map, &self.territories, &self.diplomacy)
+			.map_err(|e| e.to_string())?;
+		self.units_changed([unit], Vec::new());
+		Ok(())
+	}
+
+	/// Sends the current state of some units to spectators. Players are sent the units they can see on the next tick.
+	fn units_changed(&mut self, changed: impl IntoIterator<Item = UnitId>, removed: Vec<UnitId>) {
+		let units = changed.into_iter().filter_map(|id| self.units.get(id)).cloned().collect();
+		self.send(Audience::Spectators, ServerMessage::UnitsChanged { units, removed });
+	}
+
+	/// Places a building for the player's country and sends it to spectators
+	fn place_building(&mut self, player: &str, hex: HexCoord, kind: BuildingKind) -> Result<(), String> {
+		let country = self.country_of(player)?;
+		let building = self
+			.buildings
+			.place(&mut self.economy, &self.territories, &self.height_map, country, hex, kind)
+			.map_err(|e| e.to_string())?;
+		self.send(Audience::Spectators, ServerMessage::BuildingsChanged { buildings: vec![(hex, building)] });
+		Ok(())
+	}
+
+	/// Declares war, or makes or accepts a proposal. Relation changes are sent to everyone, and proposals only to the country they are made to.
+	fn diplomacy(&mut self, player: &str, action: DiplomaticAction) -> Result<(), String> {
+		self.check_not_over()?;
+		let country = self.country_of(player)?;
+		let (DiplomaticAction::DeclareWar(target) | DiplomaticAction::Propose(target, _) | DiplomaticAction::Accept(target, _)) = action;
+		if target == CountryId::SEA || self.territories.country(target).is_none() {
+			return Err(format!("There is no country {target:?}"));
+		}
+		let change = match action {
+			DiplomaticAction::DeclareWar(target) => self.diplomacy.declare_war(country, target),
+			DiplomaticAction::Propose(target, proposal) => {
+				self.diplomacy.propose(country, target, proposal).map_err(|e| e.to_string())?;
+				self.send(Audience::Country(target), ServerMessage::ProposalReceived { from: country, proposal });
+				return Ok(());
+			}
+			DiplomaticAction::Accept(from, proposal) => self.diplomacy.accept(country, from, proposal),
+		}
+		.map_err(|e| e.to_string())?;
+		info!("{:?} and {:?} now have a relation of {}", change.countries.0, change.countries.1, change.relation);
+		self.broadcast(ServerMessage::RelationChanged(change));
+		Ok(())
+	}
+
+	fn country_of(&self, player: &str) -> Result<CountryId, String> {
+		self.countries.get(player).copied().ok_or_else(|| "You do not control a country".to_string())
+	}
+
+	/// Changes the owners of hexes and sends the delta to spectators
+	fn change_owners(&mut self, changes: impl IntoIterator<Item = ((u32, u32), CountryId)>) {
+		let delta = self.territories.change_owners(changes);
+		if !delta.changes.is_empty() {
+			self.send(Audience::Spectators, ServerMessage::TerritoryDelta(delta));
+		}
+	}
+
+	/// Applies elevation changes and sends them to every client
+	fn change_terrain(&mut self, changes: Vec<ElevationChange>) {
+		self.height_map.apply_changes(&changes);
+		for view in self.views.values_mut() {
+			view.terrain_changed();
+		}
+		self.deformed.extend(changes.iter().map(|change| (change.hex, change.elevation)));
+		self.broadcast(ServerMessage::TerrainChanged { changes });
+	}
+
+	/// The messages needed to bring a new client up to date. Players are sent only what their country knows.
+	fn full_state(&self, country: Option<CountryId>) -> Vec<ServerMessage> {
+		let mut state = self.map_state(country);
+		state.extend(self.diplomacy.relations().map(ServerMessage::RelationChanged));
+		if let Some(country) = country {
+			let proposals = self.diplomacy.proposals_to(country);
+			state.extend(proposals.map(|(from, proposal)| ServerMessage::ProposalReceived { from, proposal }));
+		}
+		state.extend(self.result.clone().map(ServerMessage::GameOver));
+		state
+	}
+
+	/// The map, terrain, units and buildings that a client knows about
+	fn map_state(&self, country: Option<CountryId>) -> Vec<ServerMessage> {
+		let mut changes = self.deformed.iter().map(|(&hex, &elevation)| ElevationChange { hex, elevation }).collect::<Vec<_>>();
+		changes.sort_unstable_by_key(|change| (change.hex.r(), change.hex.q()));
+		let terrain = (!changes.is_empty()).then_some(ServerMessage::TerrainChanged { changes });
+		if let Some(view) = country.and_then(|country| self.views.get(&country)) {
+			let mut state = view.full_state();
+			state.splice(1..1, terrain);
+			return state;
+		}
+		let mut state = vec![ServerMessage::Map(self.territories.to_rle())];
+		state.extend(terrain);
+		let units = self.units.iter().cloned().collect::<Vec<_>>();
+		if !units.is_empty() {
+			state.push(ServerMessage::UnitsChanged { units, removed: Vec::new() });
+		}
+		let mut buildings = self.buildings.iter().collect::<Vec<_>>();
+		buildings.sort_unstable_by_key(|(hex, _)| (hex.r(), hex.q()));
+		if !buildings.is_empty() {
+			state.push(ServerMessage::BuildingsChanged { buildings });
+		}
+		state
+	}
+
+	/// The updates after `last_seen`, or `None` if some are no longer in the history
+	fn updates_since(&self, last_seen: u64, country: Option<CountryId>) -> Option<Vec<ServerMessage>> {
+		let oldest = self.history.front().map_or(self.update_index + 1, |(index, _, _)| *index);
+		if last_seen + 1 < oldest {
+			return None;
+		}
+		let missed = self.history.iter().filter(|(index, audience, _)| *index > last_seen && audience.includes(country));
+		Some(missed.map(|(_, _, message)| message.clone()).collect())
+	}
+
+	/// Remembers a disconnected session so that it can be resumed
+	fn suspend_session(&mut self, resume_token: u64, last_seen: u64) {
+		self.sessions.insert(resume_token, last_seen);
+	}
+
+	/// Removes a suspended session, returning the last update it saw
+	fn resume_session(&mut self, resume_token: u64) -> Option<u64> {
+		self.sessions.remove(&resume_token)
+	}
+}
+
+/// Generates a number that cannot be guessed by clients, for resume tokens and seeds
+pub fn random_u64() -> u64 {
+	let mut hasher = RandomState::new().build_hasher();
+	hasher.write_u128(std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_nanos());
+	hasher.finish()
+}
+
+#[test]
+fn missed_updates_are_replayed() {
+	let mut game = Game::new(StartingMap::default(), GameMode::RealTime, VictoryConditions::NONE);
+	assert_eq!(game.updates_since(0, None).map(|missed| missed.len()), Some(0));
+	for timestamp in 0..HISTORY_LEN + 10 {
+		game.broadcast(ServerMessage::Pong { timestamp: timestamp as f32 });
+	}
+	let last = game.update_index;
+	let missed = game.updates_since(last - 3, None).unwrap();
+	assert_eq!(missed.len(), 3);
+	assert!(matches!(missed[0], ServerMessage::Pong { timestamp } if timestamp == (HISTORY_LEN + 7) as f32));
+	assert_eq!(game.updates_since(last - HISTORY_LEN as u64, None).map(|missed| missed.len()), Some(HISTORY_LEN));
+	assert!(game.updates_since(last - HISTORY_LEN as u64 - 1, None).is_none());
+
+	game.suspend_session(7, last);
+	assert_eq!(game.resume_session(7), Some(last));
+	assert_eq!(game.resume_session(7), None);
+}
+
+#[tokio::test]
+async fn commands_reach_the_game() {
+	let game = Game::new(StartingMap::default(), GameMode::RealTime, VictoryConditions::NONE).spawn();
+	let subscription = game.subscribe("a".to_string(), None).await.unwrap();
+	assert!(matches!(subscription.catch_up[..], [ServerMessage::Map(_)]));
+	assert_eq!(subscription.last_update, 0);
+
+	// The map is empty, so there is no country to deform from
+	let deformation = Deformation::Flood { hex: HexCoord::new(0, 0) };
+	assert!(game.act("a".to_string(), Order::Deform(deformation)).await.is_err());
+
+	game.suspend(subscription.resume_token, 0).await;
+	let resumed = game.subscribe("a".to_string(), Some(subscription.resume_token)).await.unwrap();
+	assert!(resumed.catch_up.is_empty());
+	assert_eq!(resumed.resume_token, subscription.resume_token);
+}
+
+#[tokio::test]
+async fn turns_resolve_once_everyone_has_submitted() {
+	let mut game = Game::new(crate::load_starting_map().unwrap(), GameMode::TurnBased { turn_seconds: 60 }, VictoryConditions::NONE);
+	let subscription = game.subscribe("b".to_string(), None);
+	let mut updates = subscription.updates;
+	assert!(matches!(subscription.catch_up.last(), Some(ServerMessage::TurnStarted { turn: 1, .. })));
+	game.subscribe("a".to_string(), None);
+
+	let hex = game.territories.countries()[game.countries["a"].0 as usize].capital;
+	let flood = Order::Deform(Deformation::Flood { hex });
+	let crater = Order::Deform(Deformation::Crater { centre: hex, radius: 1, depth: 10 });
+	assert!(game.submit_orders("a".to_string(), 2, vec![flood.clone()]).is_err());
+	game.submit_orders("b".to_string(), 1, vec![crater.clone(), crater.clone()]).unwrap();
+	game.tick();
+	assert_eq!(game.turn, 1, "a has not submitted yet");
+	game.submit_orders("a".to_string(), 1, vec![flood.clone()]).unwrap();
+	game.tick();
+	assert_eq!(game.turn, 2);
+
+	let resolved = std::iter::from_fn(|| updates.try_recv().ok())
+		.filter_map(|(_, audience, message)| match message {
+			ServerMessage::TurnResolved { turn: 1, results } => Some((audience, results)),
+			_ => None,
+		})
+		.collect::<Vec<_>>();
+	let results = &resolved.iter().find(|(audience, _)| *audience == Audience::Spectators).unwrap().1;
+	// Players take turns to have their orders carried out
+	let order = results.iter().map(|result| (result.player.as_str(), &result.order)).collect::<Vec<_>>();
+	assert_eq!(order, [("a", &flood), ("b", &crater), ("b", &crater)]);
+	assert_eq!(results[0].error, None);
+	// Players are only told about their own orders
+	let (_, results) = resolved.iter().find(|(audience, _)| audience.includes(Some(game.countries["b"]))).unwrap();
+	assert!(results.iter().all(|result| result.player == "b") && results.len() == 2);
+	assert!(game.deformed.contains_key(&hex));
+
+	// Running out of time ends the turn without everyone's orders
+	game.deadline = Some(Instant::now());
+	game.tick();
+	assert_eq!(game.turn, 3);
+	assert!(game.submit_orders("a".to_string(), 2, Vec::new()).is_err());
+
+	// Only players with a country submit orders, and players who have left are not waited for
+	assert!(game.submit_orders("spectator".to_string(), 3, Vec::new()).is_err());
+	game.submit_orders("a".to_string(), 3, Vec::new()).unwrap();
+	game.release("b");
+	game.tick();
+	assert_eq!(game.turn, 4);
+}
+
+#[test]
+fn players_capture_with_their_army() {
+	let mut game = Game::new(crate::load_starting_map().unwrap(), GameMode::RealTime, VictoryConditions::NONE);
+	let subscription = game.subscribe("a".to_string(), None);
+	let Some(country) = subscription.country else { panic!("a should be given a country") };
+	assert!(subscription
+		.catch_up
+		.iter()
+		.any(|message| matches!(message, ServerMessage::CountryAssigned { country: assigned } if *assigned == country)));
+	game.subscribe("b".to_string(), None);
+	assert_ne!(game.countries["b"], country, "Players get different countries");
+	// Rejoining keeps the same country
+	game.subscribe("a".to_string(), None);
+	assert_eq!(game.countries["a"], country);
+
+	// Move a's army next to a hex in Congo
+	let (target, next_to_target) = (HexCoord::new(100, 100), HexCoord::new(101, 100));
+	assert!(![Some(country), Some(game.countries["b"])].contains(&game.territories.hex_owner(target)));
+	let army = game.units.owned_by(country).next().unwrap().id;
+	game.units.get_mut(army).unwrap().position = next_to_target;
+	assert!(game.carry_out("b", &Order::Capture { hex: target, whole_region: false }).is_err(), "b has no army next to the hex");
+	assert!(game.carry_out("a", &Order::Capture { hex: target, whole_region: false }).unwrap_err().contains("not at war"));
+	let congo = game.territories.hex_owner(target).unwrap();
+	game.diplomacy("a", DiplomaticAction::DeclareWar(congo)).unwrap();
+	game.carry_out("a", &Order::Capture { hex: target, whole_region: false }).unwrap();
+	assert_eq!(game.territories.hex_owner(target), Some(country));
+	// Spectators are sent the battle, the new owner and the army that moved
+	let last = game.history.iter().rev().take(3).map(|(_, _, message)| message).collect::<Vec<_>>();
+	assert!(
+		matches!(last[..], [ServerMessage::UnitsChanged { units, .. }, ServerMessage::TerritoryDelta(_), ServerMessage::BattleResult(result)]
+		if units[0].position == target && result.attacker_won && result.battle.attacker == country)
+	);
+	// The attacker is told about the battle straight away and the changes on the next tick
+	let sent_to_attacker = |game: &Game| {
+		game.history
+			.iter()
+			.filter(|(_, audience, _)| *audience == Audience::Country(country))
+			.map(|(_, _, message)| message.clone())
+			.collect::<Vec<_>>()
+	};
+	assert!(matches!(sent_to_attacker(&game).last(), Some(ServerMessage::BattleResult(_))));
+	game.tick();
+	assert!(sent_to_attacker(&game)
+		.iter()
+		.any(|message| matches!(message, ServerMessage::TerritoryDelta(delta) if delta.changes.len() == 1)));
+}
+
+#[test]
+fn released_countries_go_to_the_next_player() {
+	let mut game = Game::new(crate::load_starting_map().unwrap(), GameMode::RealTime, VictoryConditions::NONE);
+	let first = game.subscribe("Guest 1".to_string(), None).country.unwrap();
+	let second = game.subscribe("Guest 2".to_string(), None).country.unwrap();
+	let units = game.units.iter().count();
+	game.release("Guest 1");
+	assert!(game.country_of("Guest 1").is_err());
+	assert!(!game.views.contains_key(&first));
+
+	assert_eq!(game.subscribe("Guest 3".to_string(), None).country, Some(first));
+	assert_eq!(game.units.iter().count(), units, "The country is handed over with its army rather than given a new one");
+	assert_eq!(game.economy.stockpile(first), STARTING_RESOURCES);
+	// After a restart, only players who can resume their session keep their country
+	game.release_unless(|player| player == "Guest 3");
+	assert_eq!(game.countries.values().collect::<Vec<_>>(), [&first]);
+	assert_eq!(game.subscribe("Guest 4".to_string(), None).country, Some(second));
+}
+
+#[test]
+fn economy_reports_only_reach_their_country() {
+	let mut game = Game::new(crate::load_starting_map().unwrap(), GameMode::RealTime, VictoryConditions::NONE);
+	let mut updates = game.subscribe("a".to_string(), None).updates;
+	let a = game.countries["a"];
+	let b = game.subscribe("b".to_string(), None).country;
+	for _ in 0..REPORT_INTERVAL {
+		game.tick();
+	}
+
+	let capital = game.territories.countries()[a.0 as usize].capital;
+	let farm = geonext_shared::economy::production(Building { kind: BuildingKind::Farm, level: 1 }, capital, &game.height_map);
+	assert!(farm.food > 0);
+	let reports = std::iter::from_fn(|| updates.try_recv().ok())
+		.filter_map(|(_, audience, message)| match message {
+			ServerMessage::Economy(report) => Some((audience, report)),
+			_ => None,
+		})
+		.collect::<Vec<_>>();
+	let (audience, report) = reports.iter().find(|(audience, _)| audience.includes(Some(a))).unwrap();
+	assert!(!audience.includes(b));
+	assert_eq!(report.production, farm);
+	assert_eq!(report.stockpile, STARTING_RESOURCES + Resources::new(0, farm.food * REPORT_INTERVAL as u32, 0));
+	// Reports are not replayed to reconnecting clients
+	assert!(game.history.iter().all(|(_, _, message)| !matches!(message, ServerMessage::Economy(_))));
+}
+
+#[test]
+fn buildings_are_placed_and_sent_to_new_clients() {
+	let mut game = Game::new(crate::load_starting_map().unwrap(), GameMode::RealTime, VictoryConditions::NONE);
+	game.subscribe("a".to_string(), None);
+	let country = game.countries["a"];
+	let capital = game.territories.countries()[country.0 as usize].capital;
+	assert_eq!(game.buildings.get(capital).map(|building| building.kind), Some(BuildingKind::Farm), "Players start with a farm");
+
+	let place = Order::PlaceBuilding {
+		hex: capital,
+		kind: BuildingKind::Mine,
+	};
+	assert!(game.carry_out("a", &place).unwrap_err().contains("already has a building"));
+	assert!(game.carry_out("nobody", &place).is_err());
+	let hex = capital
+		.neighbours()
+		.find(|&hex| game.territories.hex_owner(hex) == Some(country) && game.buildings.get(hex).is_none())
+		.unwrap();
+	let kind = BuildingKind::ALL
+		.into_iter()
+		.find(|kind| game.buildings.check_placement(&game.economy, &game.territories, &game.height_map, country, hex, *kind).is_ok())
+		.expect("Some building should suit the hex");
+	game.carry_out("a", &Order::PlaceBuilding { hex, kind }).unwrap();
+	assert_eq!(game.economy.stockpile(country).checked_sub(STARTING_RESOURCES), None, "The building was paid for");
+
+	game.tick();
+
+	let buildings_sent = |state: Vec<ServerMessage>| {
+		state
+			.into_iter()
+			.filter_map(|message| match message {
+				ServerMessage::BuildingsChanged { buildings } => Some(buildings),
+				_ => None,
+			})
+			.flatten()
+			.collect::<Vec<_>>()
+	};
+	assert!(buildings_sent(game.subscribe("a".to_string(), None).catch_up).contains(&(hex, Building { kind, level: 1 })));
+	// Other players cannot see the building from the other side of the world
+	let b = game.subscribe("b".to_string(), None);
+	assert!(!buildings_sent(b.catch_up).iter().any(|&(sent, _)| sent == hex));
+	// Clients without a country see everything
+	assert!(buildings_sent(game.full_state(None)).contains(&(hex, Building { kind, level: 1 })));
+}
+
+#[test]
+fn units_move_over_ticks() {
+	let mut game = Game::new(crate::load_starting_map().unwrap(), GameMode::RealTime, VictoryConditions::NONE);
+	let mut updates = game.subscribe("a".to_string(), None).updates;
+	let country = game.countries["a"];
+	let army = game.units.owned_by(country).next().unwrap().clone();
+	let next = army.position.neighbours().find(|&hex| game.territories.hex_owner(hex) == Some(country)).unwrap();
+
+	assert!(game
+		.carry_out(
+			"a",
+			&Order::Move {
+				unit: army.id,
+				path: vec![next + HexCoord::RIGHT * 3]
+			}
+		)
+		.is_err());
+	game.carry_out("a", &Order::Move { unit: army.id, path: vec![next] }).unwrap();
+	assert_eq!(game.units.get(army.id).unwrap().position, army.position, "Units move on later ticks");
+	for _ in 0..100 {
+		game.tick();
+	}
+	assert_eq!(game.units.get(army.id).unwrap().position, next);
+	let positions = std::iter::from_fn(|| updates.try_recv().ok())
+		.filter(|(_, audience, _)| audience.includes(Some(country)))
+		.filter_map(|(_, _, message)| match message {
+			ServerMessage::UnitsChanged { units, .. } => Some(units),
+			_ => None,
+		})
+		.flatten()
+		.map(|unit| unit.position)
+		.collect::<Vec<_>>();
+	assert_eq!(positions, [army.position, next]);
+}
+
+#[test]
+fn relations_change_by_agreement() {
+	use geonext_shared::diplomacy::{Proposal, Relation, RelationChange};
+	let mut game = Game::new(crate::load_starting_map().unwrap(), GameMode::TurnBased { turn_seconds: 60 }, VictoryConditions::NONE);
+	game.subscribe("a".to_string(), None);
+	game.subscribe("b".to_string(), None);
+	let (a, b) = (game.countries["a"], game.countries["b"]);
+	assert!(game.diplomacy("a", DiplomaticAction::DeclareWar(CountryId::SEA)).is_err());
+	assert!(game.diplomacy("nobody", DiplomaticAction::DeclareWar(b)).is_err());
+
+	game.diplomacy("a", DiplomaticAction::Propose(b, Proposal::Alliance)).unwrap();
+	assert!(matches!(game.history.back(), Some((_, Audience::Country(to), ServerMessage::ProposalReceived { from, proposal: Proposal::Alliance })) if *to == b && *from == a));
+	assert!(game.diplomacy("a", DiplomaticAction::Accept(b, Proposal::Alliance)).is_err(), "Only b can accept");
+	assert!(game
+		.subscribe("b".to_string(), None)
+		.catch_up
+		.iter()
+		.any(|message| matches!(message, ServerMessage::ProposalReceived { from, .. } if *from == a)));
+
+	game.diplomacy("b", DiplomaticAction::Accept(a, Proposal::Alliance)).unwrap();
+	let alliance = RelationChange {
+		countries: (a.min(b), a.max(b)),
+		relation: Relation::Alliance,
+	};
+	assert!(matches!(game.history.back(), Some((_, Audience::Everyone, ServerMessage::RelationChanged(change))) if *change == alliance));
+	// New clients are told every relation, even in the middle of a turn
+	assert!(game
+		.full_state(None)
+		.iter()
+		.any(|message| matches!(message, ServerMessage::RelationChanged(change) if *change == alliance)));
+
+	// Allies share what they can see
+	game.tick();
+	let capital = game.territories.countries()[b.0 as usize].capital;
+	assert!(game.views[&a].can_see(capital));
+	game.diplomacy("b", DiplomaticAction::DeclareWar(a)).unwrap();
+	game.tick();
+	assert!(!game.views[&a].can_see(capital));
+}
+
+#[test]
+fn deforming_needs_a_country_at_war() {
+	let mut game = Game::new(crate::load_starting_map().unwrap(), GameMode::RealTime, VictoryConditions::NONE);
+	let country = game.subscribe("a".to_string(), None).country.unwrap();
+	let other = CountryId(if country.0 == 0 { 1 } else { 0 });
+	let (own, foreign) = (game.territories.countries()[country.0 as usize].capital, game.territories.countries()[other.0 as usize].capital);
+	let flood = |hex| Order::Deform(Deformation::Flood { hex });
+	assert!(game.carry_out("spectator", &flood(own)).is_err(), "Spectators cannot deform");
+	assert!(game.carry_out("a", &flood(foreign)).is_err(), "Countries at peace cannot deform each other");
+
+	game.diplomacy("a", DiplomaticAction::DeclareWar(other)).unwrap();
+	game.carry_out("a", &flood(foreign)).unwrap();
+	assert_eq!(game.territories.hex_owner(foreign), Some(CountryId::SEA));
+	assert_eq!(game.economy.stockpile(country), STARTING_RESOURCES.checked_sub(Deformation::Flood { hex: own }.cost()).unwrap());
+	game.carry_out("a", &flood(own)).unwrap();
+	let neighbour = own
+		.neighbours()
+		.find(|&hex| game.territories.hex_owner(hex) == Some(country) && game.height_map.elevation(hex).is_some_and(|elevation| !HeightMap::is_sea(elevation)));
+	assert!(game.carry_out("a", &flood(neighbour.unwrap())).is_err(), "The ore has run out");
+}
+
+#[test]
+fn games_end_when_a_condition_is_met() {
+	let victory = VictoryConditions {
+		turn_limit: Some(1),
+		..VictoryConditions::NONE
+	};
+	let mut game = Game::new(crate::load_starting_map().unwrap(), GameMode::TurnBased { turn_seconds: 60 }, victory);
+	game.subscribe("a".to_string(), None);
+	game.tick();
+	assert!(game.result.is_none());
+	game.submit_orders("a".to_string(), 1, Vec::new()).unwrap();
+	game.tick();
+
+	let country = game.countries["a"];
+	let Some((_, Audience::Everyone, ServerMessage::GameOver(result))) = game.history.back() else {
+		panic!("Everyone should be told that the game is over");
+	};
+	assert_eq!((result.reason, result.winner), (geonext_shared::victory::VictoryReason::TurnLimit, Some(country)));
+	assert_eq!(result.standings.iter().map(|standing| standing.country).collect::<Vec<_>>(), [country]);
+	assert!(game.submit_orders("a".to_string(), 2, Vec::new()).is_err());
+	assert!(game.carry_out("a", &Order::Deform(Deformation::Flood { hex: HexCoord::new(100, 100) })).is_err());
+	// Players who join afterwards see the result
+	assert!(matches!(game.full_state(None).last(), Some(ServerMessage::GameOver(_))));
+}
+
+#[test]
+fn games_are_restored_from_snapshots() {
+	let mut game = Game::new(crate::load_starting_map().unwrap(), GameMode::TurnBased { turn_seconds: 60 }, VictoryConditions::default());
+	game.subscribe("a".to_string(), None);
+	let hex = HexCoord::new(100, 100);
+	let owner = game.territories.hex_owner(hex).unwrap();
+	game.diplomacy("a", DiplomaticAction::DeclareWar(owner)).unwrap();
+	game.carry_out("a", &Order::Deform(Deformation::Flood { hex })).unwrap();
+	game.submit_orders("a".to_string(), 1, Vec::new()).unwrap();
+	game.tick();
+
+	let saved = bincode::serialize(&game.snapshot()).unwrap();
+
+	let mut restored = Game::restore(crate::load_starting_map().unwrap(), bincode::deserialize(&saved).unwrap());
+	assert_eq!(restored.height_map.elevation(hex), game.height_map.elevation(hex));
+	assert_eq!(restored.turn, game.turn);
+	assert!(restored.deadline.is_some());
+	assert_eq!(restored.subscribe("a".to_string(), None).country, Some(game.countries["a"]));
+	// Nothing changes by being saved again, apart from the time left in the turn and the territory sequence number, which is not saved
+	let (resaved, saved) = (restored.snapshot(), bincode::deserialize::<GameSnapshot>(&saved).unwrap());
+	assert_eq!(bincode::serialize(&resaved.territories).unwrap(), bincode::serialize(&saved.territories).unwrap());
+	let unsaved = |snapshot| GameSnapshot {
+		territories: Territories::default(),
+		turn_remaining: None,
+		..snapshot
+	};
+	assert_eq!(unsaved(resaved), unsaved(saved));
+}
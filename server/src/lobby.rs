@@ -0,0 +1,439 @@
+use crate::game::{Game, GameHandle, GameSnapshot, StartingMap};
+use geonext_shared::lobby::{GameId, GameMode, GameSummary};
+use geonext_shared::victory::VictoryConditions;
+use geonext_shared::ServerMessage;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::time::Instant;
+
+/// The most players that can be in one game
+pub const MAX_CAPACITY: u8 = 16;
+/// The most games that can exist at once
+pub const MAX_GAMES: usize = 32;
+const MAX_NAME_LEN: usize = 32;
+/// The allowed lengths of a turn in seconds. Long turns allow games to be played over days.
+pub const TURN_SECONDS: core::ops::RangeInclusive<u32> = 10..=7 * 24 * 60 * 60;
+/// How long a disconnected session can be resumed, during which it keeps its game open and its player's country
+pub const RESUME_GRACE: Duration = Duration::from_secs(10 * 60);
+
+/// Identifies a websocket connection, which can be in at most one game
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ConnectionId(pub u64);
+
+/// The reasons a lobby action can fail
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LobbyError {
+	UnknownGame(GameId),
+	Full {
+		game: GameId,
+		capacity: u8,
+	},
+	TooManyGames,
+	InvalidName,
+	InvalidCapacity(u8),
+	InvalidTurnLength(u32),
+	/// Domination must need between 1 and 100 percent of the land
+	InvalidDomination(u8),
+}
+
+impl core::fmt::Display for LobbyError {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		match self {
+			Self::UnknownGame(game) => write!(f, "Game {} does not exist", game.0),
+			Self::Full { game, capacity } => write!(f, "Game {} already has {capacity} players", game.0),
+			Self::TooManyGames => write!(f, "There cannot be more than {MAX_GAMES} games"),
+			Self::InvalidName => write!(f, "Game names must be between 1 and {MAX_NAME_LEN} characters"),
+			Self::InvalidCapacity(capacity) => write!(f, "Games must allow between 1 and {MAX_CAPACITY} players, not {capacity}"),
+			Self::InvalidTurnLength(seconds) => write!(f, "Turns must last between {} and {} seconds, not {seconds}", TURN_SECONDS.start(), TURN_SECONDS.end()),
+			Self::InvalidDomination(percent) => write!(f, "Domination must need between 1 and 100 percent of the land, not {percent}"),
+		}
+	}
+}
+
+impl std::error::Error for LobbyError {}
+
+/// How a game was set up when it was created
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct GameSettings {
+	pub name: String,
+	pub capacity: u8,
+	pub mode: GameMode,
+	pub victory: VictoryConditions,
+	/// Permanent games stay open when the last player leaves
+	pub permanent: bool,
+}
+
+/// A game that is open, with what is needed to save it
+pub struct OpenGame {
+	pub id: GameId,
+	pub settings: GameSettings,
+	/// The disconnected sessions that can be resumed, as (resume token, player)
+	pub sessions: Vec<(u64, String)>,
+	pub game: GameHandle,
+}
+
+struct LobbyGame {
+	settings: GameSettings,
+	players: Vec<(ConnectionId, String)>,
+	game: GameHandle,
+}
+
+impl LobbyGame {
+	fn summary(&self, id: GameId) -> GameSummary {
+		GameSummary {
+			id,
+			name: self.settings.name.clone(),
+			players: self.players.iter().map(|(_, name)| name.clone()).collect(),
+			capacity: self.settings.capacity,
+			mode: self.settings.mode,
+			victory: self.settings.victory,
+		}
+	}
+}
+
+/// A disconnected session that can be resumed by reconnecting
+struct SuspendedSession {
+	game: GameId,
+	player: String,
+	/// After this the session can no longer be resumed
+	expires: Instant,
+}
+
+/// Every game on the server and the players in each
+pub struct Lobby {
+	games: BTreeMap<GameId, LobbyGame>,
+	next_game: u32,
+	next_connection: u64,
+	/// Each disconnected session, by resume token
+	suspended: HashMap<u64, SuspendedSession>,
+	/// The map that new games start with
+	map: StartingMap,
+	/// Changes to the lobby, sent to every connected client
+	pub announcements: broadcast::Sender<ServerMessage>,
+}
+
+impl Lobby {
+	pub fn new(map: StartingMap) -> Self {
+		let (announcements, _) = broadcast::channel(64);
+		Self {
+			games: BTreeMap::new(),
+			next_game: 0,
+			next_connection: 0,
+			suspended: HashMap::new(),
+			map,
+			announcements,
+		}
+	}
+
+	/// A new id for a websocket connection
+	pub fn connect(&mut self) -> ConnectionId {
+		self.next_connection += 1;
+		ConnectionId(self.next_connection)
+	}
+
+	pub fn list(&self) -> Vec<GameSummary> {
+		self.games.iter().map(|(&id, game)| game.summary(id)).collect()
+	}
+
+	fn announce(&self, message: ServerMessage) {
+		let _ = self.announcements.send(message);
+	}
+
+	/// Creates a game on the starting map. Games that are not permanent close once every player has left.
+	pub fn create_game(&mut self, name: String, capacity: u8, mode: GameMode, victory: VictoryConditions, permanent: bool) -> Result<GameId, LobbyError> {
+		let name = name.trim().to_string();
+		if name.is_empty() || name.chars().count() > MAX_NAME_LEN {
+			return Err(LobbyError::InvalidName);
+		}
+		if !(1..=MAX_CAPACITY).contains(&capacity) {
+			return Err(LobbyError::InvalidCapacity(capacity));
+		}
+		if let GameMode::TurnBased { turn_seconds } = mode {
+			if !TURN_SECONDS.contains(&turn_seconds) {
+				return Err(LobbyError::InvalidTurnLength(turn_seconds));
+			}
+		}
+		if let Some(percent) = victory.domination_percent.filter(|percent| !(1..=100).contains(percent)) {
+			return Err(LobbyError::InvalidDomination(percent));
+		}
+		if self.games.len() >= MAX_GAMES {
+			return Err(LobbyError::TooManyGames);
+		}
+		let id = GameId(self.next_game);
+		self.next_game += 1;
+		let game = LobbyGame {
+			settings: GameSettings {
+				name,
+				capacity,
+				mode,
+				victory,
+				permanent,
+			},
+			players: Vec::new(),
+			game: Game::new(self.map.clone(), mode, victory).spawn(),
+		};
+		self.announce(ServerMessage::GameUpdated(game.summary(id)));
+		self.games.insert(id, game);
+		Ok(id)
+	}
+
+	/// Stops new games from being given an id, such as the id of a save that could not be loaded
+	pub fn reserve(&mut self, id: GameId) {
+		self.next_game = self.next_game.max(id.0 + 1);
+	}
+
+	/// Reopens a saved game under its old id, replacing any game that has it. Its sessions can be resumed for another [`RESUME_GRACE`].
+	pub fn restore(&mut self, id: GameId, settings: GameSettings, sessions: Vec<(u64, String)>, snapshot: GameSnapshot) {
+		self.reserve(id);
+		let mut game = Game::restore(self.map.clone(), snapshot);
+		// Players who were connected when the game was saved have no session to resume
+		game.release_unless(|player| sessions.iter().any(|(_, other)| other == player));
+		for (resume_token, player) in sessions {
+			self.suspend(resume_token, id, player);
+		}
+		let game = LobbyGame {
+			settings,
+			players: Vec::new(),
+			game: game.spawn(),
+		};
+		self.announce(ServerMessage::GameUpdated(game.summary(id)));
+		self.games.insert(id, game);
+	}
+
+	/// Every open game, so that they can be saved
+	pub fn games(&self) -> Vec<OpenGame> {
+		self.games
+			.iter()
+			.map(|(&id, game)| OpenGame {
+				id,
+				settings: game.settings.clone(),
+				sessions: self
+					.suspended
+					.iter()
+					.filter(|(_, session)| session.game == id)
+					.map(|(&token, session)| (token, session.player.clone()))
+					.collect(),
+				game: game.game.clone(),
+			})
+			.collect()
+	}
+
+	/// Adds a player to a game, returning the game so that the connection can subscribe to it
+	pub fn join(&mut self, id: GameId, connection: ConnectionId, player: String) -> Result<GameHandle, LobbyError> {
+		let game = self.games.get_mut(&id).ok_or(LobbyError::UnknownGame(id))?;
+		if !game.players.iter().any(|&(other, _)| other == connection) {
+			if game.players.len() >= game.settings.capacity as usize {
+				return Err(LobbyError::Full {
+					game: id,
+					capacity: game.settings.capacity,
+				});
+			}
+			game.players.push((connection, player));
+		}
+		let (summary, game) = (game.summary(id), game.game.clone());
+		self.announce(ServerMessage::GameUpdated(summary));
+		Ok(game)
+	}
+
+	/// Removes a player from a game, closing the game if it is now abandoned. The player's country is released unless their session was
+	/// suspended first.
+	pub fn leave(&mut self, id: GameId, connection: ConnectionId) {
+		let Some(game) = self.games.get_mut(&id) else { return };
+		let left = game.players.iter().position(|&(other, _)| other == connection).map(|index| game.players.remove(index).1);
+		let summary = game.summary(id);
+		if let Some(player) = left {
+			self.release_if_gone(id, &player);
+		}
+		if !self.close_if_abandoned(id) {
+			self.announce(ServerMessage::GameUpdated(summary));
+		}
+	}
+
+	/// Closes a game that is not permanent once it has no players and no suspended sessions. Closing drops the handle, which ends the
+	/// game's task once no connection holds it.
+	fn close_if_abandoned(&mut self, id: GameId) -> bool {
+		let Some(game) = self.games.get(&id) else { return false };
+		if !game.players.is_empty() || game.settings.permanent || self.suspended.values().any(|session| session.game == id) {
+			return false;
+		}
+		self.games.remove(&id);
+		self.announce(ServerMessage::GameClosed { game: id });
+		true
+	}
+
+	/// Releases a player's country once they have no connection to the game and no session that can be resumed
+	pub fn release_if_gone(&self, id: GameId, player: &str) {
+		let Some(game) = self.games.get(&id) else { return };
+		let connected = game.players.iter().any(|(_, other)| other == player);
+		if !connected && !self.suspended.values().any(|session| session.game == id && session.player == player) {
+			game.game.release(player.to_string());
+		}
+	}
+
+	/// Remembers the game and player of a disconnected session so that it can be resumed for [`RESUME_GRACE`]
+	pub fn suspend(&mut self, resume_token: u64, id: GameId, player: String) {
+		let expires = Instant::now() + RESUME_GRACE;
+		self.suspended.insert(resume_token, SuspendedSession { game: id, player, expires });
+	}
+
+	/// Removes a suspended session, returning its game and player if the game still exists
+	pub fn resume(&mut self, resume_token: u64) -> Option<(GameId, String)> {
+		self.suspended
+			.remove(&resume_token)
+			.filter(|session| self.games.contains_key(&session.game))
+			.map(|session| (session.game, session.player))
+	}
+
+	/// Forgets sessions once their grace period is over, releasing their players' countries and closing the games they were keeping open
+	pub fn expire(&mut self, now: Instant) {
+		let (expired, suspended): (HashMap<_, _>, _) = core::mem::take(&mut self.suspended).into_iter().partition(|(_, session)| session.expires <= now);
+		self.suspended = suspended;
+		for session in expired.into_values() {
+			self.release_if_gone(session.game, &session.player);
+		}
+		let ids = self.games.keys().copied().collect::<Vec<_>>();
+		for id in ids {
+			self.close_if_abandoned(id);
+		}
+	}
+}
+
+#[tokio::test]
+async fn lobby_capacity() {
+	let mut lobby = Lobby::new(StartingMap::default());
+	let mut announcements = lobby.announcements.subscribe();
+	let world = lobby.create_game("World".to_string(), 2, GameMode::RealTime, VictoryConditions::default(), true).unwrap();
+	let duel = lobby.create_game(" Duel ".to_string(), 1, GameMode::RealTime, VictoryConditions::default(), false).unwrap();
+	assert_eq!(lobby.list().iter().map(|game| game.name.as_str()).collect::<Vec<_>>(), ["World", "Duel"]);
+
+	let (a, b, c) = (lobby.connect(), lobby.connect(), lobby.connect());
+	lobby.join(world, a, "a".to_string()).unwrap();
+	lobby.join(world, b, "b".to_string()).unwrap();
+	// Joining twice does not take another place
+	lobby.join(world, b, "b".to_string()).unwrap();
+	assert_eq!(lobby.join(world, c, "c".to_string()).err(), Some(LobbyError::Full { game: world, capacity: 2 }));
+	assert!(lobby.list()[0].is_full());
+
+	lobby.join(duel, c, "c".to_string()).unwrap();
+	lobby.leave(world, a);
+	lobby.leave(world, b);
+	lobby.leave(duel, c);
+	// Only the permanent game is left
+	assert_eq!(lobby.list().len(), 1);
+	assert!(lobby.list()[0].players.is_empty());
+	assert_eq!(lobby.join(duel, c, "c".to_string()).err(), Some(LobbyError::UnknownGame(duel)));
+
+	let mut closed = false;
+	while let Ok(message) = announcements.try_recv() {
+		closed |= matches!(message, ServerMessage::GameClosed { game } if game == duel);
+	}
+	assert!(closed);
+}
+
+#[tokio::test]
+async fn invalid_games() {
+	let mut lobby = Lobby::new(StartingMap::default());
+	assert_eq!(
+		lobby.create_game("  ".to_string(), 2, GameMode::RealTime, VictoryConditions::default(), false),
+		Err(LobbyError::InvalidName)
+	);
+	assert_eq!(
+		lobby.create_game("a".repeat(MAX_NAME_LEN + 1), 2, GameMode::RealTime, VictoryConditions::default(), false),
+		Err(LobbyError::InvalidName)
+	);
+	assert_eq!(
+		lobby.create_game("Game".to_string(), 0, GameMode::RealTime, VictoryConditions::default(), false),
+		Err(LobbyError::InvalidCapacity(0))
+	);
+	assert_eq!(
+		lobby.create_game("Game".to_string(), MAX_CAPACITY + 1, GameMode::RealTime, VictoryConditions::default(), false),
+		Err(LobbyError::InvalidCapacity(MAX_CAPACITY + 1))
+	);
+	let mode = GameMode::TurnBased { turn_seconds: 1 };
+	assert_eq!(
+		lobby.create_game("Game".to_string(), 2, mode, VictoryConditions::default(), false),
+		Err(LobbyError::InvalidTurnLength(1))
+	);
+	let victory = VictoryConditions {
+		domination_percent: Some(101),
+		..Default::default()
+	};
+	assert_eq!(lobby.create_game("Game".to_string(), 2, GameMode::RealTime, victory, false), Err(LobbyError::InvalidDomination(101)));
+	for _ in 0..MAX_GAMES {
+		lobby.create_game("Game".to_string(), 2, GameMode::RealTime, VictoryConditions::default(), false).unwrap();
+	}
+	assert_eq!(
+		lobby.create_game("Game".to_string(), 2, GameMode::RealTime, VictoryConditions::default(), false),
+		Err(LobbyError::TooManyGames)
+	);
+
+	let game = lobby.list()[0].id;
+	let connection = lobby.connect();
+	lobby.join(game, connection, "a".to_string()).unwrap();
+	lobby.suspend(5, game, "a".to_string());
+	lobby.leave(game, connection);
+	// The suspended session keeps the game open until it expires
+	lobby.expire(Instant::now());
+	assert_eq!(lobby.resume(5), Some((game, "a".to_string())));
+	lobby.join(game, connection, "a".to_string()).unwrap();
+	lobby.suspend(6, game, "a".to_string());
+	lobby.leave(game, connection);
+	assert!(lobby.list().iter().any(|summary| summary.id == game));
+	lobby.expire(Instant::now() + RESUME_GRACE);
+	assert!(!lobby.list().iter().any(|summary| summary.id == game));
+	assert_eq!(lobby.resume(6), None);
+}
+
+#[tokio::test]
+async fn countries_are_released_when_players_leave_for_good() {
+	let mut lobby = Lobby::new(crate::load_starting_map().unwrap());
+	let world = lobby.create_game("World".to_string(), 2, GameMode::RealTime, VictoryConditions::NONE, true).unwrap();
+	let join = |lobby: &mut Lobby, player: &str| {
+		let connection = lobby.connect();
+		let game = lobby.join(world, connection, player.to_string()).unwrap();
+		(connection, game)
+	};
+	let (first, game) = join(&mut lobby, "Guest 1");
+	let country = game.subscribe("Guest 1".to_string(), None).await.unwrap().country;
+	assert!(country.is_some());
+	lobby.suspend(5, world, "Guest 1".to_string());
+	lobby.leave(world, first);
+	let (second, _) = join(&mut lobby, "Guest 2");
+	let other = game.subscribe("Guest 2".to_string(), None).await.unwrap().country;
+	assert_ne!(other, country, "Suspended sessions keep their country");
+
+	lobby.expire(Instant::now() + RESUME_GRACE);
+	assert_eq!(lobby.resume(5), None);
+	join(&mut lobby, "Guest 3");
+	assert_eq!(game.subscribe("Guest 3".to_string(), None).await.unwrap().country, country);
+	// Leaving without suspending the session releases the country straight away
+	lobby.leave(world, second);
+	join(&mut lobby, "Guest 4");
+	assert_eq!(game.subscribe("Guest 4".to_string(), None).await.unwrap().country, other);
+}
+
+#[tokio::test]
+async fn saved_games_reopen_with_their_ids() {
+	let mut lobby = Lobby::new(StartingMap::default());
+	let id = lobby.create_game("Saved".to_string(), 3, GameMode::RealTime, VictoryConditions::default(), false).unwrap();
+	let connection = lobby.connect();
+	lobby.join(id, connection, "Guest 1f".to_string()).unwrap();
+	lobby.suspend(5, id, "Guest 1f".to_string());
+	lobby.leave(id, connection);
+	let saved = lobby.games().pop().unwrap();
+	assert_eq!((saved.id, saved.settings.name.as_str(), saved.settings.capacity), (id, "Saved", 3));
+	assert_eq!(saved.sessions, [(5, "Guest 1f".to_string())]);
+	let snapshot = saved.game.snapshot().await.unwrap();
+
+	let mut lobby = Lobby::new(StartingMap::default());
+	lobby.restore(GameId(7), saved.settings, saved.sessions, snapshot);
+	lobby.expire(Instant::now());
+	assert_eq!(lobby.list()[0].id, GameId(7), "The restored session keeps the game open");
+	assert_eq!(lobby.resume(5), Some((GameId(7), "Guest 1f".to_string())));
+	assert_eq!(lobby.list()[0].name, "Saved");
+	// New games do not take the id of a restored one
+	let new = lobby.create_game("New".to_string(), 2, GameMode::RealTime, VictoryConditions::default(), false).unwrap();
+	assert_eq!(new, GameId(8));
+}
@@ -1,10 +1,20 @@
 use anyhow::{anyhow, Context};
 use futures_util::lock::Mutex;
 use futures_util::{stream::SplitSink, SinkExt};
-use geonext_shared::{territories::Territories, ServerMessage};
-use std::collections::HashMap;
+use game::{GameHandle, StartingMap, Subscription, Update};
+use geonext_shared::lobby::{GameId, GameMode};
+use geonext_shared::map_loader::HeightMap;
+use geonext_shared::orders::Order;
+use geonext_shared::regions::Regions;
+use geonext_shared::territories::{CountryId, Territories};
+use geonext_shared::victory::VictoryConditions;
+use geonext_shared::{ClientMessage, ServerMessage, PROTOCOL_VERSION};
+use lobby::{ConnectionId, Lobby};
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::time::Instant;
 use warp::filters::ws::{Message, WebSocket};
 use warp::Filter;
 
@@ -12,25 +22,25 @@ mod auth;
 mod compile_utils;
 #[cfg(feature = "debugging")]
 mod debugging;
+mod game;
 mod html;
+mod lobby;
 mod logger;
+mod save;
+mod view;
 
 #[macro_use]
 extern crate log;
 
+/// Connections that send nothing for this long are closed. Clients ping every two seconds.
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(15);
+
 #[derive(Clone)]
 pub struct State {
 	/// The file system path to the client folder (passed in as command line arg)
 	absolute_owned_client_path: PathBuf,
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
-pub struct GameId(u32);
-
-pub struct Game {
-	territories: Territories,
-}
-
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
 	logger::init_logger();
@@ -56,56 +66,150 @@ async fn main() -> Result<(), anyhow::Error> {
 		};
 		html::get_index(state)
 	});
-	let games = Arc::new(Mutex::new(HashMap::new()));
+	let save_config = save::SaveConfig::from_env();
+	let mut saves = save::Saves::new(save_config.directory);
+	let (lobby, saves) = match load_starting_map() {
+		Ok(map) => {
+			let mut lobby = Lobby::new(map);
+			let (saved, unreadable) = saves.load_all();
+			info!("Restoring {} saved games from {:?}", saved.len(), saves.directory());
+			for id in unreadable {
+				lobby.reserve(id);
+			}
+			for game in saved {
+				lobby.restore(game.id, game.settings, game.sessions, game.state);
+			}
+			if lobby.list().is_empty() {
+				create_default_games(&mut lobby);
+			}
+			(lobby, Some(saves))
+		}
+		Err(e) => {
+			error!("{e:?}");
+			// Games cannot be restored without the map, so the saves are left alone
+			(Lobby::new(StartingMap::default()), None)
+		}
+	};
+	let lobby = Arc::new(Mutex::new(lobby));
 
-	match bincode::deserialize(include_bytes!("./../../assets/starting_game_map")) {
-		Ok(territories) => {
-			games.lock().await.insert(GameId(0), Arc::new(Mutex::new(Game { territories })));
+	let expiring_lobby = lobby.clone();
+	tokio::spawn(async move {
+		let mut interval = tokio::time::interval(Duration::from_secs(60));
+		loop {
+			interval.tick().await;
+			expiring_lobby.lock().await.expire(Instant::now());
 		}
-		Err(e) => error!("Failed to load map {e:?}"),
-	}
+	});
+
+	let saving = saves.map(|mut saves| {
+		let (stop, mut stopped) = tokio::sync::oneshot::channel::<()>();
+		let lobby = lobby.clone();
+		let task = tokio::spawn(async move {
+			let mut interval = tokio::time::interval(save_config.interval);
+			interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+			// The first tick completes straight away, when there is nothing new to save
+			interval.tick().await;
+			loop {
+				// Games are saved one last time when the server shuts down
+				let stopping = tokio::select! {
+					_ = interval.tick() => false,
+					_ = &mut stopped => true,
+				};
+				let games = lobby.lock().await.games();
+				if let Err(e) = saves.save_all(games).await {
+					error!("Failed to save games to {:?} {e:?}", saves.directory());
+				}
+				if stopping {
+					break;
+				}
+			}
+		});
+		(stop, task)
+	});
 
 	let assets = warp::path("assets").and(warp::fs::dir(assets));
 	let pkg = warp::path("pkg").and(warp::fs::dir(serve_path.join("pkg")));
 
 	let ws = warp::path("__stream").and(warp::ws()).map(move |ws: warp::ws::Ws| {
-		let games = games.clone();
+		let lobby = lobby.clone();
 		let state = State {
 			absolute_owned_client_path: absolute_owned_client_path.clone(),
 		};
 
 		// And then our closure will be called when it completes...
 		ws.on_upgrade(|current_websocket| async move {
-			let games = games.lock().await;
-			let Some(game) = games.get(&GameId(0)).clone() else {
-				warn!("Failed to load game");
-				return;
+			let (connection, mut announcements) = {
+				let mut lobby = lobby.lock().await;
+				(lobby.connect(), lobby.announcements.subscribe())
 			};
-			// Just echo all messages back...
 			use futures_util::stream::StreamExt;
 			let (mut tx, mut rx) = current_websocket.split();
 			let mut stream = Stream { stream: &mut tx };
-			info!("Send map");
-			if let Err(e) = stream.send(&ServerMessage::Map(game.lock().await.territories.to_rle())).await {
-				error!("Failed to send map {e}")
-			}
-			while let Some(Ok(message)) = {
-				let val = rx.next().await;
-				info!("Val {val:?}");
-				val
-			} {
-				let input = message.as_bytes();
-				info!("Input {input:?}");
-				let message = {
-					let websocket = handle_socket_msg(&state, input, &mut stream).await.context("Handling websocket message");
-
-					let Err(e) = websocket else { continue };
-					error!("Message: {input:?}\nError: {e:?}");
-					format!("{:?}", e)
-				};
+			let mut session = Session::new(connection);
+			let mut last_message = Instant::now();
+			while !session.closing {
+				tokio::select! {
+					val = rx.next() => {
+						info!("Val {val:?}");
+						let Some(Ok(message)) = val else { break };
+						last_message = Instant::now();
+						let input = message.as_bytes();
+						info!("Input {input:?}");
+						let message = {
+							let websocket = handle_socket_msg(&state, &lobby, &mut session, input, &mut stream).await.context("Handling websocket message");
+
+							let Err(e) = websocket else { continue };
+							error!("Message: {input:?}\nError: {e:?}");
+							format!("{:?}", e)
+						};
 
-				let _ = stream.send(&ServerMessage::Error { message }).await;
+						let _ = stream.send(&ServerMessage::Error { message }).await;
+					}
+					announcement = announcements.recv() => match announcement {
+						Ok(_) if !session.handshake_complete => {}
+						Ok(announcement) => {
+							if let Err(e) = stream.send(&announcement).await {
+								error!("Failed to send lobby change {e}")
+							}
+						}
+						Err(broadcast::error::RecvError::Lagged(_)) => {
+							let games = lobby.lock().await.list();
+							let _ = stream.send(&ServerMessage::GameList { games }).await;
+						}
+						Err(broadcast::error::RecvError::Closed) => break,
+					},
+					update = session.next_update() => match update {
+						Ok((index, audience, update)) => {
+							let country = session.game.as_mut().and_then(|joined| {
+								joined.last_update = index;
+								joined.country
+							});
+							if audience.includes(country) {
+								if let Err(e) = stream.send(&update).await {
+									error!("Failed to send update {e}")
+								}
+							}
+						}
+						Err(broadcast::error::RecvError::Lagged(skipped)) => {
+							warn!("Client fell behind and missed {skipped} updates");
+							let context = SocketContext { state: &state, lobby: &lobby, session: &mut session, stream: &mut stream };
+							if let Err(e) = send_state(context).await {
+								error!("Failed to resend state {e:?}")
+							}
+						}
+						Err(broadcast::error::RecvError::Closed) => {
+							leave_game(&lobby, &mut session, false).await;
+							let _ = stream.send(&ServerMessage::Left).await;
+						}
+					},
+					_ = tokio::time::sleep_until(last_message + HEARTBEAT_TIMEOUT) => {
+						info!("Closing connection after {HEARTBEAT_TIMEOUT:?} without a message");
+						break;
+					}
+				}
 			}
+			// Remember where the client was so that it can resume if it reconnects
+			leave_game(&lobby, &mut session, true).await;
 		})
 	});
 	let routes = index.or(ws).or(assets).or(pkg);
@@ -127,16 +231,158 @@ async fn main() -> Result<(), anyhow::Error> {
 	#[cfg(not(feature = "debugging"))]
 	let final_routes = routes.with(warp::cors().allow_any_origin());
 
-	warp::serve(final_routes).run(([0, 0, 0, 0, 0, 0, 0, 0], 8080)).await;
+	tokio::select! {
+		_ = warp::serve(final_routes).run(([0, 0, 0, 0, 0, 0, 0, 0], 8080)) => {}
+		_ = shutdown_signal() => info!("Shutting down"),
+	}
+	if let Some((stop, task)) = saving {
+		let _ = stop.send(());
+		task.await.context("Save games on shutdown")?;
+	}
 
 	Ok(())
 }
 
-async fn handle_socket_msg<'a>(state: &'a State, input: &'a [u8], stream: &'a mut Stream<'_>) -> anyhow::Result<()> {
-	let message: geonext_shared::ClientMessage = bincode::deserialize(input).with_context(|| format!("Decode websocket binary \"{input:?}\""))?;
-	let context = SocketContext { state, stream };
+/// Creates the games that are open when there are no saves
+fn create_default_games(lobby: &mut Lobby) {
+	// The shared worlds never end
+	lobby
+		.create_game("World".to_string(), lobby::MAX_CAPACITY, GameMode::RealTime, VictoryConditions::NONE, true)
+		.expect("The default game should be valid");
+	// For groups that play a turn a day
+	let mode = GameMode::TurnBased { turn_seconds: 24 * 60 * 60 };
+	lobby
+		.create_game("World (daily turns)".to_string(), lobby::MAX_CAPACITY, mode, VictoryConditions::NONE, true)
+		.expect("The default game should be valid");
+}
+
+/// Completes when the server is asked to stop, with ctrl-c or, on unix, SIGTERM
+async fn shutdown_signal() {
+	let ctrl_c = async {
+		if let Err(e) = tokio::signal::ctrl_c().await {
+			error!("Failed to listen for ctrl-c {e}");
+			std::future::pending::<()>().await;
+		}
+	};
+	#[cfg(unix)]
+	let terminate = async {
+		match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+			Ok(mut signal) => {
+				signal.recv().await;
+			}
+			Err(e) => {
+				error!("Failed to listen for SIGTERM {e}");
+				std::future::pending::<()>().await;
+			}
+		}
+	};
+	#[cfg(not(unix))]
+	let terminate = std::future::pending::<()>();
+	tokio::select! {
+		_ = ctrl_c => {}
+		_ = terminate => {}
+	}
+}
+
+/// Loads the map that every game starts with
+fn load_starting_map() -> anyhow::Result<StartingMap> {
+	let mut territories = bincode::deserialize::<Territories>(include_bytes!("./../../assets/starting_game_map")).context("Failed to load map")?;
+	// Colours are assigned on load so that neighbouring countries always differ, even for newly extracted maps
+	territories.assign_colours();
+	let mut height_map = HeightMap::default();
+	height_map
+		.load(include_bytes!("./../../assets/map.txt").to_vec())
+		.map_err(|e| anyhow!("Failed to load height map {e}"))?;
+	let regions = Regions::from_json(include_str!("./../../assets/axial-coordinates.json"), include_str!("./../../assets/neighbours.json"), &territories).context("Failed to load regions")?;
+	Ok(StartingMap { territories, regions, height_map })
+}
+
+/// A game that a connection has joined
+struct JoinedGame {
+	id: GameId,
+	game: GameHandle,
+	updates: broadcast::Receiver<Update>,
+	/// Identifies the session if the client reconnects
+	resume_token: u64,
+	/// The index of the last update sent to the client
+	last_update: u64,
+	/// The country this player controls, which decides the updates they are sent
+	country: Option<CountryId>,
+}
+
+/// The state of a single websocket connection
+struct Session {
+	connection: ConnectionId,
+	/// The name shown to other players in the lobby, which is the Discord username once authenticated
+	player_name: String,
+	game: Option<JoinedGame>,
+	/// Set once the client has sent a compatible [`ClientMessage::Hello`]
+	handshake_complete: bool,
+	/// Set to close the connection after the current message
+	closing: bool,
+}
+
+impl Session {
+	fn new(connection: ConnectionId) -> Self {
+		Self {
+			connection,
+			// Countries are kept by player name, even across restarts, so guest names must not be reused
+			player_name: format!("Guest {:012x}", game::random_u64() >> 16),
+			game: None,
+			handshake_complete: false,
+			closing: false,
+		}
+	}
+
+	fn joined(&mut self) -> anyhow::Result<&mut JoinedGame> {
+		self.game.as_mut().ok_or_else(|| anyhow!("Join a game first"))
+	}
+
+	/// The next update from the current game. Never completes when not in a game.
+	async fn next_update(&mut self) -> Result<Update, broadcast::error::RecvError> {
+		match &mut self.game {
+			Some(joined) => joined.updates.recv().await,
+			None => std::future::pending().await,
+		}
+	}
+}
+
+async fn handle_socket_msg<'a>(state: &'a State, lobby: &'a Mutex<Lobby>, session: &'a mut Session, input: &'a [u8], stream: &'a mut Stream<'_>) -> anyhow::Result<()> {
+	let message: ClientMessage = bincode::deserialize(input).with_context(|| format!("Decode websocket binary \"{input:?}\""))?;
+	match (&message, session.handshake_complete) {
+		(ClientMessage::Hello { .. }, true) => return Err(anyhow!("Handshake has already been completed")),
+		(ClientMessage::Hello { .. }, false) => {}
+		(_, true) => {}
+		(_, false) => return Err(anyhow!("Handshake must be completed before sending other messages")),
+	}
+	let context = SocketContext { state, lobby, session, stream };
 	match message {
-		geonext_shared::ClientMessage::Auth { code } => identify(context, code).await.context("Authentication message"),
+		ClientMessage::Hello { protocol_version, client_build } => hello(context, protocol_version, client_build).await.context("Hello message"),
+		ClientMessage::Auth { code } => identify(context, code).await.context("Authentication message"),
+		ClientMessage::Act(order) => act(context, order).await.context("Act message"),
+		ClientMessage::PlaceBuilding { hex, kind } => act(context, Order::PlaceBuilding { hex, kind }).await.context("Place building message"),
+		ClientMessage::MoveUnit { unit, path } => act(context, Order::Move { unit, path }).await.context("Move unit message"),
+		ClientMessage::RequestMap => send_state(context).await.context("Request map message"),
+		ClientMessage::Resume { token } => resume(context, token).await.context("Resume message"),
+		ClientMessage::Ping { timestamp } => context.stream.send(&ServerMessage::Pong { timestamp }).await,
+		ClientMessage::ListGames => {
+			let games = context.lobby.lock().await.list();
+			context.stream.send(&ServerMessage::GameList { games }).await
+		}
+		ClientMessage::CreateGame { name, capacity, mode, victory } => create_game(context, name, capacity, mode, victory).await.context("Create game message"),
+		ClientMessage::JoinGame { game } => join_game(context, game, None).await.context("Join game message"),
+		ClientMessage::LeaveGame => {
+			leave_game(context.lobby, context.session, false).await;
+			context.stream.send(&ServerMessage::Left).await
+		}
+		ClientMessage::SubmitOrders { turn, orders } => {
+			let player = context.session.player_name.clone();
+			context.session.joined()?.game.submit_orders(player, turn, orders).await.context("Submit orders message")
+		}
+		ClientMessage::Diplomacy(action) => {
+			let player = context.session.player_name.clone();
+			context.session.joined()?.game.diplomacy(player, action).await.context("Diplomacy message")
+		}
 	}
 }
 
@@ -153,12 +399,144 @@ impl<'a> Stream<'a> {
 
 struct SocketContext<'a, 'b: 'a> {
 	state: &'a State,
+	lobby: &'a Mutex<Lobby>,
+	session: &'a mut Session,
 	stream: &'a mut Stream<'b>,
 }
 
+impl<'b> SocketContext<'_, 'b> {
+	fn reborrow(&mut self) -> SocketContext<'_, 'b> {
+		SocketContext {
+			state: self.state,
+			lobby: self.lobby,
+			session: self.session,
+			stream: self.stream,
+		}
+	}
+}
+
+/// Checks that the client speaks the same protocol
+async fn hello(context: SocketContext<'_, '_>, protocol_version: u32, client_build: String) -> anyhow::Result<()> {
+	if protocol_version != PROTOCOL_VERSION {
+		let reason = if protocol_version < PROTOCOL_VERSION {
+			format!("Your client (build {client_build}, protocol {protocol_version}) is out of date. Please refresh the page to update to protocol {PROTOCOL_VERSION}.")
+		} else {
+			format!("Your client (build {client_build}, protocol {protocol_version}) is newer than the server (protocol {PROTOCOL_VERSION}).")
+		};
+		info!("Rejecting client: {reason}");
+		context.session.closing = true;
+		return context.stream.send(&ServerMessage::HelloRejected { reason }).await;
+	}
+	context.session.handshake_complete = true;
+	context
+		.stream
+		.send(&ServerMessage::HelloAccepted {
+			protocol_version: PROTOCOL_VERSION,
+			server_build: env!("CARGO_PKG_VERSION").to_string(),
+		})
+		.await
+}
+
+/// Sends the full state of the current game again, replacing the subscription so that no updates are missed or repeated
+async fn send_state(context: SocketContext<'_, '_>) -> anyhow::Result<()> {
+	let player = context.session.player_name.clone();
+	let joined = context.session.joined()?;
+	let subscription = joined.game.subscribe(player, Some(joined.resume_token)).await?;
+	joined.updates = subscription.updates;
+	joined.last_update = subscription.last_update;
+	joined.country = subscription.country;
+	send_catch_up(context.stream, subscription.resume_token, subscription.catch_up).await
+}
+
+async fn send_catch_up(stream: &mut Stream<'_>, resume_token: u64, catch_up: Vec<ServerMessage>) -> anyhow::Result<()> {
+	stream.send(&ServerMessage::Session { resume_token }).await?;
+	for message in &catch_up {
+		stream.send(message).await?;
+	}
+	Ok(())
+}
+
+/// Creates a game and joins it
+async fn create_game(context: SocketContext<'_, '_>, name: String, capacity: u8, mode: GameMode, victory: VictoryConditions) -> anyhow::Result<()> {
+	let id = context.lobby.lock().await.create_game(name, capacity, mode, victory, false)?;
+	join_game(context, id, None).await
+}
+
+/// Moves the connection into a game and sends its state, resuming the session if there is a token
+async fn join_game(context: SocketContext<'_, '_>, id: GameId, resume_token: Option<u64>) -> anyhow::Result<()> {
+	if context.session.game.as_ref().is_some_and(|joined| joined.id == id) {
+		return Err(anyhow!("Already in game {}", id.0));
+	}
+	leave_game(context.lobby, context.session, false).await;
+	let game = context.lobby.lock().await.join(id, context.session.connection, context.session.player_name.clone())?;
+	let Subscription {
+		updates,
+		catch_up,
+		last_update,
+		resume_token,
+		country,
+	} = match game.subscribe(context.session.player_name.clone(), resume_token).await {
+		Ok(subscription) => subscription,
+		Err(e) => {
+			context.lobby.lock().await.leave(id, context.session.connection);
+			return Err(e);
+		}
+	};
+	context.session.game = Some(JoinedGame {
+		id,
+		game,
+		updates,
+		resume_token,
+		last_update,
+		country,
+	});
+	context.stream.send(&ServerMessage::Joined { game: id }).await?;
+	send_catch_up(context.stream, resume_token, catch_up).await
+}
+
+/// Takes the connection out of its game. If `suspend` is set the session can be resumed by reconnecting.
+async fn leave_game(lobby: &Mutex<Lobby>, session: &mut Session, suspend: bool) {
+	let Some(joined) = session.game.take() else { return };
+	if suspend {
+		joined.game.suspend(joined.resume_token, joined.last_update).await;
+	}
+	let mut lobby = lobby.lock().await;
+	if suspend {
+		lobby.suspend(joined.resume_token, joined.id, session.player_name.clone());
+	}
+	lobby.leave(joined.id, session.connection);
+}
+
+/// Rejoins the game of a previous connection, or returns the client to the lobby if the session cannot be resumed
+async fn resume(mut context: SocketContext<'_, '_>, resume_token: u64) -> anyhow::Result<()> {
+	let game = context.lobby.lock().await.resume(resume_token);
+	if let Some((id, player)) = game {
+		// Guests have a random name, so the name is restored to keep controlling the same country
+		context.session.player_name = player.clone();
+		match join_game(context.reborrow(), id, Some(resume_token)).await {
+			Ok(()) => return Ok(()),
+			Err(e) => {
+				warn!("Failed to resume session {e:?}");
+				context.lobby.lock().await.release_if_gone(id, &player);
+			}
+		}
+	}
+	leave_game(context.lobby, context.session, false).await;
+	context.stream.send(&ServerMessage::Left).await?;
+	let games = context.lobby.lock().await.list();
+	context.stream.send(&ServerMessage::GameList { games }).await
+}
+
 async fn identify(context: SocketContext<'_, '_>, code: String) -> anyhow::Result<()> {
 	let access_token = auth::exchange_code(&code, context.state).await.context("Exchange discord oauth code")?;
 	let (_id, username) = auth::get_identity(&access_token).await.context("Get discord identity")?;
-	context.stream.send(&geonext_shared::ServerMessage::AuthAccepted { username }).await?;
+	context.session.player_name = username.clone();
+	context.stream.send(&ServerMessage::AuthAccepted { username }).await?;
 	Ok(())
 }
+
+/// Asks the game to carry out an order, which sends the resulting changes to every client
+async fn act(context: SocketContext<'_, '_>, order: Order) -> anyhow::Result<()> {
+	let player = context.session.player_name.clone();
+	context.session.joined()?.game.act(player, order).await
+}
@@ -0,0 +1,238 @@
+use crate::game::GameSnapshot;
+use crate::lobby::{GameSettings, OpenGame};
+use anyhow::{anyhow, Context};
+use geonext_shared::lobby::GameId;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+pub const SAVE_VERSION: u32 = 2;
+
+type Migration = fn(Vec<u8>) -> anyhow::Result<Vec<u8>>;
+
+/// `MIGRATIONS[n - 1]` turns a version `n` save into a version `n + 1` save
+const MIGRATIONS: [Migration; SAVE_VERSION as usize - 1] = [add_sessions];
+
+/// Version 2 added the suspended sessions, which bincode writes as a zero length when there are none
+fn add_sessions(mut data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+	data.extend(0u64.to_le_bytes());
+	Ok(data)
+}
+
+const EXTENSION: &str = "bin";
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SavedGame {
+	pub id: GameId,
+	pub settings: GameSettings,
+	pub state: GameSnapshot,
+	/// As (resume token, player)
+	pub sessions: Vec<(u64, String)>,
+}
+
+pub struct SaveConfig {
+	pub directory: PathBuf,
+	pub interval: Duration,
+}
+
+impl SaveConfig {
+	/// `GEONEXT_SAVE_DIR` and `GEONEXT_SAVE_INTERVAL_SECS` default to `saves` and five minutes
+	pub fn from_env() -> Self {
+		let directory = std::env::var("GEONEXT_SAVE_DIR").unwrap_or_else(|_| "saves".to_string()).into();
+		let seconds = std::env::var("GEONEXT_SAVE_INTERVAL_SECS").ok().and_then(|seconds| seconds.parse().ok()).filter(|&seconds| seconds > 0);
+		Self {
+			directory,
+			interval: Duration::from_secs(seconds.unwrap_or(5 * 60)),
+		}
+	}
+}
+
+/// A little endian [`SAVE_VERSION`] followed by the bincode game
+pub fn encode(game: &SavedGame) -> anyhow::Result<Vec<u8>> {
+	let mut data = SAVE_VERSION.to_le_bytes().to_vec();
+	bincode::serialize_into(&mut data, game).context("Encode save")?;
+	Ok(data)
+}
+
+pub fn decode(data: &[u8]) -> anyhow::Result<SavedGame> {
+	let (version, data) = data.split_first_chunk::<4>().ok_or_else(|| anyhow!("Save is too short to have a version"))?;
+	let version = u32::from_le_bytes(*version);
+	if version == 0 || version > SAVE_VERSION {
+		return Err(anyhow!("Save version {version} is not supported by this server (version {SAVE_VERSION})"));
+	}
+	let mut data = data.to_vec();
+	for (from, migrate) in MIGRATIONS.iter().enumerate().skip(version as usize - 1) {
+		data = migrate(data).with_context(|| format!("Migrate save from version {}", from + 1))?;
+	}
+	bincode::deserialize(&data).context("Decode save")
+}
+
+fn file_name(id: GameId) -> String {
+	format!("game-{}.{EXTENSION}", id.0)
+}
+
+fn file_id(path: &Path) -> Option<GameId> {
+	let name = path.file_name()?.to_str()?;
+	let id = name.strip_prefix("game-")?.strip_suffix(EXTENSION)?.strip_suffix('.')?;
+	id.parse().ok().map(GameId)
+}
+
+pub struct Saves {
+	directory: PathBuf,
+	/// Games whose saves this server loaded or wrote, so that unreadable saves are never removed
+	owned: BTreeSet<GameId>,
+}
+
+impl Saves {
+	pub fn new(directory: PathBuf) -> Self {
+		Self { directory, owned: BTreeSet::new() }
+	}
+
+	pub fn directory(&self) -> &Path {
+		&self.directory
+	}
+
+	/// Also returns the ids of saves that could not be read, which should not be given to new games so that the files are kept
+	pub fn load_all(&mut self) -> (Vec<SavedGame>, Vec<GameId>) {
+		let Ok(entries) = std::fs::read_dir(&self.directory) else {
+			return (Vec::new(), Vec::new());
+		};
+		let (mut games, mut unreadable) = (Vec::new(), Vec::new());
+		for path in entries.filter_map(Result::ok).map(|entry| entry.path()) {
+			let Some(id) = file_id(&path) else { continue };
+			match std::fs::read(&path).map_err(anyhow::Error::from).and_then(|data| decode(&data)) {
+				Ok(game) if game.id == id => games.push(game),
+				Ok(game) => {
+					error!("Save {path:?} contains game {} instead", game.id.0);
+					unreadable.push(id);
+				}
+				Err(e) => {
+					error!("Failed to load save {path:?} {e:?}");
+					unreadable.push(id);
+				}
+			}
+		}
+		games.sort_by_key(|game| game.id);
+		unreadable.sort();
+		self.owned.extend(games.iter().map(|game| game.id));
+		(games, unreadable)
+	}
+
+	/// Each file is replaced in one step so that a crash while saving leaves the previous save intact
+	pub async fn save_all(&mut self, games: Vec<OpenGame>) -> anyhow::Result<()> {
+		let closed = self.owned.iter().copied().filter(|&id| !games.iter().any(|open| open.id == id)).collect::<Vec<_>>();
+		let mut saves = Vec::new();
+		for OpenGame { id, settings, sessions, game } in games {
+			match game.snapshot().await {
+				Ok(state) => saves.push((id, encode(&SavedGame { id, settings, state, sessions })?)),
+				// The previous save is kept
+				Err(e) => warn!("Failed to snapshot game {} {e:?}", id.0),
+			}
+		}
+		let written = saves.iter().map(|&(id, _)| id).collect::<Vec<_>>();
+		let (directory, removed) = (self.directory.clone(), closed.clone());
+		tokio::task::spawn_blocking(move || write_saves(&directory, saves, &removed)).await??;
+		for id in closed {
+			self.owned.remove(&id);
+		}
+		self.owned.extend(written);
+		Ok(())
+	}
+}
+
+fn write_saves(directory: &Path, saves: Vec<(GameId, Vec<u8>)>, closed: &[GameId]) -> anyhow::Result<()> {
+	std::fs::create_dir_all(directory).with_context(|| format!("Create save directory {directory:?}"))?;
+	for (id, data) in &saves {
+		let path = directory.join(file_name(*id));
+		let temporary = path.with_extension("tmp");
+		std::fs::write(&temporary, data).with_context(|| format!("Write {temporary:?}"))?;
+		std::fs::rename(&temporary, &path).with_context(|| format!("Replace {path:?}"))?;
+	}
+	for &id in closed {
+		let path = directory.join(file_name(id));
+		match std::fs::remove_file(&path) {
+			Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e).with_context(|| format!("Remove closed game {path:?}")),
+			_ => {}
+		}
+	}
+	info!("Saved {} games to {directory:?}", saves.len());
+	Ok(())
+}
+
+#[cfg(test)]
+use crate::game::{Game, GameHandle, StartingMap};
+
+#[cfg(test)]
+fn test_save() -> SavedGame {
+	use geonext_shared::lobby::GameMode;
+	use geonext_shared::victory::VictoryConditions;
+	let settings = GameSettings {
+		name: "Saved".to_string(),
+		capacity: 2,
+		mode: GameMode::RealTime,
+		victory: VictoryConditions::default(),
+		permanent: false,
+	};
+	let state = Game::new(StartingMap::default(), settings.mode, settings.victory).snapshot();
+	SavedGame {
+		id: GameId(3),
+		settings,
+		state,
+		sessions: vec![(5, "Guest 1f".to_string())],
+	}
+}
+
+#[test]
+fn saves_are_versioned() {
+	let game = test_save();
+	let data = encode(&game).unwrap();
+	assert_eq!(data[..4], SAVE_VERSION.to_le_bytes());
+	assert_eq!(decode(&data).unwrap(), game);
+
+	assert!(decode(&data[..2]).is_err(), "Truncated saves are rejected");
+	let mut newer = data.clone();
+	newer[..4].copy_from_slice(&(SAVE_VERSION + 1).to_le_bytes());
+	assert!(decode(&newer).is_err(), "Saves from newer servers are rejected");
+	let mut unversioned = data;
+	unversioned[..4].copy_from_slice(&0u32.to_le_bytes());
+	assert!(decode(&unversioned).is_err());
+
+	// Version 1 saves ended before the sessions
+	let old = SavedGame { sessions: Vec::new(), ..game };
+	let mut version_1 = encode(&old).unwrap();
+	version_1.truncate(version_1.len() - 8);
+	version_1[..4].copy_from_slice(&1u32.to_le_bytes());
+	assert_eq!(decode(&version_1).unwrap(), old);
+}
+
+#[tokio::test]
+async fn only_closed_games_are_removed_from_disk() {
+	let directory = std::env::temp_dir().join(format!("geonext-saves-{}", std::process::id()));
+	std::fs::create_dir_all(&directory).unwrap();
+	std::fs::write(directory.join("game-5.bin"), b"corrupt").unwrap();
+	let mut saves = Saves::new(directory.clone());
+	let (games, unreadable) = saves.load_all();
+	assert!(games.is_empty());
+	assert_eq!(unreadable, [GameId(5)]);
+
+	let game = test_save();
+	let open = |id, game_handle| OpenGame {
+		id: GameId(id),
+		settings: game.settings.clone(),
+		sessions: game.sessions.clone(),
+		game: game_handle,
+	};
+	let running = || Game::restore(StartingMap::default(), game.state.clone()).spawn();
+	saves.save_all(vec![open(3, running()), open(4, running())]).await.unwrap();
+	let (games, unreadable) = Saves::new(directory.clone()).load_all();
+	assert_eq!(games.iter().map(|game| game.id).collect::<Vec<_>>(), [GameId(3), GameId(4)]);
+	assert_eq!(unreadable, [GameId(5)], "Unreadable saves are kept");
+
+	// Game 3 cannot be saved, so its previous save is kept, and game 4 has closed
+	saves.save_all(vec![open(3, GameHandle::ended())]).await.unwrap();
+	let (games, unreadable) = Saves::new(directory.clone()).load_all();
+	assert_eq!(games.iter().map(|game| game.id).collect::<Vec<_>>(), [GameId(3)]);
+	assert_eq!(unreadable, [GameId(5)]);
+	std::fs::remove_dir_all(&directory).unwrap();
+}
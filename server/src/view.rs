@@ -0,0 +1,242 @@
+use geonext_shared::buildings::Buildings;
+use geonext_shared::diplomacy::Diplomacy;
+use geonext_shared::map_loader::{HeightMap, HexCoord};
+use geonext_shared::territories::{CountryId, Territories};
+use geonext_shared::units::{Unit, Units};
+use geonext_shared::visibility::Visibility;
+use geonext_shared::ServerMessage;
+use serde::{Deserialize, Serialize};
+
+/// What the players controlling a country know about the game.
+///
+/// Hexes they cannot see keep the owner and buildings they last saw there, and armies are only known while they can be seen. Allies see
+/// everything each other can see.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct View {
+	country: CountryId,
+	visibility: Visibility,
+	#[serde(skip)]
+	sight: Sight,
+	territories: Territories,
+	units: Units,
+	buildings: Buildings,
+}
+
+/// What the country and its allies can see from their territory and from their armies, which is only worked out again when territory
+/// changes hands or armies move. Not saved, as the sequence numbers of territories restart when they are loaded.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Sight {
+	/// The sequence number of the territories and the countries that `territory` was worked out from
+	territory_key: Option<(u32, Vec<CountryId>)>,
+	territory: Visibility,
+	/// The positions of the armies that `armies` was worked out from
+	army_key: Option<Vec<HexCoord>>,
+	armies: Visibility,
+}
+
+impl View {
+	/// Starts a view knowing only the map the game started with. Call [`Self::update`] before sending it.
+	pub fn new(country: CountryId, starting_territories: Territories) -> Self {
+		Self {
+			country,
+			visibility: Visibility::default(),
+			sight: Sight::default(),
+			territories: starting_territories,
+			units: Units::default(),
+			buildings: Buildings::default(),
+		}
+	}
+
+	pub fn can_see(&self, hex: HexCoord) -> bool {
+		self.visibility.contains(hex)
+	}
+
+	/// Makes armies look again on the next update, as the terrain decides how far they can see
+	pub fn terrain_changed(&mut self) {
+		self.sight.army_key = None;
+	}
+
+	/// Brings the view up to date with what the country can now see, returning the messages that tell its players what changed
+	pub fn update(&mut self, territories: &Territories, units: &Units, buildings: &Buildings, diplomacy: &Diplomacy, height_map: &HeightMap) -> Vec<ServerMessage> {
+		let mut messages = Vec::new();
+		let sighted = core::iter::once(self.country).chain(diplomacy.allies(self.country)).collect::<Vec<_>>();
+		let owners_changed = self.sight.territory_key.as_ref().is_none_or(|(seq, _)| *seq != territories.seq());
+		let territory_key = Some((territories.seq(), sighted.clone()));
+		let territory_changed = self.sight.territory_key != territory_key;
+		if territory_changed {
+			self.sight.territory = Visibility::territory(&sighted, territories);
+			self.sight.territory_key = territory_key;
+		}
+		let army_key = Some(units.iter().filter(|unit| sighted.contains(&unit.owner)).map(|unit| unit.position).collect());
+		let mut previous_armies = None;
+		if self.sight.army_key != army_key {
+			let mut armies = Visibility::default();
+			armies.add_armies(&sighted, units, height_map);
+			previous_armies = Some(core::mem::replace(&mut self.sight.armies, armies));
+			self.sight.army_key = army_key;
+		}
+		let (visible, hidden) = match previous_armies {
+			_ if territory_changed => {
+				let mut visibility = self.sight.territory.clone();
+				visibility.add(&self.sight.armies);
+				let changes = visibility.changes_since(&self.visibility);
+				self.visibility = visibility;
+				changes
+			}
+			// Only the armies have moved, so only the hexes they see can change
+			Some(previous) => {
+				let territory = &self.sight.territory;
+				let (mut visible, mut hidden) = self.sight.armies.changes_since(&previous);
+				visible.retain(|&hex| !territory.contains(hex));
+				hidden.retain(|&hex| !territory.contains(hex));
+				self.visibility.apply(&visible, &hidden);
+				(visible, hidden)
+			}
+			None => (Vec::new(), Vec::new()),
+		};
+		// Hexes only change hands when the territories change, but hexes that come into sight may have changed hands out of sight
+		let checked = if owners_changed { self.visibility.iter().collect() } else { visible.clone() };
+		if !visible.is_empty() || !hidden.is_empty() {
+			messages.push(ServerMessage::VisibilityChanged { visible, hidden });
+		}
+
+		let mut owners = checked
+			.into_iter()
+			.filter_map(|hex| territories.hex_owner(hex).filter(|&owner| self.territories.hex_owner(hex) != Some(owner)).map(|owner| (hex, owner)))
+			.map(|(hex, owner)| (hex.to_offset().as_uvec2(), owner))
+			.map(|(offset, owner)| ((offset.x, offset.y), owner))
+			.collect::<Vec<_>>();
+		owners.sort_unstable_by_key(|&((x, y), _)| (y, x));
+		let delta = self.territories.change_owners(owners);
+		if !delta.changes.is_empty() {
+			messages.push(ServerMessage::TerritoryDelta(delta));
+		}
+
+		let seen = |unit: &&Unit| unit.owner == self.country || self.visibility.contains(unit.position);
+		let changed = units
+			.iter()
+			.filter(seen)
+			.map(|unit| self.as_seen(unit))
+			.filter(|unit| self.units.get(unit.id) != Some(unit))
+			.collect::<Vec<_>>();
+		let removed = self.units.iter().map(|unit| unit.id).filter(|&id| !units.get(id).is_some_and(|unit| seen(&unit))).collect::<Vec<_>>();
+		for unit in &changed {
+			self.units.insert(unit.clone());
+		}
+		for &id in &removed {
+			self.units.remove(id);
+		}
+		if !changed.is_empty() || !removed.is_empty() {
+			messages.push(ServerMessage::UnitsChanged { units: changed, removed });
+		}
+
+		let mut placed = buildings
+			.iter()
+			.filter(|&(hex, building)| self.visibility.contains(hex) && self.buildings.get(hex) != Some(building))
+			.collect::<Vec<_>>();
+		placed.sort_unstable_by_key(|(hex, _)| (hex.r(), hex.q()));
+		for &(hex, building) in &placed {
+			self.buildings.insert(hex, building);
+		}
+		if !placed.is_empty() {
+			messages.push(ServerMessage::BuildingsChanged { buildings: placed });
+		}
+		messages
+	}
+
+	/// A unit as the country sees it. Movement progress is left out, as it changes every tick, and so are the paths of other countries' units.
+	fn as_seen(&self, unit: &Unit) -> Unit {
+		let mut unit = unit.clone();
+		unit.progress = 0;
+		if unit.owner != self.country {
+			unit.path.clear();
+		}
+		unit
+	}
+
+	/// The messages that bring a new client up to date with the view, apart from changes to the terrain, which everyone can see
+	pub fn full_state(&self) -> Vec<ServerMessage> {
+		let mut visible = self.visibility.iter().collect::<Vec<_>>();
+		visible.sort_unstable_by_key(|hex| (hex.r(), hex.q()));
+		let mut state = vec![ServerMessage::Map(self.territories.to_rle()), ServerMessage::VisibilityChanged { visible, hidden: Vec::new() }];
+		let units = self.units.iter().cloned().collect::<Vec<_>>();
+		if !units.is_empty() {
+			state.push(ServerMessage::UnitsChanged { units, removed: Vec::new() });
+		}
+		let mut buildings = self.buildings.iter().collect::<Vec<_>>();
+		buildings.sort_unstable_by_key(|(hex, _)| (hex.r(), hex.q()));
+		if !buildings.is_empty() {
+			state.push(ServerMessage::BuildingsChanged { buildings });
+		}
+		state
+	}
+}
+
+#[test]
+fn views_only_show_what_their_country_can_see() {
+	use geonext_shared::economy::{Building, BuildingKind};
+	use geonext_shared::test_utils;
+	let map = crate::load_starting_map().unwrap();
+	let mut territories = map.territories.clone();
+	let test_utils::Border {
+		congo_hex,
+		zambia_hex,
+		chile_hex,
+		congo,
+		zambia,
+		chile,
+	} = test_utils::border(&territories);
+	let mut units = Units::default();
+	let nearby = units.spawn(zambia, zambia_hex, 3);
+	let distant = units.spawn(chile, chile_hex, 3);
+	let mut buildings = Buildings::default();
+	let farm = Building { kind: BuildingKind::Farm, level: 1 };
+	buildings.insert(chile_hex, farm);
+
+	let mut diplomacy = Diplomacy::default();
+	let mut view = View::new(congo, map.territories.clone());
+	let messages = view.update(&territories, &units, &buildings, &diplomacy, &map.height_map);
+	assert!(
+		matches!(&messages[..], [ServerMessage::VisibilityChanged { visible, hidden }, ServerMessage::UnitsChanged { units, .. }]
+		if visible.contains(&zambia_hex) && hidden.is_empty() && units.iter().map(|unit| unit.id).eq([nearby]))
+	);
+	assert!(view.update(&territories, &units, &buildings, &diplomacy, &map.height_map).is_empty(), "Nothing is resent");
+
+	// Changes out of sight are not sent until they are seen
+	territories.change_owners([((chile_hex.to_offset().x as u32, chile_hex.to_offset().y as u32), zambia)]);
+	units.get_mut(distant).unwrap().strength = 1;
+	assert!(view.update(&territories, &units, &buildings, &diplomacy, &map.height_map).is_empty());
+	let army = units.spawn(congo, chile_hex + HexCoord::RIGHT, 5);
+	let messages = view.update(&territories, &units, &buildings, &diplomacy, &map.height_map);
+	assert!(
+		matches!(&messages[..], [ServerMessage::VisibilityChanged { .. }, ServerMessage::TerritoryDelta(delta), ServerMessage::UnitsChanged { units, removed }, ServerMessage::BuildingsChanged { buildings }]
+		if delta.changes.contains(&((chile_hex.to_offset().x as u32, chile_hex.to_offset().y as u32), zambia))
+			&& units.iter().any(|unit| unit.id == distant && unit.strength == 1) && units.iter().any(|unit| unit.id == army) && removed.is_empty()
+			&& buildings[..] == [(chile_hex, farm)])
+	);
+
+	// Armies that leave sight are removed, but buildings are remembered
+	units.remove(army);
+	let messages = view.update(&territories, &units, &buildings, &diplomacy, &map.height_map);
+	assert!(matches!(&messages[..], [ServerMessage::VisibilityChanged { .. }, ServerMessage::UnitsChanged { units, removed }] if units.is_empty() && removed.contains(&distant)));
+	assert!(view
+		.full_state()
+		.iter()
+		.any(|message| matches!(message, ServerMessage::BuildingsChanged { buildings } if buildings[..] == [(chile_hex, farm)])));
+	assert!(!view.can_see(chile_hex));
+
+	// Allies share what they can see
+	diplomacy.set(congo, chile, geonext_shared::diplomacy::Relation::Alliance);
+	let messages = view.update(&territories, &units, &buildings, &diplomacy, &map.height_map);
+	assert!(view.can_see(chile_hex));
+	assert!(
+		matches!(&messages[..], [ServerMessage::VisibilityChanged { visible, .. }, ServerMessage::UnitsChanged { units, .. }] if visible.contains(&chile_hex) && units.iter().any(|unit| unit.id == distant))
+	);
+	// Only the hexes the army sees change as it moves
+	let scout = units.spawn(congo, congo_hex, 5);
+	view.update(&territories, &units, &buildings, &diplomacy, &map.height_map);
+	units.get_mut(scout).unwrap().position = zambia_hex + HexCoord::RIGHT * 4;
+	let messages = view.update(&territories, &units, &buildings, &diplomacy, &map.height_map);
+	assert!(matches!(&messages[..], [ServerMessage::VisibilityChanged { visible, .. }, ..] if !visible.is_empty()));
+	assert_eq!(view.visibility, Visibility::compute(&[congo, chile], &territories, &units, &map.height_map));
+}
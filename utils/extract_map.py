@@ -5,6 +5,7 @@ from dataclasses import dataclass
 import copy
 import bisect
 import numpy as np
+import zlib
 # The command characters in svg
 svg_commands = "LMHVlmhvZz"
 
@@ -432,13 +433,9 @@ with open("map_base.svg") as svg_file:
 	#c = [topo.get_colour(normalised_x[index], normalised_y[index]) for index in range(len(normalised_y))]
 	#plot(normalised_x, normalised_y, max_x, max_y, "blue")
 	
-	channels = 2
-
-	# Header info, u16 for width, u16 for height, u16 for channel count
-	a = bytearray(width.to_bytes(2, byteorder="little") + height.to_bytes(2, byteorder="little") + channels.to_bytes(2, byteorder="little"))
-	
 	veg_bytes = bytearray()
 	topo_bytes = bytearray()
+	snow_bytes = bytearray()
 	hex_name_bytes = bytearray()
 	name_index_bytes = bytearray()
 	for x in range(width):
@@ -447,12 +444,23 @@ with open("map_base.svg") as svg_file:
 			if pos == None:
 				veg_bytes.append(255)
 				topo_bytes.append(255)
+				snow_bytes.append(255)
 			else:
 				veg_bytes.append(vegitation.get_pixel_neat(pos))
 				topo_bytes.append(topo.get_pixel_neat(pos))
-
-	a += topo_bytes
-	a += veg_bytes
+				snow_bytes.append(snow.get_pixel_neat(pos))
+
+	# Versioned map format (see geonext-shared/src/map_format.rs)
+	channels = [("topo", topo_bytes), ("veg", veg_bytes), ("snow", snow_bytes)]
+	u8_type, version, checksum_flag = 0, 1, 1
+	a = bytearray(b"GNXM")
+	a += version.to_bytes(2, byteorder="little") + checksum_flag.to_bytes(2, byteorder="little")
+	a += width.to_bytes(2, byteorder="little") + height.to_bytes(2, byteorder="little") + len(channels).to_bytes(2, byteorder="little")
+	for (name, _data) in channels:
+		a += len(name).to_bytes(1, byteorder="little") + bytes(name, encoding="utf8") + u8_type.to_bytes(1, byteorder="little")
+	data = b"".join(channel_data for (_name, channel_data) in channels)
+	a += zlib.crc32(data).to_bytes(4, byteorder="little")
+	a += data
 	write_file(a)
 
 
@@ -490,11 +498,22 @@ with open("map_base.svg") as svg_file:
 				else:
 					data += bytes([254])
 
+		# Country metadata (see geonext-shared/src/country.rs). Colours are left black as they are assigned when the map is loaded.
+		iso_codes = dict(zip(names, map(lambda path: path.name.split('_')[-2], path_strings)))
 		data += len(names_register).to_bytes(8, byteorder="little")
 		for name in names_register:
-			name_bytes = bytes(name, encoding="utf8")
-			data += len(name_bytes).to_bytes(8, byteorder="little")
-			data += name_bytes
+			# The capital is the hex closest to the centre of the country
+			members = [(x, y) for ((x, y), owner) in lut.items() if owner == name]
+			centre_x = sum(offset_x(x, y) for (x, y) in members) / len(members)
+			centre_y = sum(y * math.sqrt(3) / 2 for (x, y) in members) / len(members)
+			capital = min(members, key=lambda hex: ((offset_x(*hex) - centre_x)**2 + (hex[1] * math.sqrt(3) / 2 - centre_y)**2, hex[1], hex[0]))
+			for text in (name, iso_codes[name]):
+				text_bytes = bytes(text, encoding="utf8")
+				data += len(text_bytes).to_bytes(8, byteorder="little")
+				data += text_bytes
+			data += bytes(6)
+			(q, r) = to_axial(*capital)
+			data += q.to_bytes(4, byteorder="little", signed=True) + r.to_bytes(4, byteorder="little", signed=True)
 		f.write(data)
 		
 	
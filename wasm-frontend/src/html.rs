@@ -65,9 +65,12 @@ pub fn panic_hook(info: &core::panic::PanicInfo) {
 		return;
 	}
 	error!("{}", info);
+	show_error(&format!("{info}"));
+}
 
-	let document = get_document();
-	if let Some(el) = document.get_element_by_id("errorreason") {
-		el.set_inner_html(&format!("{info}"));
+/// Displays an error in the overlay, replacing the game
+pub fn show_error(reason: &str) {
+	if let Some(el) = get_document().get_element_by_id("errorreason") {
+		el.set_text_content(Some(reason));
 	}
 }
@@ -130,6 +130,9 @@ pub fn with_assets(asset_map: Map) -> Result<(), JsValue> {
 			if let Ok(mut application) = cell.try_borrow_mut() {
 				if let Some(application) = &mut *application {
 					application.update(time as f32);
+					for message in application.game_state.outgoing.drain(..) {
+						sockets::send(&message);
+					}
 				} else {
 					// Drop our handle to this closure so that it will get cleaned
 					// up once we return.
@@ -1,21 +1,82 @@
-use geonext_shared::ServerMessage;
+use geonext_shared::{ClientMessage, ServerMessage, PROTOCOL_VERSION};
+use std::cell::RefCell;
 use wasm_bindgen::{closure::Closure, JsCast, JsValue};
 
+thread_local! {
+	static SOCKET: RefCell<Option<web_sys::WebSocket>> = RefCell::new(None);
+	/// Set once the server has accepted our [`ClientMessage::Hello`]
+	static HANDSHAKE_COMPLETE: RefCell<bool> = RefCell::new(false);
+	/// Set if the server rejected the connection, as reconnecting would be rejected again
+	static REJECTED: RefCell<bool> = RefCell::new(false);
+	/// Sent when reconnecting so that the server only needs to send the updates that were missed
+	static RESUME_TOKEN: RefCell<Option<u64>> = RefCell::new(None);
+	/// The number of reconnections since the last successful handshake, used for the backoff
+	static RECONNECT_ATTEMPTS: RefCell<u32> = RefCell::new(0);
+}
+
+/// The longest wait between reconnection attempts, in milliseconds
+const MAX_RECONNECT_DELAY: i32 = 30_000;
+
+/// Sends a message over a websocket
+fn send_on(ws: &web_sys::WebSocket, message: &ClientMessage) {
+	let data = bincode::serialize(message).unwrap();
+	let buffer = js_sys::Uint8Array::new_with_length(data.len() as u32);
+	buffer.copy_from(&data);
+	let buffer = buffer.buffer();
+	match ws.send_with_array_buffer(&buffer) {
+		Ok(_) => info!("message successfully sent"),
+		Err(err) => error!("error sending message: {:?}", err),
+	}
+}
+
+/// Sends a message to the server if the websocket is open and the handshake has completed
+pub fn send(message: &ClientMessage) {
+	if !HANDSHAKE_COMPLETE.with(|complete| *complete.borrow()) {
+		warn!("Dropping {message:?} as the handshake has not completed");
+		return;
+	}
+	SOCKET.with(|socket| match &*socket.borrow() {
+		Some(ws) if ws.ready_state() == web_sys::WebSocket::OPEN => send_on(ws, message),
+		_ => warn!("Dropping {message:?} as the websocket is not open"),
+	});
+}
+
 pub fn start_websocket(code: Option<String>) -> Result<(), JsValue> {
 	let location = web_sys::window().unwrap().location().host()?;
 	let ws = web_sys::WebSocket::new(&format!("ws://{location}/__stream"))?;
 	ws.set_binary_type(web_sys::BinaryType::Arraybuffer);
 
 	// create callback
-	let _cloned_ws = ws.clone();
+	let cloned_ws = ws.clone();
 	let onmessage_callback = Closure::<dyn FnMut(_)>::new(move |e: web_sys::MessageEvent| {
 		if let Ok(data) = e.data().dyn_into::<js_sys::ArrayBuffer>() {
 			let value = js_sys::Uint8Array::new(&data).to_vec();
 
-			let Ok(message) = bincode::deserialize::<geonext_shared::ServerMessage>(&value) else {
+			let Ok(message) = bincode::deserialize::<ServerMessage>(&value) else {
 				error!("Recieved malformed message.");
 				return;
 			};
+			match &message {
+				ServerMessage::HelloAccepted { protocol_version, server_build } => {
+					info!("Connected to server build {server_build} with protocol {protocol_version}");
+					HANDSHAKE_COMPLETE.with(|complete| complete.replace(true));
+					RECONNECT_ATTEMPTS.with(|attempts| attempts.replace(0));
+					match RESUME_TOKEN.with(|token| *token.borrow()) {
+						Some(token) => send_on(&cloned_ws, &ClientMessage::Resume { token }),
+						None => send_on(&cloned_ws, &ClientMessage::ListGames),
+					}
+				}
+				ServerMessage::HelloRejected { reason } => {
+					REJECTED.with(|rejected| rejected.replace(true));
+					crate::html::show_error(&format!("The server rejected the connection: {reason}"));
+					return;
+				}
+				ServerMessage::Session { resume_token } => {
+					RESUME_TOKEN.with(|token| token.replace(Some(*resume_token)));
+					return;
+				}
+				_ => {}
+			}
 			crate::APPLICATION_CELL.with(|cell| {
 				if let Ok(mut application) = cell.try_borrow_mut() {
 					if let Some(application) = &mut *application {
@@ -47,19 +108,43 @@ pub fn start_websocket(code: Option<String>) -> Result<(), JsValue> {
 	let onopen_callback = Closure::<dyn FnMut()>::new(move || {
 		info!("socket opened");
 
+		send_on(
+			&cloned_ws,
+			&ClientMessage::Hello {
+				protocol_version: PROTOCOL_VERSION,
+				client_build: env!("CARGO_PKG_VERSION").to_string(),
+			},
+		);
 		if let Some(code) = code.clone() {
-			let data = bincode::serialize(&geonext_shared::ClientMessage::Auth { code }).unwrap();
-			let buffer = js_sys::Uint8Array::new_with_length(data.len() as u32);
-			buffer.copy_from(&data);
-			let buffer = buffer.buffer();
-			match cloned_ws.send_with_array_buffer(&buffer) {
-				Ok(_) => info!("message successfully sent"),
-				Err(err) => error!("error sending message: {:?}", err),
-			}
+			send_on(&cloned_ws, &ClientMessage::Auth { code });
 		}
 	});
 	ws.set_onopen(Some(onopen_callback.as_ref().unchecked_ref()));
 	onopen_callback.forget();
 
+	// Reconnect with exponential backoff when the connection drops
+	let onclose_callback = Closure::<dyn FnMut()>::new(move || {
+		HANDSHAKE_COMPLETE.with(|complete| complete.replace(false));
+		if REJECTED.with(|rejected| *rejected.borrow()) {
+			return;
+		}
+		let attempts = RECONNECT_ATTEMPTS.with(|attempts| attempts.replace_with(|attempts| attempts.saturating_add(1)));
+		let delay = 500i32.saturating_mul(1 << attempts.min(16)).min(MAX_RECONNECT_DELAY);
+		warn!("Socket closed, reconnecting in {delay}ms");
+		// Discord codes can only be exchanged once, so the reconnection does not authenticate again
+		let reconnect = Closure::once_into_js(|| {
+			if let Err(e) = start_websocket(None) {
+				error!("Failed to reconnect {e:?}");
+			}
+		});
+		if let Err(e) = web_sys::window().unwrap().set_timeout_with_callback_and_timeout_and_arguments_0(reconnect.unchecked_ref(), delay) {
+			error!("Failed to schedule reconnection {e:?}");
+		}
+	});
+	ws.set_onclose(Some(onclose_callback.as_ref().unchecked_ref()));
+	onclose_callback.forget();
+
+	SOCKET.with(|socket| socket.replace(Some(ws)));
+
 	Ok(())
 }